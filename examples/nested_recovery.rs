@@ -0,0 +1,90 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use unwrap_or_ai::try_unwrap_or_ai;
+use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
+
+/// One product line of an order
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct LineItem {
+    sku: String,
+    description: String,
+    quantity: u32,
+    unit_price: f64,
+}
+
+/// Where an order ships to
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct Address {
+    street: String,
+    city: String,
+    postal_code: String,
+    country: String,
+}
+
+/// A customer order, with its line items and shipping address nested inside
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct Order {
+    order_id: u32,
+    customer_name: String,
+    shipping_address: Address,
+    items: Vec<LineItem>,
+    status: String,
+}
+
+impl Order {
+    fn total(&self) -> f64 {
+        self.items
+            .iter()
+            .map(|item| item.unit_price * item.quantity as f64)
+            .sum()
+    }
+}
+
+/// Loads a customer's three most recent orders from the order service.
+/// Every order has at least one line item.
+#[unwrap_or_ai_func]
+fn fetch_recent_orders(customer_id: u32) -> Result<Vec<Order>, String> {
+    Err(format!(
+        "order service returned 503 while listing orders for customer {}",
+        customer_id
+    ))
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+
+    println!("📦 Fetching recent orders...");
+    let orders = match try_unwrap_or_ai!(fetch_recent_orders(7)).await {
+        Ok(orders) => orders,
+        Err(error) => {
+            eprintln!("Could not recover the orders: {}", error);
+            return;
+        }
+    };
+
+    // The whole Vec<Order> came back from a single recovery, nesting included
+    println!("✅ Recovered {} orders", orders.len());
+    for order in &orders {
+        println!(
+            "\nOrder #{} for {} ({}), shipping to {}, {}",
+            order.order_id,
+            order.customer_name,
+            order.status,
+            order.shipping_address.city,
+            order.shipping_address.country
+        );
+        for item in &order.items {
+            println!(
+                "   {} x {} [{}] @ ${:.2}",
+                item.quantity, item.description, item.sku, item.unit_price
+            );
+        }
+        println!("   Total: ${:.2}", order.total());
+    }
+
+    let empty = orders.iter().filter(|order| order.items.is_empty()).count();
+    if empty > 0 {
+        eprintln!("\n⚠️  {} orders came back without line items", empty);
+    }
+}
@@ -0,0 +1,46 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use unwrap_or_ai::partial::recover_partial;
+
+fn default_currency() -> String {
+    "USD".to_string()
+}
+
+/// An order as stored in our warehouse system
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct Order {
+    order_id: u32,
+    customer_name: String,
+    shipping_address: String,
+    total: f64,
+    // Fields with serde defaults may be left out by the AI; they get their default
+    #[serde(default = "default_currency")]
+    currency: String,
+    #[serde(default)]
+    gift_wrapped: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+
+    // The replica we read from lost part of the row
+    let from_replica = serde_json::json!({
+        "order_id": 1042,
+        "customer_name": "Ada Lovelace",
+        "shipping_address": null,
+        "total": 129.99,
+        "currency": null
+    });
+
+    // Only the missing fields are filled in; everything we did have is kept verbatim
+    match recover_partial::<Order, _>(
+        &from_replica,
+        "The orders replica returned a row with missing columns".to_string(),
+    )
+    .await
+    {
+        Ok(order) => println!("Completed order: {:#?}", order),
+        Err(error) => eprintln!("Could not complete the order: {}", error),
+    }
+}
@@ -14,7 +14,7 @@ struct User {
 
 // AI-ENHANCED APPROACH:
 #[unwrap_or_ai_func]
-fn fetch_user_from_database(user_id: u32) -> Result<User, String> {
+fn fetch_user_from_database(_user_id: u32) -> Result<User, String> {
     Err("Database temporarily unavailable".to_string())
 }
 
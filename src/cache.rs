@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Recovered values keyed by a hash of the model, target type and prompt.
+///
+/// Entries older than the TTL are treated as missing. A cache created with
+/// [`RecoveryCache::persistent`] loads its file on creation and appends every insert to
+/// it, so recoveries survive process restarts.
+#[derive(Debug)]
+pub struct RecoveryCache {
+    ttl: Option<Duration>,
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    value: serde_json::Value,
+    stored_at_ms: u64,
+}
+
+impl RecoveryCache {
+    /// An in-memory cache; `None` keeps entries for the lifetime of the process
+    pub fn in_memory(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            path: None,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A cache persisted at `path`, one JSON line per insert.
+    ///
+    /// Existing unexpired entries are loaded immediately, and the file is rewritten with
+    /// only those so it doesn't keep growing. A missing or unreadable file starts an
+    /// empty cache rather than failing, as do unreadable lines.
+    pub fn persistent(path: impl Into<PathBuf>, ttl: Option<Duration>) -> Self {
+        let path = path.into();
+        let cache = Self {
+            ttl,
+            entries: Mutex::new(load_entries(&path)),
+            path: Some(path),
+        };
+        {
+            let mut entries = cache.lock();
+            entries.retain(|_, entry| !cache.is_expired(entry));
+            if let Some(path) = &cache.path
+                && let Err(err) = save_entries(path, &entries)
+            {
+                log::warn!(
+                    "Failed to compact recovery cache at {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+        cache
+    }
+
+    /// The cache key for a recovery of `type_name` from `model` with `prompt`
+    pub fn key(model: &str, type_name: &str, prompt: &str) -> String {
+        format!("{:016x}", stable_hash(&[model, type_name, prompt]))
+    }
+
+    /// Like [`RecoveryCache::key`], for a prompt about a function whose source hashes to
+    /// `source_hash`, so entries recovered for an older version of it are never used
+    pub fn versioned_key(model: &str, type_name: &str, prompt: &str, source_hash: u64) -> String {
+        let source_hash = format!("{:016x}", source_hash);
+        format!(
+            "{:016x}",
+            stable_hash(&[model, type_name, prompt, &source_hash])
+        )
+    }
+
+    /// The cache key for a recovery of `type_name` from `model` after an error with
+    /// `error_message`, whatever the prompt; see
+    /// [`CacheKeyStrategy::ErrorMessage`](crate::CacheKeyStrategy::ErrorMessage)
+    pub fn error_key(model: &str, type_name: &str, error_message: &str) -> String {
+        format!(
+            "{:016x}",
+            stable_hash(&[model, type_name, "error", error_message])
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let entries = self.lock();
+        entries
+            .get(key)
+            .filter(|entry| !self.is_expired(entry))
+            .map(|entry| entry.value.clone())
+    }
+
+    pub fn insert(&self, key: String, value: serde_json::Value) {
+        let entry = CacheEntry {
+            value,
+            stored_at_ms: now_millis(),
+        };
+        // Appending under the lock keeps concurrent inserts from interleaving
+        let mut entries = self.lock();
+        if let Some(path) = &self.path
+            && let Err(err) = append_entry(path, &key, &entry)
+        {
+            log::warn!(
+                "Failed to persist recovery cache to {}: {}",
+                path.display(),
+                err
+            );
+        }
+        entries.insert(key, entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock()
+            .values()
+            .filter(|entry| !self.is_expired(entry))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_expired(&self, entry: &CacheEntry) -> bool {
+        match self.ttl {
+            Some(ttl) => {
+                u128::from(now_millis().saturating_sub(entry.stored_at_ms)) >= ttl.as_millis()
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, CacheEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Later lines win, so a key inserted again is read back with its latest value
+fn load_entries(path: &Path) -> HashMap<String, CacheEntry> {
+    let Ok(file) = std::fs::File::open(path) else {
+        return HashMap::new();
+    };
+    std::io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<(String, CacheEntry)>(&line).ok())
+        .collect()
+}
+
+fn append_entry(path: &Path, key: &str, entry: &CacheEntry) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(&(key, entry))?;
+    line.push(b'\n');
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)
+}
+
+// Write to a sibling temp file first so a crash never leaves a half-written cache
+fn save_entries(path: &Path, entries: &HashMap<String, CacheEntry>) -> std::io::Result<()> {
+    let mut contents = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut contents, &entry)?;
+        contents.push(b'\n');
+    }
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(tmp_path, path)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+        .unwrap_or(0)
+}
+
+/// FNV-1a over the given parts, separated so `["ab", "c"]` and `["a", "bc"]` differ.
+///
+/// Unlike `DefaultHasher`, the result is stable across Rust versions, which matters for
+/// keys written to disk.
+pub(crate) fn stable_hash(parts: &[&str]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut hash = OFFSET_BASIS;
+    for part in parts {
+        for byte in part.bytes().chain(std::iter::once(0xff)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_cache_survives_reload() {
+        let path = std::env::temp_dir().join(format!(
+            "unwrap_or_ai_cache_test_{}.json",
+            std::process::id()
+        ));
+        let key = RecoveryCache::key("model", "TestUser", "prompt");
+
+        let cache = RecoveryCache::persistent(&path, Some(Duration::from_secs(3600)));
+        cache.insert(key.clone(), serde_json::json!({ "id": 42 }));
+
+        let reloaded = RecoveryCache::persistent(&path, Some(Duration::from_secs(3600)));
+        assert_eq!(reloaded.get(&key), Some(serde_json::json!({ "id": 42 })));
+
+        // A zero TTL treats everything on disk as expired
+        let expired = RecoveryCache::persistent(&path, Some(Duration::ZERO));
+        assert_eq!(expired.get(&key), None);
+        assert!(expired.is_empty());
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_disk_cache_appends_inserts() {
+        let path = std::env::temp_dir().join(format!(
+            "unwrap_or_ai_cache_append_test_{}.json",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        let key = RecoveryCache::key("model", "TestUser", "prompt");
+
+        let cache = RecoveryCache::persistent(&path, None);
+        cache.insert(key.clone(), serde_json::json!({ "id": 1 }));
+        cache.insert(key.clone(), serde_json::json!({ "id": 2 }));
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 2);
+
+        // The latest insert wins, and reopening compacts the file
+        let reloaded = RecoveryCache::persistent(&path, None);
+        assert_eq!(reloaded.get(&key), Some(serde_json::json!({ "id": 2 })));
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 1);
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_sub_second_ttl_keeps_fresh_entries() {
+        let cache = RecoveryCache::in_memory(Some(Duration::from_millis(500)));
+        cache.insert("key".to_string(), serde_json::json!(1));
+        assert_eq!(cache.get("key"), Some(serde_json::json!(1)));
+    }
+
+    #[test]
+    fn test_keys_depend_on_every_part() {
+        let key = RecoveryCache::key("model", "TestUser", "prompt");
+
+        assert_eq!(key, RecoveryCache::key("model", "TestUser", "prompt"));
+        assert_ne!(key, RecoveryCache::key("other", "TestUser", "prompt"));
+        assert_ne!(key, RecoveryCache::key("model", "TestUser", "other prompt"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+use crate::groq_client::models;
+
+/// What a model can do, as far as recovery is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    /// Accepts `response_format: json_schema`; otherwise JSON mode is used
+    pub supports_json_schema: bool,
+    /// Accepts `tools` / function calling
+    pub supports_tools: bool,
+    /// Context window in tokens
+    pub max_context: usize,
+}
+
+impl ModelCapabilities {
+    /// Assumed for models that were never registered: structured output, no tools, 8k
+    pub const UNKNOWN: Self = Self {
+        supports_json_schema: true,
+        supports_tools: false,
+        max_context: 8_192,
+    };
+}
+
+static REGISTRY: LazyLock<RwLock<HashMap<String, ModelCapabilities>>> =
+    LazyLock::new(|| RwLock::new(builtin_capabilities()));
+
+fn builtin_capabilities() -> HashMap<String, ModelCapabilities> {
+    let structured = |max_context| ModelCapabilities {
+        supports_json_schema: true,
+        supports_tools: true,
+        max_context,
+    };
+    let json_mode = |max_context| ModelCapabilities {
+        supports_json_schema: false,
+        supports_tools: true,
+        max_context,
+    };
+
+    [
+        (models::LLAMA3_8B, json_mode(8_192)),
+        (models::LLAMA3_70B, json_mode(8_192)),
+    ]
+    .into_iter()
+    .chain(
+        models::all_structured()
+            .iter()
+            .map(|&model| (model, structured(131_072))),
+    )
+    .map(|(model, capabilities)| (model.to_string(), capabilities))
+    .collect()
+}
+
+/// Declare what `model` supports, replacing any earlier or built-in entry
+pub fn register_model_capabilities(model: impl Into<String>, capabilities: ModelCapabilities) {
+    REGISTRY
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(model.into(), capabilities);
+}
+
+/// The registered capabilities of `model`, or [`ModelCapabilities::UNKNOWN`]
+pub fn model_capabilities(model: &str) -> ModelCapabilities {
+    registered_capabilities(model).unwrap_or(ModelCapabilities::UNKNOWN)
+}
+
+// Built-in or registered capabilities only
+pub(crate) fn registered_capabilities(model: &str) -> Option<ModelCapabilities> {
+    REGISTRY
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(model)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_and_registered_capabilities() {
+        assert!(model_capabilities(models::KIMI_K2).supports_json_schema);
+        assert!(!model_capabilities(models::LLAMA3_8B).supports_json_schema);
+        assert_eq!(
+            model_capabilities("some/unregistered-model"),
+            ModelCapabilities::UNKNOWN
+        );
+
+        let custom = ModelCapabilities {
+            supports_json_schema: false,
+            supports_tools: false,
+            max_context: 4_096,
+        };
+        register_model_capabilities("acme/tiny-1b", custom);
+        assert_eq!(model_capabilities("acme/tiny-1b"), custom);
+    }
+}
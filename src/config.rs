@@ -0,0 +1,577 @@
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+
+use crate::cache::RecoveryCache;
+use crate::drift::DriftCheck;
+use crate::groq_client::ResponseHook;
+use crate::provider::Provider;
+
+/// Default budget for function source in prompts, about 3k tokens
+pub const DEFAULT_MAX_SOURCE_CHARS: usize = 12_000;
+
+/// Rewrites the final recovery prompt before it leaves the process
+pub type PromptHook = Arc<dyn Fn(String) -> String + Send + Sync>;
+
+/// How the `max_tokens` of a recovery request is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaxTokens {
+    /// Don't send `max_tokens`; the provider's own limit applies
+    #[default]
+    ProviderDefault,
+    /// Estimate it from the target type's schema with
+    /// [`schema::estimate_max_tokens`](crate::schema::estimate_max_tokens), capped at the
+    /// model's context size
+    Estimated,
+    /// Always send this value
+    Fixed(u32),
+}
+
+/// What makes two recoveries the same for the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheKeyStrategy {
+    /// The model, target type and full prompt, arguments included
+    #[default]
+    Prompt,
+    /// The model, target type and the message of the error that triggered recovery, so a
+    /// recurring error ("Database connection timeout") recovers once for any arguments.
+    ///
+    /// This over-generalizes whenever the right value depends on the arguments: a
+    /// `get_user(7)` that times out is served the user recovered for `get_user(3)`.
+    /// Recoveries without an error (a `None`, or a [`Recovery`](crate::Recovery)) still
+    /// use the prompt.
+    ErrorMessage,
+}
+
+/// Crate-wide settings used by `unwrap_or_ai!` and `call_ai_for_type`
+///
+/// # Environment variables
+///
+/// [`RecoveryConfig::from_env`] starts from these when they are set, so deployments can
+/// be tuned without code changes:
+///
+/// - `UNWRAP_OR_AI_PROVIDER`: `groq` or `cerebras`, replacing the provider list
+/// - `UNWRAP_OR_AI_MODEL`: the model used on every provider
+/// - `UNWRAP_OR_AI_TEMPERATURE`: the sampling temperature, e.g. `0.2`
+/// - `UNWRAP_OR_AI_TIMEOUT_MS`: how long a recovery may take, in milliseconds
+///
+/// Builder calls made afterwards take precedence over them, and they over the built-in
+/// defaults: code > environment > defaults. Values that don't parse are logged and
+/// ignored. Without a call to [`init`], recoveries use `RecoveryConfig::from_env()`,
+/// read once on first use. [`RecoveryConfig::new`] ignores the environment.
+#[derive(Clone)]
+pub struct RecoveryConfig {
+    pub(crate) providers: Vec<Provider>,
+    pub(crate) max_source_chars: usize,
+    pub(crate) cache: Option<Arc<RecoveryCache>>,
+    pub(crate) cache_key: CacheKeyStrategy,
+    pub(crate) example_skeleton: bool,
+    pub(crate) force_recovery: bool,
+    pub(crate) ai_disabled: bool,
+    pub(crate) unit_hints: bool,
+    pub(crate) prompt_template: Option<String>,
+    pub(crate) prompt_prefix: Option<String>,
+    pub(crate) prompt_suffix: Option<String>,
+    pub(crate) assistant_prefill: Option<String>,
+    pub(crate) drift_check: Option<DriftCheck>,
+    pub(crate) max_tokens: MaxTokens,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) scalar_coercion: bool,
+    pub(crate) lenient_json: bool,
+    pub(crate) fail_closed: bool,
+    pub(crate) required_field_retries: u32,
+    pub(crate) strict_schema: bool,
+    pub(crate) candidates: Option<u32>,
+    pub(crate) token_budget: Option<u64>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) runtime: Option<tokio::runtime::Handle>,
+    pub(crate) response_hooks: Vec<ResponseHook>,
+    pub(crate) prompt_hooks: Vec<PromptHook>,
+}
+
+impl fmt::Debug for RecoveryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecoveryConfig")
+            .field("providers", &self.providers)
+            .field("max_source_chars", &self.max_source_chars)
+            .field("cache", &self.cache)
+            .field("cache_key", &self.cache_key)
+            .field("example_skeleton", &self.example_skeleton)
+            .field("force_recovery", &self.force_recovery)
+            .field("ai_disabled", &self.ai_disabled)
+            .field("unit_hints", &self.unit_hints)
+            .field("prompt_template", &self.prompt_template)
+            .field("prompt_prefix", &self.prompt_prefix)
+            .field("prompt_suffix", &self.prompt_suffix)
+            .field("assistant_prefill", &self.assistant_prefill)
+            .field("drift_check", &self.drift_check)
+            .field("max_tokens", &self.max_tokens)
+            .field("temperature", &self.temperature)
+            .field("scalar_coercion", &self.scalar_coercion)
+            .field("lenient_json", &self.lenient_json)
+            .field("fail_closed", &self.fail_closed)
+            .field("required_field_retries", &self.required_field_retries)
+            .field("strict_schema", &self.strict_schema)
+            .field("candidates", &self.candidates)
+            .field("token_budget", &self.token_budget)
+            .field("timeout", &self.timeout)
+            .field("runtime", &self.runtime)
+            .field("response_hooks", &self.response_hooks.len())
+            .field("prompt_hooks", &self.prompt_hooks.len())
+            .finish()
+    }
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        Self {
+            providers: vec![Provider::groq()],
+            max_source_chars: DEFAULT_MAX_SOURCE_CHARS,
+            cache: None,
+            cache_key: CacheKeyStrategy::Prompt,
+            example_skeleton: false,
+            force_recovery: false,
+            ai_disabled: false,
+            unit_hints: false,
+            prompt_template: None,
+            prompt_prefix: None,
+            prompt_suffix: None,
+            assistant_prefill: None,
+            drift_check: None,
+            max_tokens: MaxTokens::ProviderDefault,
+            temperature: Some(0.0),
+            scalar_coercion: false,
+            lenient_json: false,
+            fail_closed: false,
+            required_field_retries: 0,
+            strict_schema: false,
+            candidates: None,
+            token_budget: None,
+            timeout: None,
+            runtime: None,
+            response_hooks: Vec::new(),
+            prompt_hooks: Vec::new(),
+        }
+    }
+}
+
+impl RecoveryConfig {
+    /// The default configuration: Groq only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The default configuration with the
+    /// [environment variables](RecoveryConfig#environment-variables) applied
+    pub fn from_env() -> Self {
+        Self::default().with_env(|name| std::env::var(name).ok())
+    }
+
+    // Apply the `UNWRAP_OR_AI_*` variables `var` returns; blank ones count as unset
+    pub(crate) fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |name| var(name).filter(|value: &String| !value.trim().is_empty());
+
+        if let Some(name) = var("UNWRAP_OR_AI_PROVIDER") {
+            match Provider::named(name.trim()) {
+                Some(provider) => self.providers = vec![provider],
+                None => log::warn!(
+                    "Ignoring UNWRAP_OR_AI_PROVIDER={:?}: expected groq or cerebras",
+                    name
+                ),
+            }
+        }
+        if let Some(model) = var("UNWRAP_OR_AI_MODEL") {
+            let model = model.trim();
+            self.providers = self
+                .providers
+                .into_iter()
+                .map(|provider| provider.with_model(model))
+                .collect();
+        }
+        if let Some(temperature) = var("UNWRAP_OR_AI_TEMPERATURE") {
+            match temperature.trim().parse::<f32>() {
+                Ok(parsed) if parsed.is_finite() => self.temperature = Some(parsed),
+                _ => log::warn!(
+                    "Ignoring UNWRAP_OR_AI_TEMPERATURE={:?}: expected a number",
+                    temperature
+                ),
+            }
+        }
+        if let Some(timeout) = var("UNWRAP_OR_AI_TIMEOUT_MS") {
+            match timeout.trim().parse::<u64>() {
+                Ok(millis) => self.timeout = Some(Duration::from_millis(millis)),
+                Err(_) => log::warn!(
+                    "Ignoring UNWRAP_OR_AI_TIMEOUT_MS={:?}: expected a whole number of milliseconds",
+                    timeout
+                ),
+            }
+        }
+        self
+    }
+
+    /// Replace the provider list; providers are tried in the given order
+    pub fn with_providers(mut self, providers: Vec<Provider>) -> Self {
+        self.providers = providers;
+        self
+    }
+
+    /// Append a provider to try after the ones already configured
+    pub fn with_provider(mut self, provider: Provider) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    /// Cap the function source included in prompts at `max_chars` characters.
+    ///
+    /// Longer bodies are cut while keeping the doc comments and signature; roughly four
+    /// characters make up one token.
+    pub fn with_max_source_chars(mut self, max_chars: usize) -> Self {
+        self.max_source_chars = max_chars;
+        self
+    }
+
+    /// Reuse recovered values for identical prompts for up to `ttl`, in memory only
+    pub fn with_cache(mut self, ttl: Duration) -> Self {
+        self.cache = Some(Arc::new(RecoveryCache::in_memory(Some(ttl))));
+        self
+    }
+
+    /// Reuse recovered values for up to `ttl`, persisted as JSON at `path`.
+    ///
+    /// Entries already in the file are loaded now; expired ones are ignored.
+    pub fn with_disk_cache(mut self, path: impl Into<PathBuf>, ttl: Duration) -> Self {
+        self.cache = Some(Arc::new(RecoveryCache::persistent(path, Some(ttl))));
+        self
+    }
+
+    /// Choose what cached recoveries are keyed on; see [`CacheKeyStrategy`] for the
+    /// tradeoff. Has no effect without a cache.
+    pub fn with_cache_key(mut self, strategy: CacheKeyStrategy) -> Self {
+        self.cache_key = strategy;
+        self
+    }
+
+    /// Whether prompts include an example JSON object built from the target's schema.
+    ///
+    /// Off by default, since it changes every prompt and with it every cache key; it
+    /// noticeably helps models without structured output support.
+    pub fn with_example_skeleton(mut self, enabled: bool) -> Self {
+        self.example_skeleton = enabled;
+        self
+    }
+
+    /// Whether prompts include unit hints guessed from the target's field names, such as
+    /// `cpu_usage_percent` being 0-100 or `price` being in USD.
+    ///
+    /// Off by default, since the guesses are wrong for data that uses other units.
+    pub fn with_unit_hints(mut self, enabled: bool) -> Self {
+        self.unit_hints = enabled;
+        self
+    }
+
+    /// Describe failed calls with `template` instead of the built-in wording.
+    ///
+    /// `{fn_name}`, `{call}`, `{args}`, `{docs}`, `{source}`, `{error}`, `{error_context}`
+    /// (as JSON) and `{identifiers}` (the instruction to keep id arguments unchanged) are
+    /// filled in from the [`RecoveryContext`](crate::RecoveryContext); missing values
+    /// become empty. The target type, example object and unit hints are still appended
+    /// afterwards.
+    ///
+    /// ```
+    /// use unwrap_or_ai::RecoveryConfig;
+    ///
+    /// let config = RecoveryConfig::new().with_prompt_template(
+    ///     "Recover {fn_name}({args}) given docs: {docs}. Error: {error}. Return JSON.",
+    /// );
+    /// ```
+    pub fn with_prompt_template(mut self, template: impl Into<String>) -> Self {
+        self.prompt_template = Some(template.into());
+        self
+    }
+
+    /// Put `prefix` before every recovery prompt, on its own paragraph. Works with the
+    /// built-in wording as well as a [template](Self::with_prompt_template).
+    pub fn with_prompt_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prompt_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Put `suffix` after every recovery prompt, once the target type, example object and
+    /// hints have been added, so it is the last thing the model reads.
+    ///
+    /// ```
+    /// use unwrap_or_ai::RecoveryConfig;
+    ///
+    /// let config = RecoveryConfig::new().with_prompt_suffix("Respond with JSON only, no markdown.");
+    /// ```
+    pub fn with_prompt_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.prompt_suffix = Some(suffix.into());
+        self
+    }
+
+    /// Start the model's answer with `prefill`, usually `{`, so it continues the JSON
+    /// object instead of opening with prose or a code fence. Off by default.
+    ///
+    /// Only recoveries of types that are always a JSON object, like structs and maps, are
+    /// prefilled. Lists, strings, numbers and `Option`s are asked for as usual, so the
+    /// prefill should be the start of an object.
+    ///
+    /// It is sent as a trailing assistant message in each provider's
+    /// [`PrefillStyle`](crate::PrefillStyle), set with
+    /// [`Provider::with_prefill_style`](crate::Provider::with_prefill_style). Groq,
+    /// Anthropic, vLLM, llama.cpp and Ollama take it as a plain message, Mistral and
+    /// DeepSeek need [`PrefillStyle::PrefixFlag`](crate::PrefillStyle::PrefixFlag), and
+    /// OpenAI ignores it and answers in full.
+    ///
+    /// ```
+    /// use unwrap_or_ai::RecoveryConfig;
+    ///
+    /// let config = RecoveryConfig::new().with_assistant_prefill("{");
+    /// ```
+    pub fn with_assistant_prefill(mut self, prefill: impl Into<String>) -> Self {
+        self.assistant_prefill = Some(prefill.into());
+        self
+    }
+
+    /// Choose the `max_tokens` sent with each recovery request.
+    ///
+    /// [`MaxTokens::Estimated`] sizes it to the type being recovered, so a single small
+    /// struct isn't given the same budget as a long list.
+    pub fn with_max_tokens(mut self, max_tokens: MaxTokens) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// The sampling temperature sent with recoveries; `None` leaves it to the provider,
+    /// whose default is often 1.0.
+    ///
+    /// Defaults to 0, so recovering the same failure twice gives (nearly) the same value
+    /// and tests stay stable. Raise it for more varied values;
+    /// [`Recovery::temperature`](crate::Recovery::temperature) overrides it per call.
+    pub fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Accept numbers and booleans the model quoted, such as `"99.99"` for an `f64`
+    /// price, by converting them before deserialization.
+    ///
+    /// Off by default, since it hides a model that doesn't follow the schema.
+    pub fn with_scalar_coercion(mut self, enabled: bool) -> Self {
+        self.scalar_coercion = enabled;
+        self
+    }
+
+    /// Repair almost-valid JSON answers, such as ones with trailing commas or single
+    /// quotes, instead of failing the recovery; see [`repair_json`](crate::repair::repair_json).
+    ///
+    /// Off by default, like scalar coercion.
+    pub fn with_lenient_json(mut self, enabled: bool) -> Self {
+        self.lenient_json = enabled;
+        self
+    }
+
+    /// Fail with [`AiError::Incomplete`](crate::AiError::Incomplete), naming the fields,
+    /// when the answer leaves out properties the schema requires. The check runs after
+    /// key repair and scalar coercion; optional fields may still be left out.
+    ///
+    /// The strict counterpart of [`with_lenient_json`](Self::with_lenient_json). For a
+    /// single call, use [`Recovery::fail_closed`](crate::Recovery::fail_closed) or run it
+    /// in a [`scope`].
+    pub fn with_fail_closed(mut self, enabled: bool) -> Self {
+        self.fail_closed = enabled;
+        self
+    }
+
+    /// How many times to ask the same provider again, naming the fields, when its answer
+    /// leaves out required properties. Once they're used up the recovery fails with
+    /// [`AiError::Incomplete`](crate::AiError::Incomplete).
+    ///
+    /// Off by default: each retry is another paid request, and without one such an
+    /// answer fails to deserialize like any other.
+    pub fn with_required_field_retries(mut self, retries: u32) -> Self {
+        self.required_field_retries = retries;
+        self
+    }
+
+    /// Ask providers to enforce the schema with `strict: true`; see
+    /// [`GroqClient::with_strict_schema`](crate::GroqClient::with_strict_schema) for which
+    /// ones honor it
+    pub fn with_strict_schema(mut self, enabled: bool) -> Self {
+        self.strict_schema = enabled;
+        self
+    }
+
+    /// Request `n` candidate answers per recovery and keep the first that deserializes;
+    /// see [`GroqClient::with_candidates`](crate::GroqClient::with_candidates)
+    pub fn with_candidates(mut self, n: u32) -> Self {
+        self.candidates = Some(n);
+        self
+    }
+
+    /// Stop asking providers once this process has spent `tokens` tokens on recoveries.
+    ///
+    /// Further recoveries fail with
+    /// [`AiError::TokenBudgetExhausted`](crate::AiError::TokenBudgetExhausted), so
+    /// `try_unwrap_or_ai!` passes the original error through and `option_or_ai!` keeps
+    /// `None`. A warning is logged when the budget runs out. The count is the one in
+    /// [`recovery_stats`](crate::recovery_stats) and starts over with
+    /// [`reset_recovery_stats`](crate::stats::reset_recovery_stats).
+    pub fn with_token_budget(mut self, tokens: u64) -> Self {
+        self.token_budget = Some(tokens);
+        self
+    }
+
+    /// Give up on a recovery that takes longer than `timeout`, across all providers and
+    /// retries, with [`AiError::Timeout`](crate::AiError::Timeout). A
+    /// [`Recovery::timeout`](crate::Recovery::timeout) still limits each of its attempts.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run recoveries' HTTP requests, rate limit waits and response parsing on `runtime`,
+    /// keeping them off the executor that serves your requests. The recovering task only
+    /// waits for the result there; building the prompt and deserializing stay on it.
+    ///
+    /// A recovery spends nearly all its time waiting on the provider, so a small
+    /// multi-threaded runtime (one or two workers) handles many at once; size it by the
+    /// response parsing it has to keep up with, not by concurrent recoveries. Dropping a
+    /// recovery aborts its request, and once the runtime shuts down recoveries fail with
+    /// [`AiError::Cancelled`](crate::AiError::Cancelled).
+    ///
+    /// ```no_run
+    /// use unwrap_or_ai::RecoveryConfig;
+    ///
+    /// let recovery_runtime = tokio::runtime::Builder::new_multi_thread()
+    ///     .worker_threads(2)
+    ///     .thread_name("ai-recovery")
+    ///     .enable_all()
+    ///     .build()
+    ///     .unwrap();
+    /// unwrap_or_ai::config::init(
+    ///     RecoveryConfig::new().with_runtime(recovery_runtime.handle().clone()),
+    /// );
+    /// // Keep `recovery_runtime` alive for as long as recoveries may run
+    /// # std::mem::forget(recovery_runtime);
+    /// ```
+    pub fn with_runtime(mut self, runtime: tokio::runtime::Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Rewrite every recovered JSON value with `hook` before it is deserialized, after
+    /// scalar coercion and any hooks added earlier. `hook` also gets the target's schema.
+    pub fn with_response_hook(
+        mut self,
+        hook: impl Fn(serde_json::Value, &serde_json::Value) -> serde_json::Value
+        + Send
+        + Sync
+        + 'static,
+    ) -> Self {
+        self.response_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Rewrite every prompt with `hook` right before it is sent, after all context,
+    /// hints and any hooks added earlier have been applied.
+    ///
+    /// Meant for scrubbing data that mustn't reach a third-party model, such as emails
+    /// in arguments or keys in function source. The rewritten prompt is also what the
+    /// recovery cache is keyed on, so nothing unredacted is persisted either.
+    ///
+    /// ```
+    /// use unwrap_or_ai::{RecoveryConfig, redact};
+    ///
+    /// let config = RecoveryConfig::new().on_build_prompt(redact::redact_secrets);
+    /// ```
+    pub fn on_build_prompt(
+        mut self,
+        hook: impl Fn(String) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.prompt_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Warn through `log` when a recovered value contradicts the call's arguments, such
+    /// as `get_user(42)` recovering a user with a different `id`.
+    ///
+    /// [`DriftCheck::id_fields`] compares id-like parameters; use [`DriftCheck::new`] to
+    /// choose which parameters must match which fields.
+    pub fn with_drift_check(mut self, check: DriftCheck) -> Self {
+        self.drift_check = Some(check);
+        self
+    }
+
+    /// Recover even when the wrapped value is `Ok`/`Some`, discarding it.
+    ///
+    /// Meant for tests: recovery paths can be exercised against functions that normally
+    /// succeed. Best combined with [`config::scope`](scope) so only one test is affected.
+    pub fn with_force_recovery(mut self, enabled: bool) -> Self {
+        if enabled {
+            FORCE_RECOVERY_USED.store(true, Ordering::Relaxed);
+        }
+        self.force_recovery = enabled;
+        self
+    }
+
+    /// Never ask a provider: every recovery fails with
+    /// [`AiError::Disabled`](crate::AiError::Disabled), so `Err` passes through
+    /// `try_unwrap_or_ai!` and `option_or_ai!` keeps `None`.
+    ///
+    /// Unlike the `no-ai` feature, this only affects the application that sets it, so
+    /// libraries should prefer it.
+    pub fn with_ai_disabled(mut self, disabled: bool) -> Self {
+        self.ai_disabled = disabled;
+        self
+    }
+
+    pub fn providers(&self) -> &[Provider] {
+        &self.providers
+    }
+}
+
+static GLOBAL_CONFIG: RwLock<Option<Arc<RecoveryConfig>>> = RwLock::new(None);
+
+// Set once any config enables forced recovery, so successful calls in programs that
+// never do skip looking up the current config
+static FORCE_RECOVERY_USED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the current config recovers even successful calls
+pub(crate) fn force_recovery() -> bool {
+    FORCE_RECOVERY_USED.load(Ordering::Relaxed) && current().force_recovery
+}
+
+// Used until `init` is called; the environment is only read once
+static ENV_CONFIG: LazyLock<Arc<RecoveryConfig>> =
+    LazyLock::new(|| Arc::new(RecoveryConfig::from_env()));
+
+tokio::task_local! {
+    static SCOPED_CONFIG: Arc<RecoveryConfig>;
+}
+
+/// Install the configuration used by every recovery that isn't inside a [`scope`]
+pub fn init(config: RecoveryConfig) {
+    *GLOBAL_CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(config));
+}
+
+/// Run `fut` with `config` taking precedence over the global configuration
+pub async fn scope<F: Future>(config: RecoveryConfig, fut: F) -> F::Output {
+    SCOPED_CONFIG.scope(Arc::new(config), fut).await
+}
+
+/// The configuration in effect for the current task
+pub fn current() -> Arc<RecoveryConfig> {
+    if let Ok(config) = SCOPED_CONFIG.try_with(Arc::clone) {
+        return config;
+    }
+
+    GLOBAL_CONFIG
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_else(|| Arc::clone(&ENV_CONFIG))
+}
@@ -0,0 +1,340 @@
+//! Recovering `chrono` date and time types (the `chrono` feature).
+//!
+//! schemars describes `DateTime<Tz>`, `NaiveDate` and `NaiveDateTime` as strings with a
+//! `date-time`, `date` or `partial-date-time` format, which models often ignore:
+//! `"2024-05-01 10:00"`, `"May 1, 2024"` or a unix timestamp all come back where chrono
+//! only accepts RFC 3339. [`with_format_hints`] spells the expected format out in the
+//! schema and [`normalize_dates`] rewrites the common alternatives before
+//! deserializing. Both run automatically for every recovery when the feature is on.
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+use crate::schema::resolve_ref;
+
+const MAX_DEPTH: usize = 8;
+
+// Timestamps without an offset are taken as UTC
+const NAIVE_DATE_TIME_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+    "%Y/%m/%d %H:%M:%S",
+    "%Y/%m/%d %H:%M",
+];
+
+const OFFSET_DATE_TIME_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f%:z",
+    "%Y-%m-%d %H:%M:%S%.f %:z",
+    "%Y-%m-%d %H:%M:%S%.f%z",
+    "%Y-%m-%d %H:%M:%S%.f %z",
+];
+
+// Day first and month first numeric dates are ambiguous and left alone
+const DATE_FORMATS: &[&str] = &[
+    "%Y-%m-%d",
+    "%Y/%m/%d",
+    "%B %d, %Y",
+    "%b %d, %Y",
+    "%d %B %Y",
+    "%d %b %Y",
+];
+
+/// Add the expected format to the description of every date and time string in
+/// `schema`, e.g. "RFC 3339 timestamp, such as 2024-05-01T13:45:00Z".
+pub fn with_format_hints(mut schema: Value) -> Value {
+    add_hints(&mut schema, 0);
+    schema
+}
+
+fn add_hints(node: &mut Value, depth: usize) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    match node {
+        Value::Object(object) => {
+            if let Some(hint) = object
+                .get("format")
+                .and_then(Value::as_str)
+                .and_then(format_hint)
+            {
+                let description = match object.get("description").and_then(Value::as_str) {
+                    Some(description) => {
+                        format!("{} ({})", description.trim_end_matches('.'), hint)
+                    }
+                    None => hint.to_string(),
+                };
+                object.insert("description".to_string(), Value::String(description));
+            }
+            for child in object.values_mut() {
+                add_hints(child, depth + 1);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                add_hints(item, depth + 1);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn format_hint(format: &str) -> Option<&'static str> {
+    match format {
+        "date-time" => Some("RFC 3339 timestamp with offset, such as 2024-05-01T13:45:00Z"),
+        "date" => Some("date as YYYY-MM-DD, such as 2024-05-01"),
+        "partial-date-time" => Some("date and time without offset, such as 2024-05-01T13:45:00"),
+        _ => None,
+    }
+}
+
+/// Rewrite dates the model wrote in another common format into the one `schema` asks for.
+///
+/// Applies to strings (and, for timestamps, unix seconds or milliseconds) where the schema
+/// has a `date-time`, `date` or `partial-date-time` format. A timestamp without an offset
+/// is taken as UTC. Anything that doesn't parse is left for deserialization to reject.
+pub fn normalize_dates(value: Value, schema: &Value) -> Value {
+    normalize(value, schema, schema, 0)
+}
+
+fn normalize(value: Value, node: &Value, root: &Value, depth: usize) -> Value {
+    let Some(node) = node.as_object().filter(|_| depth <= MAX_DEPTH) else {
+        return value;
+    };
+
+    if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+        return match resolve_ref(root, reference) {
+            Some(target) => normalize(value, target, root, depth + 1),
+            None => value,
+        };
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(variants) = node.get(key).and_then(Value::as_array) {
+            // The first variant that rewrites the value wins
+            for variant in variants {
+                let normalized = normalize(value.clone(), variant, root, depth + 1);
+                if normalized != value {
+                    return normalized;
+                }
+            }
+            return value;
+        }
+    }
+
+    if let Some(format) = node
+        .get("format")
+        .and_then(Value::as_str)
+        .filter(|format| format_hint(format).is_some())
+    {
+        return normalize_leaf(value, format);
+    }
+
+    match value {
+        Value::Object(object) => {
+            let properties = node.get("properties").and_then(Value::as_object);
+            let values = node.get("additionalProperties").filter(|v| v.is_object());
+            Value::Object(normalize_fields(object, properties, values, root, depth))
+        }
+        Value::Array(items) => {
+            let positions = node.get("prefixItems").and_then(Value::as_array);
+            let item_schema = node.get("items");
+            Value::Array(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .map(
+                        |(i, item)| match positions.and_then(|p| p.get(i)).or(item_schema) {
+                            Some(schema) => normalize(item, schema, root, depth + 1),
+                            None => item,
+                        },
+                    )
+                    .collect(),
+            )
+        }
+        other => other,
+    }
+}
+
+fn normalize_fields(
+    object: Map<String, Value>,
+    properties: Option<&Map<String, Value>>,
+    values: Option<&Value>,
+    root: &Value,
+    depth: usize,
+) -> Map<String, Value> {
+    object
+        .into_iter()
+        .map(|(key, field)| {
+            let field = match properties.and_then(|p| p.get(&key)).or(values) {
+                Some(field_schema) => normalize(field, field_schema, root, depth + 1),
+                None => field,
+            };
+            (key, field)
+        })
+        .collect()
+}
+
+fn normalize_leaf(value: Value, format: &str) -> Value {
+    let parsed = match &value {
+        Value::String(text) => parse_timestamp(text.trim()),
+        Value::Number(number) if format == "date-time" => number.as_i64().and_then(from_unix),
+        _ => None,
+    };
+    let Some(timestamp) = parsed else {
+        return value;
+    };
+
+    match format {
+        "date-time" => Value::String(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        "date" => Value::String(
+            timestamp
+                .naive_local()
+                .date()
+                .format("%Y-%m-%d")
+                .to_string(),
+        ),
+        "partial-date-time" => Value::String(
+            timestamp
+                .naive_local()
+                .format("%Y-%m-%dT%H:%M:%S%.f")
+                .to_string(),
+        ),
+        _ => value,
+    }
+}
+
+fn parse_timestamp(text: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(text) {
+        return Some(timestamp);
+    }
+    if let Ok(timestamp) = DateTime::parse_from_rfc2822(text) {
+        return Some(timestamp);
+    }
+    if let Some(timestamp) = OFFSET_DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| DateTime::parse_from_str(text, format).ok())
+    {
+        return Some(timestamp);
+    }
+    let naive = NAIVE_DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+                .map(|date| date.and_time(NaiveTime::MIN))
+        });
+    match naive {
+        Some(naive) => Some(naive.and_utc().fixed_offset()),
+        None => text.parse::<i64>().ok().and_then(from_unix),
+    }
+}
+
+// Seconds, or milliseconds once the number is too large to be a plausible date in
+// seconds. Short numbers such as `20240501` are more likely something else.
+fn from_unix(timestamp: i64) -> Option<DateTime<FixedOffset>> {
+    let timestamp = if timestamp.abs() < 100_000_000 {
+        None
+    } else if timestamp.abs() >= 100_000_000_000 {
+        DateTime::<Utc>::from_timestamp_millis(timestamp)
+    } else {
+        DateTime::<Utc>::from_timestamp(timestamp, 0)
+    };
+    timestamp.map(|timestamp| timestamp.fixed_offset())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_common_formats_become_rfc_3339() {
+        let schema = json!({ "type": "string", "format": "date-time" });
+        let normalized = |value: Value| normalize_dates(value, &schema);
+
+        assert_eq!(
+            normalized(json!("2024-05-01 10:00:00")),
+            "2024-05-01T10:00:00Z"
+        );
+        assert_eq!(
+            normalized(json!("2024-05-01T10:00")),
+            "2024-05-01T10:00:00Z"
+        );
+        assert_eq!(normalized(json!("2024-05-01")), "2024-05-01T00:00:00Z");
+        assert_eq!(normalized(json!("May 1, 2024")), "2024-05-01T00:00:00Z");
+        assert_eq!(
+            normalized(json!("2024-05-01 10:00:00 +02:00")),
+            "2024-05-01T10:00:00+02:00"
+        );
+        assert_eq!(
+            normalized(json!("Wed, 01 May 2024 10:00:00 +0000")),
+            "2024-05-01T10:00:00Z"
+        );
+        assert_eq!(normalized(json!(1714557600)), "2024-05-01T10:00:00Z");
+        assert_eq!(normalized(json!("1714557600000")), "2024-05-01T10:00:00Z");
+        // Left for deserialization to reject
+        assert_eq!(normalized(json!("next Tuesday")), "next Tuesday");
+        assert_eq!(normalized(json!("01/05/2024")), "01/05/2024");
+    }
+
+    #[test]
+    fn test_nested_and_optional_dates() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "title": { "type": "string" },
+                "due": { "type": ["string", "null"], "format": "date" },
+                "events": { "type": "array", "items": { "$ref": "#/$defs/Event" } }
+            },
+            "$defs": {
+                "Event": {
+                    "type": "object",
+                    "properties": { "at": { "type": "string", "format": "partial-date-time" } }
+                }
+            }
+        });
+        let value = json!({
+            "title": "2024-05-01 10:00",
+            "due": "2024-06-30T23:00:00-02:00",
+            "events": [{ "at": "2024/05/01 09:30" }]
+        });
+
+        assert_eq!(
+            normalize_dates(value, &schema),
+            json!({
+                "title": "2024-05-01 10:00",
+                "due": "2024-06-30",
+                "events": [{ "at": "2024-05-01T09:30:00" }]
+            })
+        );
+        assert_eq!(
+            normalize_dates(json!({ "due": null }), &schema),
+            json!({ "due": null })
+        );
+    }
+
+    #[test]
+    fn test_format_hints_are_added_to_descriptions() {
+        let schema = with_format_hints(json!({
+            "type": "object",
+            "properties": {
+                "starts_at": { "type": "string", "format": "date-time", "description": "When it starts." },
+                "day": { "type": "string", "format": "date" },
+                "id": { "type": "string", "format": "uuid" }
+            }
+        }));
+
+        assert_eq!(
+            schema["properties"]["starts_at"]["description"],
+            "When it starts (RFC 3339 timestamp with offset, such as 2024-05-01T13:45:00Z)"
+        );
+        assert_eq!(
+            schema["properties"]["day"]["description"],
+            "date as YYYY-MM-DD, such as 2024-05-01"
+        );
+        assert!(schema["properties"]["id"].get("description").is_none());
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::prompt::is_identifier_name;
+use crate::recover::RecoveryContext;
+
+/// Integer and float suffixes stripped from literal arguments, e.g. `42u32`
+const NUMERIC_SUFFIXES: &[&str] = &[
+    "u128", "i128", "usize", "isize", "u64", "i64", "u32", "i32", "u16", "i16", "f64", "f32", "u8",
+    "i8",
+];
+
+type FieldMatcher = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Checks that a recovered value echoes the arguments it was requested with.
+///
+/// Asking for `get_user(42)` and getting back a user with `id: 7` means the model ignored
+/// the request. After each recovery, every argument written as a literal is compared with
+/// the recovered object's fields its parameter is matched to, and mismatches are logged
+/// with `log::warn!`. Arguments passed as variables or expressions can't be compared.
+#[derive(Clone)]
+pub struct DriftCheck {
+    matches: FieldMatcher,
+}
+
+impl fmt::Debug for DriftCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DriftCheck").finish_non_exhaustive()
+    }
+}
+
+impl Default for DriftCheck {
+    fn default() -> Self {
+        Self::id_fields()
+    }
+}
+
+impl DriftCheck {
+    /// Match id-like parameters (see [`is_identifier_name`]): `id` to the `id` field,
+    /// and `user_id` to either a `user_id` or an `id` field
+    pub fn id_fields() -> Self {
+        Self::new(|param, field| is_identifier_name(param) && (param == field || field == "id"))
+    }
+
+    /// Match parameters to fields with `matches(parameter_name, field_name)`
+    pub fn new(matches: impl Fn(&str, &str) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            matches: Arc::new(matches),
+        }
+    }
+
+    /// The matched fields of `recovered` that differ from the literal arguments in `context`
+    pub fn find_drift(&self, context: &RecoveryContext, recovered: &Value) -> Vec<Drift> {
+        let Some(fields) = recovered.as_object() else {
+            return Vec::new();
+        };
+
+        let mut drift = Vec::new();
+        for (param, arg) in context.params.iter().zip(&context.args) {
+            let Some(argument) = literal_value(arg) else {
+                continue;
+            };
+            for (field, value) in fields {
+                if (self.matches)(param, field) && !same_value(&argument, value) {
+                    drift.push(Drift {
+                        param: param.clone(),
+                        field: field.clone(),
+                        argument: argument.clone(),
+                        recovered: value.clone(),
+                    });
+                }
+            }
+        }
+        drift
+    }
+
+    // Called after every successful recovery when a check is configured
+    pub(crate) fn warn_on_drift(&self, context: &RecoveryContext, recovered: &Value) {
+        for drift in self.find_drift(context, recovered) {
+            log::warn!("AI recovery of {} drifted: {}", context.fn_name, drift);
+        }
+    }
+}
+
+/// A recovered field that doesn't echo the argument it should match
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub param: String,
+    pub field: String,
+    /// The argument's value, as written at the call site
+    pub argument: Value,
+    pub recovered: Value,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` was {} but the recovered `{}` is {}",
+            self.param, self.argument, self.field, self.recovered
+        )
+    }
+}
+
+// The JSON value of a literal argument such as `42`, `7u64`, `"ada"` or `true`
+fn literal_value(arg: &str) -> Option<Value> {
+    let arg = arg.trim();
+    if let Ok(value) = serde_json::from_str::<Value>(arg)
+        && !value.is_array()
+        && !value.is_object()
+    {
+        return Some(value);
+    }
+
+    let number = NUMERIC_SUFFIXES
+        .iter()
+        .find_map(|suffix| arg.strip_suffix(suffix))?
+        .trim_end_matches('_')
+        .replace('_', "");
+    serde_json::from_str::<Value>(&number)
+        .ok()
+        .filter(Value::is_number)
+}
+
+// `42` and `42.0` are the same id
+fn same_value(argument: &Value, recovered: &Value) -> bool {
+    match (argument.as_f64(), recovered.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => argument == recovered,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn context(params: &[&str], args: &[&str]) -> RecoveryContext {
+        RecoveryContext {
+            fn_name: "get_user".to_string(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_id_drift_is_found() {
+        let check = DriftCheck::id_fields();
+        let context = context(&["user_id", "name"], &["42u32", "\"ada\""]);
+
+        let drift = check.find_drift(&context, &json!({ "id": 7, "name": "bob" }));
+        assert_eq!(
+            drift,
+            [Drift {
+                param: "user_id".to_string(),
+                field: "id".to_string(),
+                argument: json!(42),
+                recovered: json!(7),
+            }]
+        );
+        assert_eq!(
+            drift[0].to_string(),
+            "`user_id` was 42 but the recovered `id` is 7"
+        );
+
+        assert!(
+            check
+                .find_drift(&context, &json!({ "id": 42.0 }))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_variables_and_custom_matches() {
+        let check = DriftCheck::id_fields();
+        assert!(
+            check
+                .find_drift(&context(&["id"], &["user.id"]), &json!({ "id": 7 }))
+                .is_empty()
+        );
+
+        let by_name = DriftCheck::new(|param, field| param == field);
+        let drift = by_name.find_drift(
+            &context(&["id", "name"], &["1", "\"ada\""]),
+            &json!({ "id": 1, "name": "bob" }),
+        );
+        assert_eq!(drift.len(), 1);
+        assert_eq!(drift[0].field, "name");
+    }
+}
@@ -0,0 +1,278 @@
+//! Recovery of trait objects, by letting the model pick one of the registered
+//! implementors.
+//!
+//! Each implementor of a trait is registered under a name with [`register_dyn`]. The model
+//! is then asked for `{"implementor": <name>, "value": <that implementor's fields>}`, and
+//! [`recover_dyn`] deserializes `value` as the implementor it named:
+//!
+//! ```no_run
+//! use unwrap_or_ai::RecoveryContext;
+//! use unwrap_or_ai::dynamic::{recover_dyn, register_dyn};
+//!
+//! trait Shape {
+//!     fn area(&self) -> f64;
+//! }
+//!
+//! /// A circle, by radius
+//! #[derive(serde::Deserialize, schemars::JsonSchema)]
+//! struct Circle {
+//!     radius: f64,
+//! }
+//!
+//! #[derive(serde::Deserialize, schemars::JsonSchema)]
+//! struct Square {
+//!     side: f64,
+//! }
+//!
+//! impl Shape for Circle {
+//!     fn area(&self) -> f64 {
+//!         std::f64::consts::PI * self.radius * self.radius
+//!     }
+//! }
+//!
+//! impl Shape for Square {
+//!     fn area(&self) -> f64 {
+//!         self.side * self.side
+//!     }
+//! }
+//!
+//! # async fn run() -> Result<(), unwrap_or_ai::AiRecoveryError> {
+//! register_dyn::<dyn Shape, Circle>("circle", |circle| Box::new(circle));
+//! register_dyn::<dyn Shape, Square>("square", |square| Box::new(square));
+//!
+//! let context =
+//!     RecoveryContext::new("load_logo_shape").with_original_error("logo.svg is missing");
+//! let shape: Box<dyn Shape> = recover_dyn::<dyn Shape>(context).await?;
+//! println!("area: {}", shape.area());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Doc comments on the implementors end up in their schemas, which helps the model
+//! choose between them.
+
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::de::{Deserialize, Deserializer, Error as _};
+use serde_json::Value;
+
+use crate::error::{AiError, AiRecoveryError};
+use crate::groq_client::register_schema;
+use crate::prompt::short_type_name;
+use crate::recover::{RecoveryContext, recover};
+use crate::recoverable::Recoverable;
+
+type IntoDyn<D> = Arc<dyn Fn(Value) -> Result<Box<D>, serde_json::Error> + Send + Sync>;
+
+// A concrete type registered for the trait object `D`
+struct Implementor<D: ?Sized> {
+    name: String,
+    schema: fn(&mut SchemaGenerator) -> Schema,
+    deserialize: IntoDyn<D>,
+}
+
+impl<D: ?Sized> Clone for Implementor<D> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            schema: self.schema,
+            deserialize: Arc::clone(&self.deserialize),
+        }
+    }
+}
+
+// `Vec<Implementor<D>>` for each trait object type `D`
+static IMPLEMENTORS: LazyLock<RwLock<HashMap<TypeId, Box<dyn Any + Send + Sync>>>> =
+    LazyLock::new(Default::default);
+
+/// Register `T` as an implementor of the trait object `D` that the model may choose as
+/// `name`; `into_dyn` boxes it, which is usually just `|value| Box::new(value)`.
+///
+/// Registering a name again replaces the previous implementor.
+pub fn register_dyn<D, T>(name: impl Into<String>, into_dyn: fn(T) -> Box<D>)
+where
+    D: ?Sized + 'static,
+    T: Recoverable,
+{
+    let name = name.into();
+    {
+        let mut registry = IMPLEMENTORS.write().unwrap_or_else(|e| e.into_inner());
+        let implementors = registry
+            .entry(TypeId::of::<D>())
+            .or_insert_with(|| Box::new(Vec::<Implementor<D>>::new()))
+            .downcast_mut::<Vec<Implementor<D>>>()
+            .expect("implementors are keyed by their trait object's TypeId");
+
+        implementors.retain(|implementor| implementor.name != name);
+        implementors.push(Implementor {
+            name,
+            schema: |generator| generator.subschema_for::<T>(),
+            deserialize: Arc::new(move |value| serde_json::from_value::<T>(value).map(into_dyn)),
+        });
+    }
+
+    // Schemas are memoized per type, so the one listing the implementors is replaced
+    register_schema::<DynChoice<D>>(schemars::schema_for!(DynChoice<D>).to_value());
+}
+
+/// Ask the model to choose one of the implementors registered for `D` and recover it.
+///
+/// Fails with [`AiError::NoImplementors`] when none are registered.
+pub async fn recover_dyn<D>(context: RecoveryContext) -> Result<Box<D>, AiRecoveryError>
+where
+    D: ?Sized + 'static,
+{
+    if implementors::<D>().is_empty() {
+        return Err(context.into_error(AiError::NoImplementors(std::any::type_name::<D>())));
+    }
+
+    recover::<DynChoice<D>>(context)
+        .await
+        .map(|choice| choice.0)
+}
+
+fn implementors<D: ?Sized + 'static>() -> Vec<Implementor<D>> {
+    IMPLEMENTORS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&TypeId::of::<D>())
+        .and_then(|implementors| implementors.downcast_ref::<Vec<Implementor<D>>>())
+        .cloned()
+        .unwrap_or_default()
+}
+
+// What the model is asked for: the name of an implementor and its value
+struct DynChoice<D: ?Sized>(Box<D>);
+
+impl<D: ?Sized + 'static> JsonSchema for DynChoice<D> {
+    fn schema_name() -> Cow<'static, str> {
+        short_type_name::<D>().into()
+    }
+
+    fn inline_schema() -> bool {
+        true
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        let implementors = implementors::<D>();
+        let names: Vec<_> = implementors.iter().map(|i| i.name.clone()).collect();
+        let values: Vec<_> = implementors
+            .iter()
+            .map(|i| (i.schema)(generator).to_value())
+            .collect();
+
+        json_schema!({
+            "type": "object",
+            "properties": {
+                "implementor": {
+                    "description": "Which implementation `value` is",
+                    "type": "string",
+                    "enum": names
+                },
+                "value": { "anyOf": values }
+            },
+            "required": ["implementor", "value"],
+            "additionalProperties": false
+        })
+    }
+}
+
+impl<'de, D: ?Sized + 'static> Deserialize<'de> for DynChoice<D> {
+    fn deserialize<De: Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        #[derive(serde::Deserialize)]
+        struct Tagged {
+            implementor: String,
+            value: Value,
+        }
+
+        let tagged = Tagged::deserialize(deserializer)?;
+        let implementors = implementors::<D>();
+        let implementor = implementors
+            .iter()
+            .find(|i| i.name == tagged.implementor)
+            .ok_or_else(|| {
+                let names: Vec<_> = implementors.iter().map(|i| i.name.as_str()).collect();
+                De::Error::custom(format!(
+                    "unknown implementor `{}`, expected one of {:?}",
+                    tagged.implementor, names
+                ))
+            })?;
+
+        (implementor.deserialize)(tagged.value)
+            .map(DynChoice)
+            .map_err(De::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    trait Animal {
+        fn speak(&self) -> String;
+    }
+
+    #[derive(serde::Deserialize, JsonSchema)]
+    struct Dog {
+        name: String,
+    }
+
+    #[derive(serde::Deserialize, JsonSchema)]
+    struct Cat {
+        lives: u8,
+    }
+
+    impl Animal for Dog {
+        fn speak(&self) -> String {
+            format!("{} says woof", self.name)
+        }
+    }
+
+    impl Animal for Cat {
+        fn speak(&self) -> String {
+            format!("meow ({} lives)", self.lives)
+        }
+    }
+
+    #[test]
+    fn test_implementor_is_chosen_by_name() {
+        register_dyn::<dyn Animal, Dog>("dog", |dog| Box::new(dog));
+        register_dyn::<dyn Animal, Cat>("cat", |cat| Box::new(cat));
+
+        let schema = crate::groq_client::recovery_schema::<DynChoice<dyn Animal>>()
+            .unwrap()
+            .1;
+        assert_eq!(
+            schema["properties"]["implementor"]["enum"],
+            json!(["dog", "cat"])
+        );
+        assert_eq!(
+            schema["properties"]["value"]["anyOf"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let cat: DynChoice<dyn Animal> =
+            serde_json::from_value(json!({ "implementor": "cat", "value": { "lives": 9 } }))
+                .unwrap();
+        assert_eq!(cat.0.speak(), "meow (9 lives)");
+
+        let unknown = serde_json::from_value::<DynChoice<dyn Animal>>(
+            json!({ "implementor": "parrot", "value": {} }),
+        );
+        assert!(
+            unknown
+                .err()
+                .unwrap()
+                .to_string()
+                .contains("unknown implementor `parrot`")
+        );
+    }
+}
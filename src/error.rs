@@ -0,0 +1,428 @@
+use std::fmt;
+
+/// Errors produced while asking a provider for a recovered value
+#[derive(Debug)]
+pub enum AiError {
+    /// No API key was configured or found in the environment for a provider
+    MissingApiKey { provider: String, env_var: String },
+    /// The provider's API key callback returned an error
+    ApiKeyProvider {
+        provider: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The request never produced a response (connection refused, DNS, TLS, timeout)
+    Network {
+        source: Box<dyn std::error::Error + Send + Sync>,
+        request_id: Option<String>,
+    },
+    /// The provider answered with a non-success HTTP status
+    Api {
+        status: u16,
+        body: String,
+        request_id: Option<String>,
+    },
+    /// The provider answered successfully but without any choices
+    NoChoices { request_id: Option<String> },
+    /// The response body or the model's content was not the JSON we expected
+    Json {
+        source: serde_json::Error,
+        request_id: Option<String>,
+    },
+    /// The model's content was JSON but didn't fit the target type; holds where it went
+    /// wrong (`age`, `items[0].sku`, or `.` for the whole answer), the JSON found there,
+    /// shortened if long, and the whole answer
+    Deserialize {
+        path: String,
+        snippet: String,
+        value: serde_json::Value,
+        source: serde_json::Error,
+        request_id: Option<String>,
+    },
+    /// The model declined to answer, either through the provider's `refusal` field or
+    /// with prose instead of JSON; holds what it said
+    ModelRefused {
+        message: String,
+        request_id: Option<String>,
+    },
+    /// The configuration did not list any provider to try
+    NoProviders,
+    /// Every configured provider failed; holds each provider's name and error in order
+    AllProvidersFailed(Vec<(String, AiError)>),
+    /// The recovery was cancelled before a provider answered
+    Cancelled,
+    /// No provider answered within the time allowed by [`Recovery::timeout`](crate::Recovery::timeout)
+    /// or [`RecoveryConfig::with_timeout`](crate::RecoveryConfig::with_timeout)
+    Timeout(std::time::Duration),
+    /// The answer left out properties the schema requires, and
+    /// [fail-closed](crate::RecoveryConfig::with_fail_closed) recovery was asked for or
+    /// the [retries](crate::RecoveryConfig::with_required_field_retries) ran out; holds
+    /// their paths, e.g. `address.city` or `items[0].sku`
+    Incomplete {
+        missing: Vec<String>,
+        request_id: Option<String>,
+    },
+    /// A number in the answer fell far outside the range seen in the examples registered
+    /// with [`plausibility::register_examples`](crate::plausibility::register_examples);
+    /// holds a description of each, e.g. `price = 1000000 (examples range from 3.5 to 6)`
+    Implausible {
+        fields: Vec<String>,
+        request_id: Option<String>,
+    },
+    /// [`recover_vec`](crate::recover_vec) kept getting a list of the wrong length
+    WrongLength {
+        expected: usize,
+        actual: usize,
+        request_id: Option<String>,
+    },
+    /// The recovered value could not be written in the format asked of
+    /// [`recover_as`](crate::recover_as)
+    Encode {
+        format: &'static str,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Every recovered value was rejected by the predicate given to
+    /// [`Recovery::recover_where`](crate::Recovery::recover_where)
+    Rejected,
+    /// The tokens spent by this process reached the limit set with
+    /// [`RecoveryConfig::with_token_budget`](crate::RecoveryConfig::with_token_budget),
+    /// so no provider was asked
+    TokenBudgetExhausted { budget: u64 },
+    /// [`recover_dyn`](crate::dynamic::recover_dyn) was called for a trait object with
+    /// no registered implementors; holds the trait object's type name
+    NoImplementors(&'static str),
+    /// Recovery was turned off with
+    /// [`RecoveryConfig::with_ai_disabled`](crate::RecoveryConfig::with_ai_disabled), or
+    /// the crate was built with the `no-ai` feature or without `network`, so no provider
+    /// is asked
+    Disabled,
+}
+
+/// What a non-success HTTP status from a provider means for the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    /// 401 or 403: the API key is missing, wrong or lacks access
+    Auth,
+    /// 429: too many requests or tokens; worth retrying later
+    RateLimited,
+    /// 5xx: the provider failed; worth retrying or trying another provider
+    Server,
+    /// Any other 4xx: the request itself was rejected
+    Client,
+}
+
+impl ApiErrorKind {
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            401 | 403 => ApiErrorKind::Auth,
+            429 => ApiErrorKind::RateLimited,
+            500.. => ApiErrorKind::Server,
+            _ => ApiErrorKind::Client,
+        }
+    }
+}
+
+impl AiError {
+    /// Whether the next provider in the list should be tried after this error.
+    ///
+    /// Connection problems, server errors, rate limits and unconfigured providers move
+    /// on; anything else (bad request, bad key, unparseable output) would fail the same
+    /// way elsewhere.
+    pub fn should_try_next_provider(&self) -> bool {
+        match self {
+            AiError::Network { .. }
+            | AiError::MissingApiKey { .. }
+            | AiError::ApiKeyProvider { .. } => true,
+            AiError::Api { .. } => matches!(
+                self.api_error_kind(),
+                Some(ApiErrorKind::Server | ApiErrorKind::RateLimited)
+            ),
+            _ => false,
+        }
+    }
+
+    /// How an [`AiError::Api`] status should be treated; `None` for other errors
+    pub fn api_error_kind(&self) -> Option<ApiErrorKind> {
+        match self {
+            AiError::Api { status, .. } => Some(ApiErrorKind::from_status(*status)),
+            _ => None,
+        }
+    }
+
+    /// Whether the provider refused the request because of its JSON schema (or
+    /// `response_format` as a whole), judging by the 400 response body.
+    ///
+    /// OpenAI-compatible providers report these with an `invalid_json_schema` code, a
+    /// `response_format` param, or a message naming `response_format` or `json_schema`;
+    /// other validation errors, even ones mentioning a schema, don't count.
+    ///
+    /// Retrying the same request is pointless, but JSON mode may still work.
+    pub fn is_schema_rejection(&self) -> bool {
+        let AiError::Api {
+            status: 400, body, ..
+        } = self
+        else {
+            return false;
+        };
+
+        let error = serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|value| value.get("error").cloned());
+        let field = |name: &str| {
+            error
+                .as_ref()
+                .and_then(|error| error.get(name))
+                .and_then(serde_json::Value::as_str)
+        };
+        if field("code") == Some("invalid_json_schema")
+            || field("param").is_some_and(|param| param.starts_with("response_format"))
+        {
+            return true;
+        }
+
+        let message = field("message").unwrap_or(body);
+        ["response_format", "json_schema"]
+            .iter()
+            .any(|name| message.contains(name))
+    }
+
+    /// The `X-Request-Id` sent with the request that failed, for matching up with the
+    /// provider's logs. For [`AiError::AllProvidersFailed`] this is the first one found.
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            AiError::AllProvidersFailed(failures) => {
+                failures.iter().find_map(|(_, err)| err.request_id())
+            }
+            _ => self.own_request_id(),
+        }
+    }
+
+    fn own_request_id(&self) -> Option<&str> {
+        match self {
+            AiError::Network { request_id, .. }
+            | AiError::Api { request_id, .. }
+            | AiError::NoChoices { request_id }
+            | AiError::Json { request_id, .. }
+            | AiError::Deserialize { request_id, .. }
+            | AiError::ModelRefused { request_id, .. }
+            | AiError::Incomplete { request_id, .. }
+            | AiError::Implausible { request_id, .. }
+            | AiError::WrongLength { request_id, .. } => request_id.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Tag an error raised while handling the request identified by `id`
+    pub(crate) fn with_request_id(mut self, id: &str) -> Self {
+        match &mut self {
+            AiError::Network { request_id, .. }
+            | AiError::Api { request_id, .. }
+            | AiError::NoChoices { request_id }
+            | AiError::Json { request_id, .. }
+            | AiError::Deserialize { request_id, .. }
+            | AiError::ModelRefused { request_id, .. }
+            | AiError::Incomplete { request_id, .. }
+            | AiError::Implausible { request_id, .. }
+            | AiError::WrongLength { request_id, .. } => {
+                request_id.get_or_insert_with(|| id.to_string());
+            }
+            _ => {}
+        }
+        self
+    }
+}
+
+impl fmt::Display for AiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AiError::MissingApiKey { env_var, .. } => {
+                write!(f, "{} environment variable not set", env_var)
+            }
+            AiError::ApiKeyProvider { provider, source } => {
+                write!(
+                    f,
+                    "Could not fetch the API key for {}: {}",
+                    provider, source
+                )
+            }
+            AiError::Network { source, .. } => write!(f, "Network error: {}", source),
+            AiError::Api { status, body, .. } => match ApiErrorKind::from_status(*status) {
+                ApiErrorKind::Auth => write!(
+                    f,
+                    "API authentication failed with status {}, check the API key: {}",
+                    status, body
+                ),
+                ApiErrorKind::RateLimited => {
+                    write!(f, "Rate limited by the API (status {}): {}", status, body)
+                }
+                ApiErrorKind::Server => {
+                    write!(f, "API server error with status {}: {}", status, body)
+                }
+                ApiErrorKind::Client => {
+                    write!(f, "API request failed with status {}: {}", status, body)
+                }
+            },
+            AiError::NoChoices { .. } => write!(f, "No choices in response"),
+            AiError::Json { source, .. } => write!(f, "Invalid JSON in response: {}", source),
+            AiError::Deserialize {
+                path,
+                snippet,
+                source,
+                ..
+            } => write!(
+                f,
+                "The answer doesn't fit the target type at {}: {}, got {}",
+                path, source, snippet
+            ),
+            AiError::ModelRefused { message, .. } => {
+                write!(f, "The model refused to answer: {}", message)
+            }
+            AiError::NoProviders => write!(f, "No AI providers configured"),
+            AiError::Cancelled => write!(f, "AI recovery was cancelled"),
+            AiError::Timeout(timeout) => write!(f, "AI recovery timed out after {:?}", timeout),
+            AiError::Incomplete { missing, .. } => {
+                write!(f, "The recovered value is missing {}", missing.join(", "))
+            }
+            AiError::WrongLength {
+                expected, actual, ..
+            } => write!(
+                f,
+                "The recovered list has {} items instead of {}",
+                actual, expected
+            ),
+            AiError::Implausible { fields, .. } => {
+                write!(
+                    f,
+                    "The recovered value is implausible: {}",
+                    fields.join(", ")
+                )
+            }
+            AiError::Encode { format, source } => {
+                write!(
+                    f,
+                    "Could not write the recovered value as {}: {}",
+                    format, source
+                )
+            }
+            AiError::Rejected => write!(f, "The recovered value failed validation"),
+            AiError::Disabled => write!(f, "AI recovery is disabled"),
+            AiError::TokenBudgetExhausted { budget } => {
+                write!(f, "AI recovery token budget of {} is used up", budget)
+            }
+            AiError::NoImplementors(target) => {
+                write!(
+                    f,
+                    "No implementors of {} are registered for recovery",
+                    target
+                )
+            }
+            AiError::AllProvidersFailed(failures) => {
+                write!(f, "All AI providers failed")?;
+                for (provider, err) in failures {
+                    write!(f, "; {}: {}", provider, err)?;
+                }
+                Ok(())
+            }
+        }?;
+
+        match self.own_request_id() {
+            Some(request_id) => write!(f, " (request id {})", request_id),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for AiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AiError::Network { source, .. } => Some(source.as_ref()),
+            AiError::ApiKeyProvider { source, .. } => Some(source.as_ref()),
+            AiError::Encode { source, .. } => Some(source.as_ref()),
+            AiError::Json { source, .. } => Some(source),
+            AiError::Deserialize { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for AiError {
+    fn from(err: serde_json::Error) -> Self {
+        AiError::Json {
+            source: err,
+            request_id: None,
+        }
+    }
+}
+
+#[cfg(feature = "network")]
+impl From<reqwest::Error> for AiError {
+    fn from(err: reqwest::Error) -> Self {
+        AiError::Network {
+            source: Box::new(err),
+            request_id: None,
+        }
+    }
+}
+
+/// A recovery that could not produce a value.
+///
+/// Returned by the `try_` variants of the recovery API. The AI failure is available
+/// through [`AiRecoveryError::cause`], and the error the original function returned
+/// (if any) is reported as the [`source`](std::error::Error::source).
+#[derive(Debug)]
+pub struct AiRecoveryError {
+    cause: AiError,
+    original: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl AiRecoveryError {
+    pub fn new(cause: AiError) -> Self {
+        Self {
+            cause,
+            original: None,
+        }
+    }
+
+    /// Attach the error the recovered function originally returned
+    pub fn with_original(
+        mut self,
+        original: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        self.original = Some(original.into());
+        self
+    }
+
+    /// Why the AI could not provide a value
+    pub fn cause(&self) -> &AiError {
+        &self.cause
+    }
+
+    /// The error returned by the function whose failure triggered the recovery
+    pub fn original_error(&self) -> Option<&(dyn std::error::Error + Send + Sync + 'static)> {
+        self.original.as_deref()
+    }
+
+    pub fn into_cause(self) -> AiError {
+        self.cause
+    }
+}
+
+impl fmt::Display for AiRecoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AI recovery failed: {}", self.cause)
+    }
+}
+
+impl std::error::Error for AiRecoveryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // The cause is already part of the `Display` text, so chains don't repeat it
+        self.original
+            .as_deref()
+            .map(|original| original as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<AiError> for AiRecoveryError {
+    fn from(cause: AiError) -> Self {
+        Self::new(cause)
+    }
+}
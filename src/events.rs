@@ -0,0 +1,74 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::groq_client::GroqUsage;
+use crate::partial::FieldChange;
+
+/// Events buffered per subscriber; a receiver that falls further behind gets `Lagged`
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// What happened while asking one provider for a recovered value.
+///
+/// Every provider attempt emits `Started` followed by either `Finished` or `Failed`;
+/// attempts belonging to the same recovery share a `request_id`. A partial recovery
+/// also emits `Filled` once its value is complete.
+#[derive(Debug, Clone)]
+pub enum RecoveryEvent {
+    Started {
+        request_id: String,
+        /// The failed function, when the recovery came from `unwrap_or_ai!` or `recover`
+        fn_name: Option<String>,
+        /// Hash of the failed function's source, when it is annotated with
+        /// `#[unwrap_or_ai_func]`; changes whenever its implementation does
+        source_hash: Option<u64>,
+        provider: String,
+        model: String,
+    },
+    Finished {
+        request_id: String,
+        fn_name: Option<String>,
+        provider: String,
+        model: String,
+        latency: Duration,
+        /// Tokens billed for the request; `None` when the value came from the cache
+        usage: Option<GroqUsage>,
+    },
+    Failed {
+        request_id: String,
+        fn_name: Option<String>,
+        provider: String,
+        model: String,
+        latency: Duration,
+        error: String,
+    },
+    /// What [`recover_partial`](crate::partial::recover_partial) changed in the
+    /// partial value, for auditing which data was made up
+    Filled {
+        request_id: String,
+        fn_name: Option<String>,
+        changes: Vec<FieldChange>,
+    },
+}
+
+fn sender() -> &'static broadcast::Sender<RecoveryEvent> {
+    static EVENTS: OnceLock<broadcast::Sender<RecoveryEvent>> = OnceLock::new();
+    EVENTS.get_or_init(|| broadcast::channel(EVENT_CHANNEL_CAPACITY).0)
+}
+
+/// Receive every [`RecoveryEvent`] emitted from now on.
+///
+/// Sending never waits for subscribers, so a slow telemetry task cannot hold up
+/// recoveries; it sees `RecvError::Lagged` instead once it falls behind.
+pub fn subscribe_recovery_events() -> broadcast::Receiver<RecoveryEvent> {
+    sender().subscribe()
+}
+
+pub(crate) fn emit(event: RecoveryEvent) {
+    let sender = sender();
+    if sender.receiver_count() > 0 {
+        // Only fails when the last receiver was dropped in the meantime
+        let _ = sender.send(event);
+    }
+}
@@ -0,0 +1,63 @@
+//! Serialization formats for [`recover_as`](crate::recover_as), for callers that pass the
+//! recovered value straight on to another system.
+//!
+//! [`Json`] is always available and `Yaml` comes with the `yaml` feature. Other formats
+//! only need a [`Format`] impl, e.g. MessagePack through `rmp_serde::to_vec_named`.
+
+use serde::Serialize;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Turns a recovered value into bytes
+pub trait Format {
+    /// Shown in [`AiError::Encode`](crate::AiError::Encode), e.g. `"YAML"`
+    const NAME: &'static str;
+
+    fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BoxError>;
+}
+
+/// Compact JSON
+pub struct Json;
+
+impl Format for Json {
+    const NAME: &'static str = "JSON";
+
+    fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+}
+
+/// YAML, through `serde_yaml_ng`
+#[cfg(feature = "yaml")]
+pub struct Yaml;
+
+#[cfg(feature = "yaml")]
+impl Format for Yaml {
+    const NAME: &'static str = "YAML";
+
+    fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_yaml_ng::to_string(value)?.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_json_is_compact() {
+        let value = BTreeMap::from([("city", "Oslo")]);
+        assert_eq!(Json::to_bytes(&value).unwrap(), br#"{"city":"Oslo"}"#);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_yaml() {
+        let value = BTreeMap::from([("city", "Oslo"), ("conditions", "snow")]);
+        assert_eq!(
+            String::from_utf8(Yaml::to_bytes(&value).unwrap()).unwrap(),
+            "city: Oslo\nconditions: snow\n"
+        );
+    }
+}
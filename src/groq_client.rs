@@ -1,7 +1,42 @@
+//! A small client for OpenAI-compatible chat completion APIs, usable on its own.
+//!
+//! Recovery uses it internally, but it is a supported API for structured completions
+//! that have nothing to do with recovery:
+//!
+//! ```no_run
+//! use unwrap_or_ai::{GroqClient, models};
+//!
+//! #[derive(serde::Deserialize, schemars::JsonSchema)]
+//! struct Sentiment {
+//!     label: String,
+//!     score: f64,
+//! }
+//!
+//! # async fn run() -> Result<(), unwrap_or_ai::AiError> {
+//! let client = GroqClient::new(std::env::var("GROQ_API").unwrap());
+//! let sentiment: Sentiment = client
+//!     .chat_completion_typed(
+//!         models::KIMI_K2,
+//!         vec![("user", "Classify: \"the update broke everything\"")],
+//!     )
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+
 use schemars::JsonSchema;
 use serde::Deserialize;
 use serde_json::json;
 
+use crate::error::AiError;
+use crate::rate_limit::{RateLimitState, RateLimiter};
+use crate::repair::{extract_json, repair_json};
+use crate::schema::{coerce_scalars, conform_to_schema, root_type};
+
 /// Response types for Groq API
 #[derive(Debug, Deserialize)]
 pub struct GroqResponse {
@@ -23,167 +58,748 @@ pub struct GroqChoice {
 #[derive(Debug, Deserialize)]
 pub struct GroqMessage {
     pub role: String,
+    /// Empty when the model refused and the provider sent `null`
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub content: String,
+    /// Why the model declined to answer, sent by OpenAI instead of content
+    #[serde(default)]
+    pub refusal: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 pub struct GroqUsage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
 }
 
+/// A structured completion along with what the model actually sent
+#[derive(Debug)]
+pub struct StructuredResponse<T> {
+    pub parsed: T,
+    /// The JSON `parsed` was read from, after any repairs
+    pub json: serde_json::Value,
+    /// `choices[0].message.content`, exactly as returned
+    pub content: String,
+    pub usage: GroqUsage,
+}
+
+/// How a provider takes the start of an answer written for the model to continue; see
+/// [`GroqClient::with_assistant_prefill`].
+///
+/// Groq, Anthropic and self-hosted servers such as vLLM, llama.cpp and Ollama continue a
+/// trailing assistant message. Mistral and DeepSeek only do when it is marked with
+/// `prefix: true`. OpenAI doesn't support prefilling: it reads the message as an earlier
+/// turn and answers from scratch, which still parses, since the prefill is only put back
+/// in front of answers that don't already start with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrefillStyle {
+    /// A plain trailing assistant message
+    #[default]
+    Message,
+    /// A trailing assistant message with `prefix: true`
+    PrefixFlag,
+}
+
+/// The role names a provider expects, and where it wants the system prompt.
+///
+/// Messages are always passed to [`GroqClient`] with the OpenAI roles `"system"`,
+/// `"user"` and `"assistant"`; they are renamed to these when the request is built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageRoles {
+    pub system: String,
+    pub user: String,
+    pub assistant: String,
+    /// Send the system prompt as a top-level `system` field instead of a message
+    pub system_as_field: bool,
+}
+
+impl Default for MessageRoles {
+    fn default() -> Self {
+        Self::openai()
+    }
+}
+
+impl MessageRoles {
+    /// `system`, `user` and `assistant`, all in `messages`
+    pub fn openai() -> Self {
+        Self {
+            system: "system".to_string(),
+            user: "user".to_string(),
+            assistant: "assistant".to_string(),
+            system_as_field: false,
+        }
+    }
+
+    /// The system prompt sent as a `developer` message, as OpenAI's reasoning models
+    /// expect
+    pub fn developer() -> Self {
+        Self {
+            system: "developer".to_string(),
+            ..Self::openai()
+        }
+    }
+
+    /// The system prompt moved out of `messages` into a top-level `system` field, as in
+    /// Anthropic's Messages API.
+    ///
+    /// Only the messages are reshaped. The request still goes to the chat completions
+    /// path with a bearer token and is read as a chat completions response, so this is
+    /// for OpenAI-compatible gateways in front of Anthropic models that expect this
+    /// layout, not for calling the Messages API directly.
+    pub fn anthropic() -> Self {
+        Self {
+            system_as_field: true,
+            ..Self::openai()
+        }
+    }
+
+    // The provider's name for an OpenAI role; unknown roles are kept as given
+    fn rename<'a>(&'a self, role: &'a str) -> &'a str {
+        match role {
+            "system" => &self.system,
+            "user" => &self.user,
+            "assistant" => &self.assistant,
+            other => other,
+        }
+    }
+}
+
+/// Model pinged by [`GroqClient::connect`]
+pub const PING_MODEL: &str = models::LLAMA4_SCOUT;
+
+/// Rewrites a structured response before it is deserialized; called with the parsed
+/// content and the JSON schema it should follow
+pub type ResponseHook =
+    Arc<dyn Fn(serde_json::Value, &serde_json::Value) -> serde_json::Value + Send + Sync>;
+
+// Told the token usage of every response received, including ones that are then
+// rejected, so retries are paid for too
+pub(crate) type UsageObserver = Arc<dyn Fn(GroqUsage) + Send + Sync>;
+
 /// A simple client for Groq API that provides both simple and structured completions
+#[cfg_attr(not(feature = "network"), allow(dead_code))]
 pub struct GroqClient {
-    client: reqwest::Client,
+    // Built on first use, unless a provider's pool is passed in
+    #[cfg(feature = "network")]
+    client: std::sync::OnceLock<reqwest::Client>,
     api_key: String,
     base_url: String,
+    completions_path: String,
+    headers: Vec<(String, String)>,
+    rate_limiter: RateLimiter,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    candidates: Option<u32>,
+    stop: Vec<String>,
+    logprobs: bool,
+    response_hooks: Vec<ResponseHook>,
+    usage_observer: Option<UsageObserver>,
+    roles: MessageRoles,
+    lenient_json: bool,
+    strict_schema: bool,
+    runtime: Option<tokio::runtime::Handle>,
+    prefill: Option<(String, PrefillStyle)>,
 }
 
 impl GroqClient {
-    /// Create a new Groq client with the given API key
+    /// Create a new Groq client with the given API key.
+    ///
+    /// Each client has its own connection pool, so reuse one client for many requests.
     pub fn new(api_key: String) -> Self {
         Self {
-            client: reqwest::Client::new(),
+            #[cfg(feature = "network")]
+            client: std::sync::OnceLock::new(),
             api_key,
             base_url: "https://api.groq.com/openai/v1".to_string(),
+            completions_path: "/chat/completions".to_string(),
+            headers: Vec::new(),
+            rate_limiter: RateLimiter::new(),
+            max_tokens: None,
+            temperature: None,
+            candidates: None,
+            stop: Vec::new(),
+            logprobs: false,
+            response_hooks: Vec::new(),
+            usage_observer: None,
+            roles: MessageRoles::openai(),
+            lenient_json: false,
+            strict_schema: false,
+            runtime: None,
+            prefill: None,
         }
     }
 
+    /// Create a Groq client and check right away that the key works, for failing fast at
+    /// startup instead of on the first recovery.
+    ///
+    /// ```no_run
+    /// # async fn run() -> Result<(), unwrap_or_ai::AiError> {
+    /// let client = unwrap_or_ai::GroqClient::connect(std::env::var("GROQ_API").unwrap()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn connect(api_key: String) -> Result<Self, AiError> {
+        let client = Self::new(api_key);
+        client.verify(PING_MODEL).await?;
+        Ok(client)
+    }
+
+    /// Send a one-token completion to `model`, returning the error if the endpoint can't
+    /// be reached or rejects the key. Ping a model the endpoint serves: other providers
+    /// and gateways reject Groq's model names.
+    pub async fn verify(&self, model: &str) -> Result<(), AiError> {
+        let mut request_body = self.chat_body(model, vec![("user", "ping")]);
+        request_body["max_tokens"] = json!(1);
+
+        self.send_chat_request(&request_body).await.map(|_| ())
+    }
+
+    /// Point the client at another OpenAI-compatible endpoint
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Post completions to `path` under the base URL instead of `/chat/completions`, for
+    /// gateways that expose them elsewhere
+    pub fn with_completions_path(mut self, path: impl AsRef<str>) -> Self {
+        self.completions_path = format!("/{}", path.as_ref().trim_start_matches('/'));
+        self
+    }
+
+    /// The URL completion requests are posted to
+    pub fn completions_url(&self) -> String {
+        format!("{}{}", self.base_url, self.completions_path)
+    }
+
+    // Share a provider's connection pool
+    #[cfg(feature = "network")]
+    pub(crate) fn with_http_client(mut self, client: reqwest::Client) -> Self {
+        self.client = std::sync::OnceLock::from(client);
+        self
+    }
+
+    /// Add a header sent with every request, e.g. for gateway routing
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Send `OpenAI-Organization`, required by some OpenAI-compatible gateways
+    pub fn with_organization(self, organization: impl Into<String>) -> Self {
+        self.with_header("OpenAI-Organization", organization)
+    }
+
+    /// Send `OpenAI-Project`, required by some OpenAI-compatible gateways
+    pub fn with_project(self, project: impl Into<String>) -> Self {
+        self.with_header("OpenAI-Project", project)
+    }
+
+    /// Send `max_tokens` with every completion request, limiting the answer's length
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Send `temperature` with every completion request; 0 makes answers repeatable
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Ask for `n` completions per request (the `n` parameter); structured completions
+    /// then use the first one that parses and deserializes. Costs roughly `n` times the
+    /// output tokens, but rescues cheap models that only sometimes follow the schema.
+    pub fn with_candidates(mut self, n: u32) -> Self {
+        self.candidates = Some(n);
+        self
+    }
+
+    /// Send `stop` sequences that end the answer as soon as the model writes one, e.g.
+    /// `"\n\n"` or `"Explanation"` for models that follow the JSON with prose. Most
+    /// providers accept at most four.
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Send `logprobs: true`, asking for the log probability of every output token. They
+    /// come back in each choice's `logprobs`, which this client doesn't read.
+    pub fn with_logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    /// Run `hook` on every structured response before it is deserialized, after the
+    /// hooks already added
+    pub fn with_response_hook(
+        self,
+        hook: impl Fn(serde_json::Value, &serde_json::Value) -> serde_json::Value
+        + Send
+        + Sync
+        + 'static,
+    ) -> Self {
+        self.with_shared_response_hook(Arc::new(hook))
+    }
+
+    pub(crate) fn with_shared_response_hook(mut self, hook: ResponseHook) -> Self {
+        self.response_hooks.push(hook);
+        self
+    }
+
+    pub(crate) fn with_usage_observer(mut self, observer: UsageObserver) -> Self {
+        self.usage_observer = Some(observer);
+        self
+    }
+
+    /// Accept quoted numbers and booleans (`"99.99"`, `"true"`) where the schema expects
+    /// the real thing; see [`coerce_scalars`]
+    pub fn with_scalar_coercion(self) -> Self {
+        self.with_response_hook(coerce_scalars)
+    }
+
+    /// When a structured answer isn't valid JSON, retry parsing it after
+    /// [`repair_json`] fixes trailing commas, single quotes and the like. If that fails
+    /// too, the original parse error is returned.
+    pub fn with_lenient_json(mut self) -> Self {
+        self.lenient_json = true;
+        self
+    }
+
+    /// Send `strict: true` with the `json_schema` response format, asking the endpoint to
+    /// guarantee the answer matches the schema.
+    ///
+    /// OpenAI honors it, but only for schemas that list every property as required and
+    /// set `additionalProperties: false`; others are rejected, and recovery then retries
+    /// in JSON mode. Groq accepts the flag and enforces it on the models that support
+    /// constrained decoding, such as [`models::GPT_OSS_120B`]. Endpoints that don't know
+    /// it usually ignore it.
+    pub fn with_strict_schema(mut self, strict: bool) -> Self {
+        self.strict_schema = strict;
+        self
+    }
+
+    /// Rename message roles for providers that don't use OpenAI's
+    pub fn with_message_roles(mut self, roles: MessageRoles) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// Pace requests with `rate_limiter`, e.g. one shared with other clients for the
+    /// same account. Each client otherwise paces only its own requests.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// End every request with an assistant message holding `prefill`, such as `{`, for the
+    /// model to continue, which keeps it from opening with prose or a code fence. Answers
+    /// that continue it get `prefill` put back in front. See [`PrefillStyle`] for which
+    /// providers support it.
+    pub fn with_assistant_prefill(
+        mut self,
+        prefill: impl Into<String>,
+        style: PrefillStyle,
+    ) -> Self {
+        self.prefill = Some((prefill.into(), style));
+        self
+    }
+
+    /// Send requests and parse responses on `runtime` instead of the caller's, which only
+    /// awaits the result. Dropping the call's future aborts the request there.
+    pub fn with_runtime(mut self, runtime: tokio::runtime::Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// The `x-ratelimit-*` headers of the last response that had any
+    pub fn rate_limit_state(&self) -> Option<RateLimitState> {
+        self.rate_limiter.state()
+    }
+
     /// Simple chat completion - returns just the text content
     pub async fn chat_completion_simple(
         &self,
         model: &str,
         messages: Vec<(&str, &str)>,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let messages: Vec<serde_json::Value> = messages
-            .into_iter()
-            .map(|(role, content)| {
-                json!({
-                    "role": role,
-                    "content": content
-                })
-            })
-            .collect();
-
-        let request_body = json!({
-            "model": model,
-            "messages": messages
-        });
-
-        let response = self
-            .client
-            .post(&format!("{}/chat/completions", self.base_url))
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+    ) -> Result<String, AiError> {
+        let request_body = self.chat_body(model, messages);
 
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            return Err(format!("API request failed: {}", error_text).into());
-        }
-
-        let groq_response: GroqResponse = response.json().await?;
-
-        if groq_response.choices.is_empty() {
-            return Err("No choices in response".into());
-        }
+        let groq_response = self.send_chat_request(&request_body).await?;
 
         Ok(groq_response.choices[0].message.content.clone())
     }
 
     /// Structured chat completion using JsonSchema trait - automatically generates JSON schema
-    /// Only works with models that support structured output:
-    /// - openai/gpt-oss-20b
-    /// - openai/gpt-oss-120b  
-    /// - moonshotai/kimi-k2-instruct
-    /// - meta-llama/llama-4-maverick-17b-128e-instruct
-    /// - meta-llama/llama-4-scout-17b-16e-instruct
+    /// (memoized per type), or sends the one registered with [`register_schema`]
+    /// Only works with models that support structured output; see
+    /// [`model_capabilities`](crate::capabilities::model_capabilities)
     pub async fn chat_completion_typed<T>(
         &self,
         model: &str,
         messages: Vec<(&str, &str)>,
-    ) -> Result<T, Box<dyn std::error::Error>>
+    ) -> Result<T, AiError>
     where
-        T: for<'de> Deserialize<'de> + JsonSchema,
+        T: for<'de> Deserialize<'de> + JsonSchema + 'static,
     {
-        let schema_name = std::any::type_name::<T>()
-            .split("::")
-            .last()
-            .unwrap_or("response")
-            .to_lowercase();
-
-        let schema_def = schemars::schema_for!(T);
-        let schema = serde_json::to_value(&schema_def)?;
+        let (schema_name, schema) = recovery_schema::<T>()?;
 
         self.chat_completion_structured(model, messages, &schema_name, schema)
             .await
     }
 
+    /// Like [`chat_completion_typed`](Self::chat_completion_typed), but returns every
+    /// choice that deserializes, in order, with its `finish_reason`, for picking among
+    /// [candidates](Self::with_candidates) yourself. Choices that don't fit `T` are
+    /// skipped; if none does, the first one's error is returned.
+    pub async fn chat_completion_all<T>(
+        &self,
+        model: &str,
+        messages: Vec<(&str, &str)>,
+    ) -> Result<Vec<(T, String)>, AiError>
+    where
+        T: for<'de> Deserialize<'de> + JsonSchema + 'static,
+    {
+        let (schema_name, schema) = recovery_schema::<T>()?;
+        let mut request_body = self.chat_body(model, messages);
+        request_body["response_format"] = self.json_schema_format(&schema_name, &schema);
+
+        let groq_response = self.send_chat_request(&request_body).await?;
+        let mut parsed = Vec::new();
+        let mut first_error = None;
+        for choice in groq_response.choices {
+            match self.parse_message(&choice.message, &schema) {
+                Ok((value, _)) => parsed.push((value, choice.finish_reason)),
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+        match first_error {
+            Some(error) if parsed.is_empty() => Err(error),
+            _ => Ok(parsed),
+        }
+    }
+
     /// Structured chat completion - returns a typed response based on JSON schema
-    /// Only works with models that support structured output:
-    /// - openai/gpt-oss-20b
-    /// - openai/gpt-oss-120b  
-    /// - moonshotai/kimi-k2-instruct
-    /// - meta-llama/llama-4-maverick-17b-128e-instruct
-    /// - meta-llama/llama-4-scout-17b-16e-instruct
+    /// Only works with models that support structured output; see
+    /// [`model_capabilities`](crate::capabilities::model_capabilities)
     pub async fn chat_completion_structured<T>(
         &self,
         model: &str,
         messages: Vec<(&str, &str)>,
         schema_name: &str,
         schema: serde_json::Value,
-    ) -> Result<T, Box<dyn std::error::Error>>
+    ) -> Result<T, AiError>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        self.chat_completion_structured_response(model, messages, schema_name, schema)
+            .await
+            .map(|response| response.parsed)
+    }
+
+    /// Like [`GroqClient::chat_completion_structured`], also returning the model's raw
+    /// content and the token usage
+    pub async fn chat_completion_structured_response<T>(
+        &self,
+        model: &str,
+        messages: Vec<(&str, &str)>,
+        schema_name: &str,
+        schema: serde_json::Value,
+    ) -> Result<StructuredResponse<T>, AiError>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let mut request_body = self.chat_body(model, messages);
+        request_body["response_format"] = self.json_schema_format(schema_name, &schema);
+
+        let groq_response = self.send_chat_request(&request_body).await?;
+        self.parse_structured(groq_response, &schema)
+    }
+
+    /// JSON mode for models without structured output support.
+    ///
+    /// The model is only constrained to produce *some* JSON object, so `messages` should
+    /// describe the expected shape; the answer is then checked against `schema` like a
+    /// structured completion.
+    pub async fn chat_completion_json_object_response<T>(
+        &self,
+        model: &str,
+        messages: Vec<(&str, &str)>,
+        schema: &serde_json::Value,
+    ) -> Result<StructuredResponse<T>, AiError>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let mut request_body = self.chat_body(model, messages);
+        request_body["response_format"] = json!({ "type": "json_object" });
+
+        let groq_response = self.send_chat_request(&request_body).await?;
+        self.parse_structured(groq_response, schema)
+    }
+
+    // The first choice that parses; with several candidates, the first one's error is
+    // returned if none does
+    fn parse_structured<T>(
+        &self,
+        groq_response: GroqResponse,
+        schema: &serde_json::Value,
+    ) -> Result<StructuredResponse<T>, AiError>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let usage = groq_response.usage;
+        let mut first_error = None;
+        for choice in groq_response.choices {
+            match self.parse_message(&choice.message, schema) {
+                Ok((parsed, json)) => {
+                    return Ok(StructuredResponse {
+                        parsed,
+                        json,
+                        content: choice.message.content,
+                        usage,
+                    });
+                }
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+        Err(first_error.unwrap_or(AiError::NoChoices { request_id: None }))
+    }
+
+    // A choice's answer, or why it has none; refusals are reported as such, whether the
+    // provider flags them or the model just answers in prose
+    fn parse_message<T>(
+        &self,
+        message: &GroqMessage,
+        schema: &serde_json::Value,
+    ) -> Result<(T, serde_json::Value), AiError>
     where
         T: for<'de> Deserialize<'de>,
     {
-        let messages: Vec<serde_json::Value> = messages
+        if let Some(refusal) = &message.refusal {
+            return Err(AiError::ModelRefused {
+                message: refusal.clone(),
+                request_id: None,
+            });
+        }
+        self.parse_content(&message.content, schema)
+            .map_err(|error| match error {
+                AiError::Json { .. } if looks_like_refusal(&message.content, schema) => {
+                    AiError::ModelRefused {
+                        message: message.content.trim().to_string(),
+                        request_id: None,
+                    }
+                }
+                error => error,
+            })
+    }
+
+    fn parse_content<T>(
+        &self,
+        content: &str,
+        schema: &serde_json::Value,
+    ) -> Result<(T, serde_json::Value), AiError>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let value = match serde_json::from_str(content) {
+            Ok(value) => value,
+            Err(strict) => {
+                // Fenced or wrapped in prose; the error reported is still the original one
+                let extracted = extract_json(content).unwrap_or(content);
+                match serde_json::from_str(extracted) {
+                    Ok(value) => value,
+                    Err(_) if self.lenient_json => {
+                        serde_json::from_str(&repair_json(extracted)).map_err(|_| strict)?
+                    }
+                    Err(_) => return Err(strict.into()),
+                }
+            }
+        };
+        // Models sometimes use Rust field names instead of serde-renamed keys
+        let mut value = conform_to_schema(value, schema);
+        for hook in &self.response_hooks {
+            value = hook(value, schema);
+        }
+        match serde_path_to_error::deserialize(&value) {
+            Ok(parsed) => Ok((parsed, value)),
+            Err(error) => Err(deserialize_error(error, value)),
+        }
+    }
+
+    fn json_schema_format(
+        &self,
+        schema_name: &str,
+        schema: &serde_json::Value,
+    ) -> serde_json::Value {
+        let mut json_schema = json!({
+            "name": schema_name,
+            "schema": schema
+        });
+        if self.strict_schema {
+            json_schema["strict"] = json!(true);
+        }
+        json!({
+            "type": "json_schema",
+            "json_schema": json_schema
+        })
+    }
+
+    // `model` and `messages`, with roles renamed for the provider
+    fn chat_body(&self, model: &str, messages: Vec<(&str, &str)>) -> serde_json::Value {
+        let (system, messages): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .partition(|(role, _)| self.roles.system_as_field && *role == "system");
+
+        let mut messages: Vec<_> = messages
             .into_iter()
             .map(|(role, content)| {
                 json!({
-                    "role": role,
+                    "role": self.roles.rename(role),
                     "content": content
                 })
             })
             .collect();
-
-        let request_body = json!({
-            "model": model,
-            "messages": messages,
-            "response_format": {
-                "type": "json_schema",
-                "json_schema": {
-                    "name": schema_name,
-                    "schema": schema
-                }
+        if let Some((prefill, style)) = &self.prefill {
+            let mut message = json!({
+                "role": self.roles.rename("assistant"),
+                "content": prefill
+            });
+            if *style == PrefillStyle::PrefixFlag {
+                message["prefix"] = json!(true);
             }
+            messages.push(message);
+        }
+        let mut body = json!({
+            "model": model,
+            "messages": messages
         });
+        if !system.is_empty() {
+            let system: Vec<_> = system.into_iter().map(|(_, content)| content).collect();
+            body["system"] = json!(system.join("\n\n"));
+        }
+        body
+    }
 
-        let response = self
+    #[cfg(feature = "network")]
+    fn build_request(&self, request_body: &serde_json::Value) -> reqwest::RequestBuilder {
+        let mut request = self
             .client
-            .post(&format!("{}/chat/completions", self.base_url))
+            .get_or_init(reqwest::Client::new)
+            .post(self.completions_url())
             .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+            .header("Content-Type", "application/json");
+
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+
+        let sampling = [
+            (
+                "max_tokens",
+                self.max_tokens.map(|max_tokens| json!(max_tokens)),
+            ),
+            (
+                "temperature",
+                self.temperature.map(|temperature| json!(temperature)),
+            ),
+            ("n", self.candidates.map(|n| json!(n))),
+            ("stop", (!self.stop.is_empty()).then(|| json!(self.stop))),
+            ("logprobs", self.logprobs.then_some(json!(true))),
+        ];
+        match request_body {
+            serde_json::Value::Object(body) if sampling.iter().any(|(_, v)| v.is_some()) => {
+                let mut body = body.clone();
+                for (name, value) in sampling {
+                    if let Some(value) = value {
+                        body.insert(name.to_string(), value);
+                    }
+                }
+                request.json(&body)
+            }
+            _ => request.json(request_body),
+        }
+    }
+
+    /// POST a chat completions request and return the parsed response, which is
+    /// guaranteed to contain at least one choice.
+    ///
+    /// Waits first if the last rate limit headers said the budget is running out.
+    #[cfg(feature = "network")]
+    async fn send_chat_request(
+        &self,
+        request_body: &serde_json::Value,
+    ) -> Result<GroqResponse, AiError> {
+        let exchange = Self::exchange(self.rate_limiter.clone(), self.build_request(request_body));
+        let mut groq_response = match &self.runtime {
+            None => exchange.await?,
+            Some(runtime) => {
+                match tokio_util::task::AbortOnDropHandle::new(runtime.spawn(exchange)).await {
+                    Ok(result) => result?,
+                    Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+                    // The runtime was shut down
+                    Err(_) => return Err(AiError::Cancelled),
+                }
+            }
+        };
+        if let Some(observer) = &self.usage_observer {
+            observer(groq_response.usage);
+        }
+
+        if let Some((prefill, _)) = &self.prefill {
+            for choice in &mut groq_response.choices {
+                let content = &mut choice.message.content;
+                if !content.is_empty() && !content.trim_start().starts_with(prefill.trim()) {
+                    content.insert_str(0, prefill);
+                }
+            }
+        }
+        Ok(groq_response)
+    }
+
+    // Owns everything it needs, so it can run on another runtime
+    #[cfg(feature = "network")]
+    async fn exchange(
+        rate_limiter: RateLimiter,
+        request: reqwest::RequestBuilder,
+    ) -> Result<GroqResponse, AiError> {
+        rate_limiter.wait().await;
+        let response = request.send().await?;
+        rate_limiter.observe(response.headers());
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
             let error_text = response.text().await?;
-            return Err(format!("API request failed: {}", error_text).into());
+            return Err(AiError::Api {
+                status: status.as_u16(),
+                body: error_text,
+                request_id: None,
+            });
         }
 
-        let groq_response: GroqResponse = response.json().await?;
+        let groq_response: GroqResponse = serde_json::from_str(&response.text().await?)?;
 
         if groq_response.choices.is_empty() {
-            return Err("No choices in response".into());
+            return Err(AiError::NoChoices { request_id: None });
         }
 
-        let content = &groq_response.choices[0].message.content;
-        let parsed: T = serde_json::from_str(content)?;
-        Ok(parsed)
+        Ok(groq_response)
+    }
+
+    #[cfg(not(feature = "network"))]
+    async fn send_chat_request(
+        &self,
+        _request_body: &serde_json::Value,
+    ) -> Result<GroqResponse, AiError> {
+        Err(AiError::Disabled)
     }
 
     /// Helper function to create a JSON schema for simple types
@@ -211,6 +827,130 @@ impl GroqClient {
     }
 }
 
+type SchemaCache = RwLock<HashMap<TypeId, (String, serde_json::Value)>>;
+
+static SCHEMA_CACHE: LazyLock<SchemaCache> = LazyLock::new(Default::default);
+
+/// Use `schema` instead of `T`'s derived `JsonSchema` whenever `T` is recovered or
+/// requested through [`GroqClient::chat_completion_typed`].
+///
+/// For constraints the derive can't express, such as `"format": "date-time"` on a date
+/// stored as a `String`. Registering again replaces the previous override.
+pub fn register_schema<T: JsonSchema + 'static>(schema: serde_json::Value) {
+    let schema_name = schema_name(&crate::prompt::short_type_name::<T>());
+    SCHEMA_CACHE
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(TypeId::of::<T>(), (schema_name, schema));
+}
+
+/// The `json_schema` name and JSON schema derived from `T`'s `JsonSchema` impl.
+///
+/// Recoveries and [`GroqClient::chat_completion_typed`] send a schema registered with
+/// [`register_schema`] instead, when there is one.
+pub fn schema_for_type<T: JsonSchema>() -> Result<(String, serde_json::Value), AiError> {
+    let schema_name = schema_name(&crate::prompt::short_type_name::<T>());
+    let schema_def = schemars::schema_for!(T);
+    Ok((schema_name, serde_json::to_value(&schema_def)?))
+}
+
+// What recoveries and the typed completions send for `T`: the registered schema if there is one, else the derived
+// one, memoized per type so hot recovery loops only pay for a clone
+pub(crate) fn recovery_schema<T: JsonSchema + 'static>()
+-> Result<(String, serde_json::Value), AiError> {
+    let type_id = TypeId::of::<T>();
+    if let Some(cached) = SCHEMA_CACHE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&type_id)
+    {
+        return Ok(cached.clone());
+    }
+
+    let (schema_name, schema) = schema_for_type::<T>()?;
+    SCHEMA_CACHE
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(type_id, (schema_name.clone(), schema.clone()));
+    Ok((schema_name, schema))
+}
+
+/// Longest `json_schema.name` accepted by OpenAI-compatible APIs
+pub const MAX_SCHEMA_NAME_LEN: usize = 64;
+
+// Schema names may only contain `[a-zA-Z0-9_-]`, so generic types such as `Vec<User>`
+// become `vec_user`
+fn schema_name(type_name: &str) -> String {
+    let mut name = type_name
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+        .to_lowercase();
+
+    // Only ASCII is left, so any byte index is a char boundary
+    name.truncate(MAX_SCHEMA_NAME_LEN);
+    let name = name.trim_end_matches('_');
+
+    if name.is_empty() {
+        "response".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// Prose with no JSON anywhere in it, like "I'm sorry, but I can't help with that."
+// A truncated or malformed answer still has the start of an object or array. Only
+// object and array targets qualify: for a string or number, prose may be a bad answer
+// rather than a refusal.
+fn looks_like_refusal(content: &str, schema: &serde_json::Value) -> bool {
+    let content = content.trim();
+    matches!(root_type(schema), Some("object" | "array"))
+        && extract_json(content).is_none()
+        && !content.contains(['{', '['])
+        && content.starts_with(char::is_alphabetic)
+        && content.split_whitespace().count() >= 3
+}
+
+/// Longest snippet of the offending JSON kept in [`AiError::Deserialize`]
+const MAX_SNIPPET_CHARS: usize = 200;
+
+// Points at the part of `value` that didn't fit, which is far more useful than serde's
+// message alone when the model got one field wrong
+fn deserialize_error(
+    error: serde_path_to_error::Error<serde_json::Error>,
+    value: serde_json::Value,
+) -> AiError {
+    use serde_path_to_error::Segment;
+
+    let path = error.path().to_string();
+    let mut offending = &value;
+    for segment in error.path().iter() {
+        let child = match segment {
+            Segment::Seq { index } => offending.get(index),
+            Segment::Map { key } => offending.get(key),
+            Segment::Enum { .. } | Segment::Unknown => None,
+        };
+        match child {
+            Some(child) => offending = child,
+            None => break,
+        }
+    }
+    let mut snippet = offending.to_string();
+    if let Some((cut, _)) = snippet.char_indices().nth(MAX_SNIPPET_CHARS) {
+        snippet.truncate(cut);
+        snippet.push_str("...");
+    }
+
+    AiError::Deserialize {
+        path,
+        snippet,
+        value,
+        source: error.into_inner(),
+        request_id: None,
+    }
+}
+
 /// Commonly used models for different purposes
 pub mod models {
     /// Fast models - good for simple text generation
@@ -223,4 +963,392 @@ pub mod models {
     pub const KIMI_K2: &str = "moonshotai/kimi-k2-instruct";
     pub const LLAMA4_MAVERICK: &str = "meta-llama/llama-4-maverick-17b-128e-instruct";
     pub const LLAMA4_SCOUT: &str = "meta-llama/llama-4-scout-17b-16e-instruct";
+
+    const STRUCTURED: &[&str] = &[
+        GPT_OSS_20B,
+        GPT_OSS_120B,
+        KIMI_K2,
+        LLAMA4_MAVERICK,
+        LLAMA4_SCOUT,
+    ];
+
+    /// The built-in models that support structured output
+    pub fn all_structured() -> &'static [&'static str] {
+        STRUCTURED
+    }
+
+    /// Whether `model` is known to accept `response_format: json_schema`, either as a
+    /// built-in model or through
+    /// [`register_model_capabilities`](crate::register_model_capabilities).
+    ///
+    /// Unknown models are `false` here, though recovery still tries structured output
+    /// with them first.
+    pub fn supports_structured_output(model: &str) -> bool {
+        crate::capabilities::registered_capabilities(model)
+            .is_some_and(|capabilities| capabilities.supports_json_schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_custom_headers_are_sent() {
+        let client = GroqClient::new("key".to_string())
+            .with_organization("org-123")
+            .with_project("proj-456")
+            .with_header("X-Route", "fast-pool");
+
+        let request = client.build_request(&json!({})).build().unwrap();
+        let headers = request.headers();
+
+        assert_eq!(headers["Authorization"], "Bearer key");
+        assert_eq!(headers["OpenAI-Organization"], "org-123");
+        assert_eq!(headers["OpenAI-Project"], "proj-456");
+        assert_eq!(headers["X-Route"], "fast-pool");
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_completions_path_is_configurable() {
+        let request = |client: GroqClient| client.build_request(&json!({})).build().unwrap();
+
+        let default = GroqClient::new("key".to_string());
+        assert_eq!(
+            request(default).url().as_str(),
+            "https://api.groq.com/openai/v1/chat/completions"
+        );
+
+        let gateway = GroqClient::new("key".to_string())
+            .with_base_url("https://gateway.internal/llm/")
+            .with_completions_path("v2/generate");
+        assert_eq!(
+            gateway.completions_url(),
+            "https://gateway.internal/llm/v2/generate"
+        );
+        assert_eq!(
+            request(gateway).url().as_str(),
+            "https://gateway.internal/llm/v2/generate"
+        );
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_sampling_options_are_sent() {
+        let client = GroqClient::new("key".to_string())
+            .with_max_tokens(300)
+            .with_temperature(0.5);
+        let request = client
+            .build_request(&json!({ "model": "m" }))
+            .build()
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+
+        assert_eq!(
+            body,
+            json!({ "model": "m", "max_tokens": 300, "temperature": 0.5 })
+        );
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_stop_and_logprobs_are_sent() {
+        let client = GroqClient::new("key".to_string())
+            .with_stop(vec!["\n\n".to_string(), "Explanation:".to_string()])
+            .with_logprobs(true);
+        let request = client
+            .build_request(&json!({ "model": "m" }))
+            .build()
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+
+        assert_eq!(
+            body,
+            json!({ "model": "m", "stop": ["\n\n", "Explanation:"], "logprobs": true })
+        );
+
+        let defaults = GroqClient::new("key".to_string())
+            .with_stop(Vec::new())
+            .with_logprobs(false);
+        let request = defaults
+            .build_request(&json!({ "model": "m" }))
+            .build()
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body, json!({ "model": "m" }));
+    }
+
+    #[test]
+    fn test_strict_schema_flag_is_sent() {
+        let schema = json!({ "type": "object" });
+        let client = GroqClient::new("key".to_string());
+        let lax = serde_json::to_string(&client.json_schema_format("user", &schema)).unwrap();
+        assert!(!lax.contains("strict"));
+
+        let strict = client.with_strict_schema(true);
+        assert_eq!(
+            strict.json_schema_format("user", &schema),
+            json!({
+                "type": "json_schema",
+                "json_schema": { "name": "user", "schema": schema, "strict": true }
+            })
+        );
+    }
+
+    #[test]
+    fn test_structured_models_are_listed() {
+        assert!(models::all_structured().contains(&models::KIMI_K2));
+        assert!(
+            models::all_structured()
+                .iter()
+                .all(|model| models::supports_structured_output(model))
+        );
+        assert!(!models::supports_structured_output(models::LLAMA3_8B));
+        assert!(!models::supports_structured_output("acme/unknown-7b"));
+    }
+
+    #[test]
+    fn test_schema_is_memoized_per_type() {
+        #[derive(Deserialize, JsonSchema)]
+        #[allow(dead_code)]
+        struct Memoized {
+            id: u32,
+        }
+
+        let first = recovery_schema::<Memoized>().unwrap();
+        assert!(
+            SCHEMA_CACHE
+                .read()
+                .unwrap()
+                .contains_key(&TypeId::of::<Memoized>())
+        );
+        assert_eq!(recovery_schema::<Memoized>().unwrap(), first);
+        // Distinct types never share an entry
+        assert_ne!(recovery_schema::<Vec<Memoized>>().unwrap(), first);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_typed_completions_memoize_the_schema() {
+        #[derive(Debug, Deserialize, JsonSchema)]
+        struct Typed {
+            id: u32,
+        }
+
+        let body = json!({
+            "id": "chatcmpl-stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub-model",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": r#"{"id": 7}"# },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 }
+        });
+        let server = crate::test_common::StubServer::start(vec![(200, body.to_string())]).await;
+        let typed: Typed = GroqClient::new("test-key".to_string())
+            .with_base_url(&server.url)
+            .chat_completion_typed("stub-model", vec![("user", "Id?")])
+            .await
+            .unwrap();
+        assert_eq!(typed.id, 7);
+
+        // Later calls for the same type reuse the derived schema
+        assert!(
+            SCHEMA_CACHE
+                .read()
+                .unwrap()
+                .contains_key(&TypeId::of::<Typed>())
+        );
+    }
+
+    #[test]
+    fn test_assistant_prefill_ends_the_messages() {
+        let messages = vec![("system", "Be terse"), ("user", "Hi")];
+
+        let plain = GroqClient::new("key".to_string())
+            .with_assistant_prefill("{", PrefillStyle::Message)
+            .chat_body("m", messages.clone());
+        assert_eq!(
+            plain["messages"][2],
+            json!({ "role": "assistant", "content": "{" })
+        );
+
+        let flagged = GroqClient::new("key".to_string())
+            .with_assistant_prefill("{", PrefillStyle::PrefixFlag)
+            .chat_body("m", messages);
+        assert_eq!(
+            flagged["messages"][2],
+            json!({ "role": "assistant", "content": "{", "prefix": true })
+        );
+    }
+
+    #[test]
+    fn test_message_roles_are_renamed() {
+        let messages = vec![
+            ("system", "Be terse"),
+            ("user", "Hi"),
+            ("assistant", "Hello"),
+        ];
+
+        let openai = GroqClient::new("key".to_string()).chat_body("m", messages.clone());
+        assert_eq!(openai["messages"][0]["role"], "system");
+        assert_eq!(openai.get("system"), None);
+
+        let developer = GroqClient::new("key".to_string())
+            .with_message_roles(MessageRoles::developer())
+            .chat_body("m", messages.clone());
+        assert_eq!(developer["messages"][0]["role"], "developer");
+
+        let anthropic = GroqClient::new("key".to_string())
+            .with_message_roles(MessageRoles::anthropic())
+            .chat_body("m", messages);
+        assert_eq!(anthropic["system"], "Be terse");
+        assert_eq!(
+            anthropic["messages"],
+            json!([
+                { "role": "user", "content": "Hi" },
+                { "role": "assistant", "content": "Hello" }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_schema_for_borrowing_types() {
+        #[derive(JsonSchema)]
+        #[allow(dead_code)]
+        struct Label<'a> {
+            text: &'a str,
+        }
+
+        let (name, schema) = schema_for_type::<Label<'_>>().unwrap();
+        assert_eq!(name, "label");
+        assert_eq!(schema["properties"]["text"]["type"], "string");
+    }
+
+    #[test]
+    fn test_registered_schema_overrides_the_derive() {
+        #[derive(Deserialize, JsonSchema)]
+        #[allow(dead_code)]
+        struct Event {
+            starts_at: String,
+        }
+
+        let derived = schema_for_type::<Event>().unwrap().1;
+        assert_eq!(derived["properties"]["starts_at"].get("format"), None);
+
+        let schema = json!({
+            "type": "object",
+            "properties": { "starts_at": { "type": "string", "format": "date-time" } },
+            "required": ["starts_at"]
+        });
+        register_schema::<Event>(schema.clone());
+        assert_eq!(
+            recovery_schema::<Event>().unwrap(),
+            ("event".to_string(), schema)
+        );
+    }
+
+    #[test]
+    fn test_schema_names_are_valid_for_tricky_types() {
+        let cases = [
+            ("Vec<User>", "vec_user"),
+            ("Option<(u32, String)>", "option_u32_string"),
+            ("[u8; 4]", "u8_4"),
+            ("&str", "str"),
+            ("()", "response"),
+            ("Café", "caf"),
+            (
+                "HashMap<String, Vec<Option<Order_Line>>>",
+                "hashmap_string_vec_option_order_line",
+            ),
+        ];
+        for (type_name, expected) in cases {
+            assert_eq!(schema_name(type_name), expected, "for {}", type_name);
+        }
+
+        let long = schema_name(&format!("Wrapper<{}>", "VeryLongTypeName, ".repeat(10)));
+        assert!(long.len() <= MAX_SCHEMA_NAME_LEN);
+        assert!(!long.ends_with('_'));
+        assert!(
+            long.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        );
+
+        let (name, _) = schema_for_type::<Vec<Option<String>>>().unwrap();
+        assert_eq!(name, "vec_option_string");
+        let (name, _) = schema_for_type::<std::collections::BTreeMap<u32, Vec<String>>>().unwrap();
+        assert_eq!(name, "btreemap_u32_vec_string");
+    }
+
+    #[test]
+    fn test_map_schema_uses_additional_properties() {
+        #[derive(Deserialize, JsonSchema)]
+        #[allow(dead_code)]
+        struct WeatherData {
+            temperature: f64,
+        }
+
+        let (_, schema) =
+            schema_for_type::<std::collections::HashMap<String, WeatherData>>().unwrap();
+        assert_eq!(schema["type"], "object");
+        assert_eq!(
+            schema["additionalProperties"]["$ref"],
+            "#/$defs/WeatherData"
+        );
+
+        let (_, schema) =
+            schema_for_type::<std::collections::BTreeMap<u32, Vec<String>>>().unwrap();
+        assert_eq!(schema["type"], "object");
+    }
+
+    #[test]
+    fn test_deserialize_errors_point_at_the_field() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct LineItem {
+            sku: String,
+            quantity: u32,
+        }
+
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Order {
+            items: Vec<LineItem>,
+        }
+
+        let client = GroqClient::new("test-key".to_string());
+        let content =
+            r#"{"items": [{"sku": "A", "quantity": 1}, {"sku": "B", "quantity": "two"}]}"#;
+        match client.parse_content::<Order>(content, &json!({})) {
+            Err(AiError::Deserialize {
+                path,
+                snippet,
+                value,
+                ..
+            }) => {
+                assert_eq!(path, "items[1].quantity");
+                assert_eq!(snippet, "\"two\"");
+                assert_eq!(value["items"][0]["sku"], "A");
+            }
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+
+        let long = format!(r#"{{"items": "{}"}}"#, "x".repeat(500));
+        match client.parse_content::<Order>(&long, &json!({})) {
+            Err(AiError::Deserialize { path, snippet, .. }) => {
+                assert_eq!(path, "items");
+                assert_eq!(snippet.chars().count(), MAX_SNIPPET_CHARS + 3);
+                assert!(snippet.ends_with("..."));
+            }
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+    }
 }
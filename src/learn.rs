@@ -0,0 +1,104 @@
+//! Real output remembered from successful calls, used as an example when the same
+//! function later fails.
+//!
+//! Opt in per function with `#[unwrap_or_ai_func(learn)]`. Every successful call then
+//! stores its `Ok`/`Some` value as JSON, which needs the value to implement `Serialize`.
+//! Recovering a failed call to that function includes the last one in the prompt, so the
+//! model copies a shape and style that really occurs. Examples are tied to the
+//! function's [source hash](crate::RecoveryContext::source_hash): once the function
+//! changes, the old example is dropped rather than shown.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+use serde::Serialize;
+
+/// Longer examples are not stored; they would crowd the prompt for little gain
+pub const MAX_EXAMPLE_CHARS: usize = 2_000;
+
+struct Example {
+    source_hash: u64,
+    json: String,
+}
+
+static EXAMPLES: LazyLock<RwLock<HashMap<String, Example>>> = LazyLock::new(Default::default);
+
+/// The value of a successful call; implemented for `Result` and `Option`
+pub trait Outcome {
+    type Value: Serialize;
+
+    fn success(&self) -> Option<&Self::Value>;
+}
+
+impl<T: Serialize, E> Outcome for Result<T, E> {
+    type Value = T;
+
+    fn success(&self) -> Option<&T> {
+        self.as_ref().ok()
+    }
+}
+
+impl<T: Serialize> Outcome for Option<T> {
+    type Value = T;
+
+    fn success(&self) -> Option<&T> {
+        self.as_ref()
+    }
+}
+
+/// Store the value of `outcome` as the example for `fn_name`, if the call succeeded.
+///
+/// Called by functions annotated with `#[unwrap_or_ai_func(learn)]`.
+pub fn remember_success<O: Outcome>(fn_name: &str, source_hash: u64, outcome: &O) {
+    let Some(json) = outcome
+        .success()
+        .and_then(|value| serde_json::to_string(value).ok())
+        .filter(|json| json.len() <= MAX_EXAMPLE_CHARS)
+    else {
+        return;
+    };
+
+    EXAMPLES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(fn_name.to_string(), Example { source_hash, json });
+}
+
+/// The last example remembered for `fn_name`, unless the function has changed since
+pub fn example_for(fn_name: &str, source_hash: u64) -> Option<String> {
+    let examples = EXAMPLES.read().unwrap_or_else(|e| e.into_inner());
+    examples
+        .get(fn_name)
+        .filter(|example| example.source_hash == source_hash)
+        .map(|example| example.json.clone())
+}
+
+/// Drop every remembered example
+pub fn forget_examples() {
+    EXAMPLES.write().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_examples_follow_the_source_hash() {
+        let failed: Result<u32, String> = Err("down".to_string());
+        remember_success("learn_test_fn", 1, &failed);
+        assert_eq!(example_for("learn_test_fn", 1), None);
+
+        remember_success("learn_test_fn", 1, &Some(vec!["a", "b"]));
+        assert_eq!(
+            example_for("learn_test_fn", 1).as_deref(),
+            Some(r#"["a","b"]"#)
+        );
+        // The function changed: the example no longer applies
+        assert_eq!(example_for("learn_test_fn", 2), None);
+
+        let huge = "x".repeat(MAX_EXAMPLE_CHARS);
+        remember_success("learn_test_fn", 2, &Some(huge));
+        assert_eq!(example_for("learn_test_fn", 2), None);
+        assert!(example_for("learn_test_fn", 1).is_some());
+    }
+}
@@ -1,13 +1,79 @@
+pub mod cache;
+pub mod capabilities;
+pub mod config;
+#[cfg(feature = "chrono")]
+pub mod dates;
+pub mod drift;
+pub mod dynamic;
+pub mod error;
+pub mod events;
+pub mod format;
 pub mod groq_client;
+pub mod learn;
+pub mod partial;
+pub mod plausibility;
+pub mod prompt;
+pub mod provider;
+pub mod rate_limit;
+pub mod recover;
+pub mod redact;
+pub mod repair;
+pub mod routing;
+pub mod schema;
+pub mod stats;
+
+// Used by `unwrap_or_ai!` to build helper names, so callers don't need `paste` themselves
+#[doc(hidden)]
+pub use paste::paste as __paste;
+
+// The macros used to need callers to reach `paste` through this crate; kept so code
+// that still does keeps compiling
 pub use paste;
 
-pub use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
+pub use capabilities::{ModelCapabilities, register_model_capabilities};
+pub use config::{CacheKeyStrategy, MaxTokens, RecoveryConfig};
+pub use drift::DriftCheck;
+pub use error::{AiError, AiRecoveryError, ApiErrorKind};
+pub use events::{RecoveryEvent, subscribe_recovery_events};
+pub use groq_client::{GroqClient, MessageRoles, PrefillStyle, models, register_schema};
+pub use provider::Provider;
+pub use rate_limit::{RateLimitState, RateLimiter};
+pub use recover::{
+    Confident, ErrorContext, Recovery, RecoveryContext, recover, recover_as, recover_future,
+    recover_into, recover_optional, recover_vec, recover_with_confidence,
+};
+pub use routing::route_type;
+pub use schema::schema_skeleton;
+pub use stats::{RecoveryStats, recovery_stats};
+pub use tokio_util::sync::CancellationToken;
+
+pub use unwrap_or_ai_proc_macro::{Recoverable, auto_recover, unwrap_or_ai_func};
+
+pub mod recoverable;
+pub use recoverable::Recoverable;
 
 #[macro_use]
 pub mod unwrap_or_ai;
 
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::learn::remember_success;
+    pub use crate::recoverable::{RequiresDeserialize, RequiresJsonSchema};
+}
+
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod test_common;
+
 #[cfg(test)]
 mod tests {
+    // Tests that go through a provider are compiled out without one, leaving some of
+    // their fixtures unused
+    #![cfg_attr(
+        any(feature = "no-ai", not(feature = "network")),
+        allow(dead_code, unused_imports)
+    )]
+
     use dotenv::dotenv;
     use serde::{Deserialize, Serialize};
     use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
@@ -15,8 +81,6 @@ mod tests {
     // Import the helper functions and traits
     use crate::unwrap_or_ai::UnwrapOrAi;
 
-    use super::*;
-
     // Test data structures with comprehensive documentation for AI context
     /// Represents a user in our system with basic profile information.
     /// This structure contains the essential fields needed to identify and contact a user.
@@ -165,6 +229,11 @@ mod tests {
     async fn test_unwrap_or_ai_with_real_api_call_failed_result() {
         dotenv().ok();
 
+        if std::env::var("GROQ_API").is_err() {
+            println!("Skipping test - GROQ_API environment variable not set");
+            return;
+        }
+
         let result = unwrap_or_ai!(get_user_failure(42)).await;
 
         print!("result: {:?}", result);
@@ -247,8 +316,11 @@ mod tests {
 
         // We can't actually test the AI call without a real API key,
         // but we can test that the trait method exists and can be called
-        let prompt = "test prompt".to_string();
-        let _result = success_result.unwrap_or_ai_impl(prompt.clone()).await;
+        let context = || crate::RecoveryContext {
+            fn_name: "test prompt".to_string(),
+            ..Default::default()
+        };
+        let _result = success_result.unwrap_or_ai_impl(context()).await;
 
         // Test Option implementation
         let some_option: Option<TestProduct> = Some(TestProduct {
@@ -257,10 +329,9 @@ mod tests {
             price: 10.0,
         });
 
-        let _option_result = some_option.unwrap_or_ai_impl(prompt).await;
+        let _option_result = some_option.unwrap_or_ai_impl(context()).await;
 
         // If we get here, the trait implementations compiled and executed
-        assert!(true);
     }
 
     #[test]
@@ -275,6 +346,2389 @@ mod tests {
         assert!(product_source.contains("TestProduct"));
     }
 
+    #[test]
+    fn test_function_docs_are_a_separate_prompt_section() {
+        let docs = doc_of_get_user_success();
+        assert!(docs.starts_with("Retrieves a user by ID from the database.\nThis function"));
+        assert!(docs.contains("Returns:\n- Ok(TestUser)"));
+        assert!(!docs.contains("fn get_user_success"));
+
+        let prompt = crate::RecoveryContext {
+            fn_name: "get_user_success".to_string(),
+            args: vec!["1".to_string()],
+            source: Some(print_source_of_get_user_success().to_string()),
+            docs: Some(docs.to_string()),
+            ..Default::default()
+        }
+        .prompt();
+        assert!(prompt.contains(&format!("Function documentation:\n{}", docs)));
+    }
+
+    #[test]
+    fn test_expansion_shows_the_matched_arm() {
+        // stringify! spaces tokens its own way
+        let compact = |expansion: &str| expansion.split_whitespace().collect::<String>();
+
+        let function = compact(unwrap_or_ai_expand!(get_user_failure(42)));
+        assert!(function.contains("letresult=get_user_failure(42)"));
+        assert!(function.contains("_to_be_annotated_with_unwrap_or_ai_func"));
+        assert!(function.contains("result.unwrap_or_ai_impl(context)"));
+
+        let expression = compact(unwrap_or_ai_expand!(loaded, fallback = None));
+        assert!(expression.contains("letresult=loaded"));
+        assert!(expression.contains("RecoveryContext::new(stringify!(loaded))"));
+        assert!(expression.contains("unwrap_or_ai_or_else(context,||None)"));
+    }
+
+    #[test]
+    fn test_parameter_names_line_up_with_arguments() {
+        assert_eq!(params_of_get_user_success(), ["id"]);
+        assert_eq!(
+            params_of_get_user_preferences(),
+            ["user_id", "preference_type"]
+        );
+    }
+
+    #[test]
+    fn test_prompt_asks_to_keep_identifier_arguments() {
+        let prompt = crate::RecoveryContext {
+            fn_name: "get_user_preferences".to_string(),
+            args: vec!["42".to_string(), "\"theme\"".to_string()],
+            params: params_of_get_user_preferences()
+                .iter()
+                .map(|param| param.to_string())
+                .collect(),
+            source: Some(print_source_of_get_user_preferences().to_string()),
+            ..Default::default()
+        }
+        .prompt();
+
+        assert!(prompt.contains("Keep identifiers unchanged"));
+        assert!(prompt.contains("- user_id: $1"));
+        assert!(!prompt.contains("- preference_type: $2"));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_template_replaces_built_in_wording() {
+        let config = crate::RecoveryConfig::new().with_prompt_template(
+            "Recover {fn_name} as {call} given docs: {docs}. Error: {error}. {args}",
+        );
+        let prompt = crate::config::scope(config, async {
+            crate::RecoveryContext {
+                fn_name: "get_user_success".to_string(),
+                args: vec!["1".to_string()],
+                docs: Some(" Looks up a user ".to_string()),
+                original_error: Some("database offline".into()),
+                ..Default::default()
+            }
+            .prompt()
+        })
+        .await;
+
+        assert_eq!(
+            prompt,
+            "Recover get_user_success as get_user_success($1) given docs: Looks up a user. \
+             Error: database offline. <argument index=\"1\">\n1\n</argument>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_ai_passes_failures_through() {
+        let config = crate::RecoveryConfig::new()
+            .with_providers(vec![unconfigured_provider()])
+            .with_ai_disabled(true);
+        let failed: Result<TestUser, String> = Err("database offline".to_string());
+
+        let error = crate::config::scope(config, async { try_unwrap_or_ai!(failed).await })
+            .await
+            .unwrap_err();
+        assert!(matches!(error.cause(), crate::AiError::Disabled));
+    }
+
+    #[cfg(any(feature = "no-ai", not(feature = "network")))]
+    #[tokio::test]
+    async fn test_no_ai_passes_failures_through() {
+        let missing: Option<TestUser> = None;
+        assert!(option_or_ai!(missing).await.unwrap().is_none());
+
+        let failed: Result<TestUser, String> = Err("database offline".to_string());
+        let error = try_unwrap_or_ai!(failed).await.unwrap_err();
+        assert!(matches!(error.cause(), crate::AiError::Disabled));
+        assert_eq!(
+            error.original_error().unwrap().to_string(),
+            "database offline"
+        );
+    }
+
+    /// A provider without an API key, so any recovery that reaches it fails offline
+    fn unconfigured_provider() -> crate::Provider {
+        crate::Provider::new(
+            "unconfigured",
+            "http://127.0.0.1:1",
+            "UNWRAP_OR_AI_TEST_MISSING_KEY",
+            "test-model",
+        )
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_providers_are_tried_in_order() {
+        // The first provider refuses connections and the second has no key, so both
+        // failures should be reported in priority order
+        let config = crate::RecoveryConfig::new().with_providers(vec![
+            crate::Provider::new("unreachable", "http://127.0.0.1:1", "UNUSED", "test-model")
+                .with_api_key("test-key"),
+            unconfigured_provider(),
+        ]);
+
+        let result = crate::config::scope(config, async {
+            crate::unwrap_or_ai::call_ai_for_type::<TestUser>("prompt".to_string()).await
+        })
+        .await;
+
+        match result {
+            Err(crate::AiError::AllProvidersFailed(failures)) => {
+                let names: Vec<&str> = failures.iter().map(|(name, _)| name.as_str()).collect();
+                assert_eq!(names, ["unreachable", "unconfigured"]);
+                assert!(matches!(failures[0].1, crate::AiError::Network { .. }));
+                // The request that reached the network is tagged with its X-Request-Id
+                let request_id = failures[0].1.request_id().expect("request id");
+                assert!(failures[0].1.to_string().contains(request_id));
+                assert!(matches!(
+                    failures[1].1,
+                    crate::AiError::MissingApiKey { .. }
+                ));
+            }
+            other => panic!("expected every provider to fail, got {:?}", other),
+        }
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_cached_recovery_skips_providers() {
+        // A cache hit must be served even though no provider could answer
+        let cache_path = std::env::temp_dir().join(format!(
+            "unwrap_or_ai_recovery_cache_{}.json",
+            std::process::id()
+        ));
+        let (base_url, requests) = stub_server(vec![(
+            200,
+            completion_body(
+                &serde_json::json!({ "id": 42, "name": "Cached User", "email": "cached@example.com" }),
+            ),
+        )])
+        .await;
+        // Same model as the unconfigured provider, since cache keys include it
+        let provider =
+            crate::Provider::new("stub", base_url, "UNUSED", "test-model").with_api_key("test-key");
+        let recover =
+            || crate::unwrap_or_ai::call_ai_for_type::<TestUser>("recover user 42".to_string());
+
+        let answered = crate::RecoveryConfig::new()
+            .with_providers(vec![provider])
+            .with_disk_cache(&cache_path, std::time::Duration::from_secs(60));
+        crate::config::scope(answered, recover()).await.unwrap();
+
+        // A new cache reads the file back; the unconfigured provider can't answer
+        let offline = crate::RecoveryConfig::new()
+            .with_providers(vec![unconfigured_provider()])
+            .with_disk_cache(&cache_path, std::time::Duration::from_secs(60));
+        let user = crate::config::scope(offline, recover()).await.unwrap();
+
+        assert_eq!(user.name, "Cached User");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        std::fs::remove_file(cache_path).ok();
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct OrderMeta {
+        created_at: String,
+        source_system: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct RenamedOrder {
+        order_id: u32,
+        #[serde(rename = "total")]
+        total_cents: u64,
+        #[serde(flatten)]
+        meta: OrderMeta,
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_renamed_and_flattened_struct_recovered_from_err() {
+        // The keys the schema advertises must be exactly the keys serde accepts
+        let (_, schema) = crate::groq_client::schema_for_type::<RenamedOrder>().unwrap();
+        let from_model = serde_json::json!({
+            "orderId": 9,
+            "total": 1999,
+            "createdAt": "2024-05-01T12:00:00Z",
+            "sourceSystem": "pos"
+        });
+        let mut advertised: Vec<_> = schema["properties"].as_object().unwrap().keys().collect();
+        let mut returned: Vec<_> = from_model.as_object().unwrap().keys().collect();
+        advertised.sort();
+        returned.sort();
+        assert_eq!(advertised, returned);
+
+        let (config, _) = stub_config(vec![(200, completion_body(&from_model))]).await;
+
+        let loaded: Result<RenamedOrder, String> = Err("order service timed out".into());
+        let order = crate::config::scope(config, async { try_unwrap_or_ai!(loaded).await })
+            .await
+            .unwrap();
+
+        assert_eq!(order.order_id, 9);
+        assert_eq!(order.total_cents, 1999);
+        assert_eq!(order.meta.source_system.as_deref(), Some("pos"));
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_raw_json_returned_with_value() {
+        // `nickname` isn't part of TestUser, but the raw JSON keeps it
+        let (config, _) = stub_config(vec![(
+            200,
+            completion_body(
+                &serde_json::json!({ "id": 43, "name": "Raw User", "email": "raw@example.com", "nickname": "rawr" }),
+            ),
+        )]).await;
+
+        let (user, raw) = crate::config::scope(config, async {
+            crate::unwrap_or_ai::call_ai_for_type_raw::<TestUser>(
+                "recover user 43 with raw json".to_string(),
+            )
+            .await
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(user.name, "Raw User");
+        let raw: serde_json::Value = serde_json::from_str(&raw).unwrap();
+        assert_eq!(raw["nickname"], "rawr");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_recovery_returns_cancelled() {
+        let cancel = tokio_util::sync::CancellationToken::new();
+        cancel.cancel();
+
+        let result = crate::unwrap_or_ai::call_ai_for_type_with_cancel::<TestUser>(
+            "never sent".to_string(),
+            &cancel,
+        )
+        .await;
+        assert!(matches!(result, Err(crate::AiError::Cancelled)));
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_try_unwrap_or_ai_returns_recovery_error() {
+        // With no providers the recovery fails, and the error keeps the function's own error
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+
+        let result = crate::config::scope(config, async {
+            try_unwrap_or_ai!(get_user_failure(7)).await
+        })
+        .await;
+
+        let error = result.expect_err("recovery without providers should fail");
+        assert!(matches!(error.cause(), crate::AiError::NoProviders));
+        assert_eq!(
+            std::error::Error::source(&error).map(|source| source.to_string()),
+            Some("User with id 7 not found in database".to_string())
+        );
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
+    struct WeatherData {
+        temperature: f64,
+        conditions: String,
+    }
+
+    /// Reads the last value stored under `key` by the sensor gateway, in whatever shape
+    /// the caller asks for
+    #[unwrap_or_ai_func]
+    fn read_cached<T: serde::de::DeserializeOwned>(key: &str) -> Result<T, String> {
+        Err(format!("nothing cached under {key}"))
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_target_type_inferred_from_binding() {
+        let weather =
+            || completion_body(&serde_json::json!({ "temperature": 21.5, "conditions": "clear" }));
+        let (config, requests) = stub_config(vec![(200, weather()), (200, weather())]).await;
+
+        crate::config::scope(config, async {
+            // Neither the generic function nor the expression names WeatherData
+            let cached: WeatherData = try_unwrap_or_ai!(read_cached("oslo")).await.unwrap();
+            assert_eq!(cached.conditions, "clear");
+
+            let parsed = serde_json::from_str("{ temperature: 21.5 }");
+            let parsed: WeatherData = unwrap_or_ai!(parsed).await;
+            assert_eq!(parsed.temperature, 21.5);
+        })
+        .await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            assert!(request.body.contains("Expected return type: WeatherData"));
+        }
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_async_closure_is_awaited_and_recovered() {
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({ "temperature": 4.0, "conditions": "rain" })),
+        )])
+        .await;
+
+        let station = String::from("bergen");
+        let parsed = crate::config::scope(config, async {
+            let recovered: WeatherData = unwrap_or_ai!(|| async {
+                tokio::task::yield_now().await;
+                Err(format!("station {} is offline", station))
+            })
+            .await;
+            assert_eq!(recovered.conditions, "rain");
+
+            try_unwrap_or_ai!(
+                || async move { station.parse::<f64>() },
+                recover_if = |_| false
+            )
+            .await
+        })
+        .await;
+        assert!(matches!(parsed, Ok(Err(_))));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("Function name: async_closure"));
+        assert!(requests[0].body.contains("tokio::task::yield_now().await"));
+        assert!(requests[0].body.contains("station bergen is offline"));
+    }
+
+    #[derive(Debug, Deserialize, schemars::JsonSchema)]
+    struct Profile {
+        name: String,
+        nickname: Option<String>,
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_fail_closed_rejects_left_out_fields() {
+        let (config, _) = stub_config(vec![
+            (200, completion_body(&serde_json::json!({ "name": "Ada" }))),
+            (
+                200,
+                completion_body(&serde_json::json!({ "nickname": "Ace" })),
+            ),
+        ])
+        .await;
+
+        let (optional_left_out, refused) = crate::config::scope(config, async {
+            let recovery = crate::Recovery::new("Load the profile of user 1").fail_closed();
+            (
+                recovery.recover::<Profile>().await,
+                recovery.recover::<Profile>().await,
+            )
+        })
+        .await;
+
+        // Optional fields may still be left out
+        let profile = optional_left_out.unwrap();
+        assert_eq!(profile.name, "Ada");
+        assert!(profile.nickname.is_none());
+        match refused {
+            Err(crate::AiError::Incomplete { missing, .. }) => assert_eq!(missing, ["name"]),
+            other => panic!("expected an incomplete answer, got {:?}", other),
+        }
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recover_into_replaces_the_value() {
+        let (config, _) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({ "temperature": 12.0, "conditions": "fog" })),
+        )])
+        .await;
+
+        let mut weather = WeatherData {
+            temperature: 0.0,
+            conditions: "unknown".to_string(),
+        };
+        crate::config::scope(config, async {
+            crate::recover_into(&mut weather, "Weather in Bergen".to_string()).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(weather.conditions, "fog");
+        assert_eq!(weather.temperature, 12.0);
+
+        // A failed recovery leaves the value as it was
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let error = crate::config::scope(config, async {
+            crate::recover_into(&mut weather, "Weather in Oslo".to_string()).await
+        })
+        .await;
+        assert!(matches!(error, Err(crate::AiError::NoProviders)));
+        assert_eq!(weather.conditions, "fog");
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recovery_temperature_defaults_to_zero() {
+        let answer =
+            || completion_body(&serde_json::json!({ "temperature": 1.0, "conditions": "hail" }));
+        let (config, requests) = stub_config(vec![(200, answer()), (200, answer())]).await;
+
+        let recover = |config| {
+            crate::config::scope(config, async {
+                crate::Recovery::new("Weather in Tromsø")
+                    .recover::<WeatherData>()
+                    .await
+                    .unwrap()
+            })
+        };
+        recover(config.clone()).await;
+        recover(config.with_temperature(None)).await;
+
+        let requests = requests.lock().unwrap();
+        let body = |i: usize| serde_json::from_str::<serde_json::Value>(&requests[i].body).unwrap();
+        assert_eq!(body(0)["temperature"], 0.0);
+        assert!(body(1).get("temperature").is_none());
+    }
+
+    #[cfg(all(feature = "chrono", feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_chrono_dates_are_normalized() {
+        use chrono::{DateTime, TimeZone, Utc};
+
+        #[derive(Debug, Clone, serde::Deserialize, schemars::JsonSchema)]
+        struct Meeting {
+            title: String,
+            starts_at: DateTime<Utc>,
+        }
+
+        let answer = serde_json::json!({ "title": "Standup", "starts_at": "2024-05-01 09:30" });
+        let (config, requests) = stub_config(vec![(200, completion_body(&answer))]).await;
+
+        let missing: Option<Meeting> = None;
+        let meeting =
+            crate::config::scope(config, async { try_unwrap_or_ai!(missing).await.unwrap() }).await;
+        assert_eq!(meeting.title, "Standup");
+        assert_eq!(
+            meeting.starts_at,
+            Utc.with_ymd_and_hms(2024, 5, 1, 9, 30, 0).unwrap()
+        );
+
+        let requests = requests.lock().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        let schema = &body["response_format"]["json_schema"]["schema"];
+        assert!(
+            schema["properties"]["starts_at"]["description"]
+                .as_str()
+                .unwrap()
+                .contains("RFC 3339")
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_cache_keyed_on_error_message() {
+        let answer =
+            || completion_body(&serde_json::json!({ "temperature": 4.0, "conditions": "rain" }));
+        let (by_error, requests) = stub_config(vec![(200, answer()), (200, answer())]).await;
+        let timed_out = |city: &str| crate::RecoveryContext {
+            fn_name: "fetch_weather".to_string(),
+            args: vec![format!("{:?}", city)],
+            original_error: Some("Database connection timeout".into()),
+            ..Default::default()
+        };
+
+        let by_error = by_error
+            .with_cache(std::time::Duration::from_secs(60))
+            .with_cache_key(crate::CacheKeyStrategy::ErrorMessage);
+        let (first, second) = crate::config::scope(by_error.clone(), async {
+            let first = crate::recover::<WeatherData>(timed_out("Oslo"))
+                .await
+                .unwrap();
+            let second = crate::recover::<WeatherData>(timed_out("Lima"))
+                .await
+                .unwrap();
+            (first, second)
+        })
+        .await;
+        assert_eq!(first.conditions, "rain");
+        assert_eq!(second.conditions, "rain");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Keyed on the prompt, other arguments are another recovery
+        let by_prompt = by_error.with_cache_key(crate::CacheKeyStrategy::Prompt);
+        crate::config::scope(by_prompt, async {
+            crate::recover::<WeatherData>(timed_out("Lima"))
+                .await
+                .unwrap()
+        })
+        .await;
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unit_results_recover_without_a_model() {
+        let flushed: Result<(), String> = Err("disk full".to_string());
+
+        // No providers: any request to a model would fail the recovery
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let recovered =
+            crate::config::scope(config, async { try_unwrap_or_ai!(flushed).await }).await;
+        assert!(recovered.is_ok());
+
+        // Nor does it need AI to be enabled
+        let flushed: Result<(), String> = Err("disk full".to_string());
+        let config = crate::RecoveryConfig::new().with_ai_disabled(true);
+        crate::config::scope(config, async { unwrap_or_ai!(flushed).await }).await;
+        let flushed: Result<(), String> = Err("disk full".to_string());
+        let config = crate::RecoveryConfig::new().with_ai_disabled(true);
+        let recovered =
+            crate::config::scope(config, async { try_unwrap_or_ai!(flushed).await }).await;
+        assert!(recovered.is_ok());
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_types_are_routed_to_models() {
+        #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+        struct Forecast {
+            summary: String,
+        }
+
+        let answer = || completion_body(&serde_json::json!({ "summary": "clear" }));
+        let (config, requests) = stub_config(vec![(200, answer()), (200, answer())]).await;
+
+        crate::route_type::<Forecast>("cheap-model");
+        crate::config::scope(config, async {
+            let forecast =
+                crate::unwrap_or_ai::call_ai_for_type::<Forecast>("Forecast for Oslo".to_string())
+                    .await
+                    .unwrap();
+            assert_eq!(forecast.summary, "clear");
+            // A model chosen for the call itself wins over the route
+            crate::Recovery::new("Forecast for Oslo")
+                .model("strong-model")
+                .recover::<Forecast>()
+                .await
+                .unwrap();
+        })
+        .await;
+        crate::routing::unroute_type::<Forecast>();
+
+        let requests = requests.lock().unwrap();
+        let model = |i: usize| {
+            serde_json::from_str::<serde_json::Value>(&requests[i].body).unwrap()["model"].clone()
+        };
+        assert_eq!(model(0), "cheap-model");
+        assert_eq!(model(1), "strong-model");
+        assert_eq!(crate::routing::routed_model::<Forecast>(), None);
+    }
+
+    #[test]
+    fn test_schema_skeleton_of_test_user() {
+        assert_eq!(
+            crate::schema_skeleton::<TestUser>(),
+            serde_json::json!({ "id": 0, "name": "string", "email": "string" })
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_implausible_numbers_are_rejected() {
+        #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+        struct CoffeeOrder {
+            drink: String,
+            price: f64,
+        }
+
+        let order = |price: f64| serde_json::json!({ "drink": "flat white", "price": price });
+        let (config, _) = stub_config(vec![
+            (200, completion_body(&order(1_000_000.0))),
+            (200, completion_body(&order(4.8))),
+        ])
+        .await;
+
+        crate::plausibility::register_examples(&[
+            CoffeeOrder {
+                drink: "espresso".to_string(),
+                price: 3.5,
+            },
+            CoffeeOrder {
+                drink: "latte".to_string(),
+                price: 6.0,
+            },
+        ]);
+        let (rejected, retried) = crate::config::scope(config, async {
+            let rejected = crate::Recovery::new("Coffee order")
+                .recover::<CoffeeOrder>()
+                .await;
+            let retried = crate::Recovery::new("Coffee order")
+                .recover::<CoffeeOrder>()
+                .await;
+            (rejected, retried)
+        })
+        .await;
+
+        match rejected {
+            Err(crate::AiError::Implausible { fields, .. }) => {
+                assert_eq!(fields, ["price = 1000000 (examples range from 3.5 to 6)"])
+            }
+            other => panic!("expected an implausible answer, got {:?}", other),
+        }
+        assert_eq!(retried.unwrap().price, 4.8);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recover_as_serializes_the_value() {
+        // The model's spacing, key order and integer temperature go through `WeatherData`
+        let answer = completion_body_text(r#"{ "conditions": "sleet",  "temperature": 1 }"#);
+        let (config, _) = stub_config(vec![(200, answer)]).await;
+
+        let bytes = crate::config::scope(config, async {
+            crate::recover_as::<WeatherData, crate::format::Json>("Weather in Narvik".to_string())
+                .await
+                .unwrap()
+        })
+        .await;
+        assert_eq!(bytes, br#"{"temperature":1.0,"conditions":"sleet"}"#);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_missing_required_fields_are_asked_for_again() {
+        let (config, requests) = stub_config(vec![
+            (
+                200,
+                completion_body(&serde_json::json!({ "temperature": 3.0 })),
+            ),
+            (
+                200,
+                completion_body(&serde_json::json!({ "temperature": 3.0, "conditions": "sleet" })),
+            ),
+        ])
+        .await;
+        let config = config.with_required_field_retries(1);
+        let mut events = crate::subscribe_recovery_events();
+        let context = crate::RecoveryContext {
+            fn_name: "missing_fields_test_fn".to_string(),
+            ..Default::default()
+        };
+
+        let weather = crate::config::scope(config, crate::recover::<WeatherData>(context))
+            .await
+            .unwrap();
+        assert_eq!(weather.conditions, "sleet");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].body.contains("left out"));
+        assert!(
+            requests[1]
+                .body
+                .contains("left out these required fields: conditions")
+        );
+
+        // The incomplete answer's tokens are counted too
+        let mut usage = None;
+        while let Ok(event) = events.try_recv() {
+            if let crate::RecoveryEvent::Finished {
+                fn_name: Some(fn_name),
+                usage: finished,
+                ..
+            } = event
+                && fn_name == "missing_fields_test_fn"
+            {
+                usage = finished;
+            }
+        }
+        assert_eq!(usage.map(|usage| usage.total_tokens), Some(30));
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_requests_run_on_the_configured_runtime() {
+        let weather =
+            || completion_body(&serde_json::json!({ "temperature": 21.0, "conditions": "sunny" }));
+        let (config, requests) = stub_config(vec![(200, weather()), (200, weather())]).await;
+        let recovery_runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let config = config.with_runtime(recovery_runtime.handle().clone());
+
+        let recover = || {
+            crate::config::scope(
+                config.clone(),
+                crate::unwrap_or_ai::call_ai_for_type::<WeatherData>("Weather".to_string()),
+            )
+        };
+        let weather = recover().await.unwrap();
+        assert_eq!(weather.conditions, "sunny");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Nothing is sent once the runtime is gone
+        recovery_runtime.shutdown_background();
+        assert!(matches!(recover().await, Err(crate::AiError::Cancelled)));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_refusals_get_their_own_error() {
+        let refusal = serde_json::json!({
+            "id": "chatcmpl-stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub-model",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "refusal": "I can't help with that request."
+                },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 }
+        });
+        let (config, _) = stub_config(vec![
+            (200, refusal.to_string()),
+            (
+                200,
+                completion_body_text("I'm sorry, but I can't generate that."),
+            ),
+            (
+                200,
+                completion_body_text("I'm sorry, but it rained all day."),
+            ),
+        ])
+        .await;
+
+        let (field, prose, number) = crate::config::scope(config, async {
+            let recover =
+                || crate::unwrap_or_ai::call_ai_for_type::<WeatherData>("Weather".to_string());
+            let number = crate::unwrap_or_ai::call_ai_for_type::<f64>("Rainfall".to_string());
+            (recover().await, recover().await, number.await)
+        })
+        .await;
+
+        match field {
+            Err(crate::AiError::ModelRefused { message, .. }) => {
+                assert_eq!(message, "I can't help with that request.")
+            }
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+        match prose {
+            Err(crate::AiError::ModelRefused { message, .. }) => {
+                assert_eq!(message, "I'm sorry, but I can't generate that.")
+            }
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+        // Prose for a number is a wrong answer, not a refusal
+        assert!(
+            matches!(number, Err(crate::AiError::Json { .. })),
+            "{number:?}"
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recover_vec_asks_again_for_the_right_length() {
+        let reading = |temperature: f64| serde_json::json!({ "temperature": temperature, "conditions": "clear" });
+        let (config, requests) = stub_config(vec![
+            (
+                200,
+                completion_body(&serde_json::json!([reading(1.0), reading(2.0)])),
+            ),
+            (
+                200,
+                completion_body(&serde_json::json!([
+                    reading(1.0),
+                    reading(2.0),
+                    reading(3.0)
+                ])),
+            ),
+        ])
+        .await;
+
+        let readings = crate::config::scope(
+            config,
+            crate::recover_vec::<WeatherData>("Hourly weather in Tromsø".to_string(), 3),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            readings.iter().map(|r| r.temperature).collect::<Vec<_>>(),
+            [1.0, 2.0, 3.0]
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].body.contains("Return exactly 3 items."));
+        assert!(
+            requests[1]
+                .body
+                .contains("Your previous answer had 2 items")
+        );
+    }
+
+    #[cfg(feature = "network")]
+    #[tokio::test]
+    async fn test_chat_completion_all_returns_every_choice() {
+        let choice = |index: u32, content: &str, finish_reason: &str| {
+            serde_json::json!({
+                "index": index,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": finish_reason
+            })
+        };
+        let body = serde_json::json!({
+            "id": "chatcmpl-stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub-model",
+            "choices": [
+                choice(0, r#"{"temperature": 7.5, "conditions": "rain"}"#, "stop"),
+                choice(1, r#"{"temperature": 8.0, "condi"#, "length"),
+                choice(2, r#"{"temperature": 6.0, "conditions": "drizzle"}"#, "stop"),
+            ],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 30, "total_tokens": 40 }
+        });
+        let (base_url, _) = stub_server(vec![(200, body.to_string())]).await;
+        let client = crate::GroqClient::new("test-key".to_string())
+            .with_base_url(base_url)
+            .with_candidates(3);
+
+        let all = client
+            .chat_completion_all::<WeatherData>("stub-model", vec![("user", "Weather in Bergen")])
+            .await
+            .unwrap();
+        let summary: Vec<_> = all
+            .iter()
+            .map(|(weather, finish_reason)| (weather.conditions.as_str(), finish_reason.as_str()))
+            .collect();
+        assert_eq!(summary, [("rain", "stop"), ("drizzle", "stop")]);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_chat_completion_all_reports_refusals() {
+        let body = serde_json::json!({
+            "id": "chatcmpl-stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub-model",
+            "choices": [
+                {
+                    "index": 0,
+                    "message": { "role": "assistant", "content": null, "refusal": "Not today." },
+                    "finish_reason": "stop"
+                },
+                {
+                    "index": 1,
+                    "message": { "role": "assistant", "content": "I'm sorry, but I can't share that." },
+                    "finish_reason": "stop"
+                }
+            ],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 10, "total_tokens": 20 }
+        });
+        let (base_url, _) = stub_server(vec![(200, body.to_string())]).await;
+        let client = crate::GroqClient::new("test-key".to_string())
+            .with_base_url(base_url)
+            .with_candidates(2);
+
+        let all = client
+            .chat_completion_all::<WeatherData>("stub-model", vec![("user", "Weather in Tromsø")])
+            .await;
+        match all {
+            Err(crate::AiError::ModelRefused { message, .. }) => assert_eq!(message, "Not today."),
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_assistant_prefill_is_continued() {
+        let (config, requests) = stub_config(vec![
+            (
+                200,
+                completion_body_text(r#""temperature": 5.0, "conditions": "windy"}"#),
+            ),
+            (
+                200,
+                completion_body_text(r#"{"temperature": 7.0, "conditions": "calm"}"#),
+            ),
+            (200, completion_body_text(r#"["Oslo", "Bergen"]"#)),
+        ])
+        .await;
+        let config = config.with_assistant_prefill("{");
+
+        let (continued, restarted, cities) = crate::config::scope(config, async {
+            let recover =
+                || crate::unwrap_or_ai::call_ai_for_type::<WeatherData>("Weather".to_string());
+            (
+                recover().await.unwrap(),
+                recover().await.unwrap(),
+                crate::unwrap_or_ai::call_ai_for_type::<Vec<String>>("Cities".to_string())
+                    .await
+                    .unwrap(),
+            )
+        })
+        .await;
+
+        assert_eq!(continued.conditions, "windy");
+        // An answer that starts over is kept as it is
+        assert_eq!(restarted.conditions, "calm");
+        assert_eq!(cities, ["Oslo", "Bergen"]);
+
+        let requests = requests.lock().unwrap();
+        let last_message = |i: usize| {
+            let body: serde_json::Value = serde_json::from_str(&requests[i].body).unwrap();
+            body["messages"].as_array().unwrap().last().unwrap().clone()
+        };
+        assert_eq!(
+            last_message(0),
+            serde_json::json!({ "role": "assistant", "content": "{" })
+        );
+        // A list isn't an object, so it isn't prefilled
+        assert_eq!(last_message(2)["role"], "user");
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_boxed_futures_are_recovered() {
+        type BoxFuture<T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send>>;
+
+        trait WeatherSource {
+            fn current(&self, city: &str) -> BoxFuture<Result<WeatherData, String>>;
+        }
+
+        struct Offline;
+
+        impl WeatherSource for Offline {
+            fn current(&self, city: &str) -> BoxFuture<Result<WeatherData, String>> {
+                let city = city.to_string();
+                Box::pin(async move { Err(format!("no station in {}", city)) })
+            }
+        }
+
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({ "temperature": -2.0, "conditions": "snow" })),
+        )])
+        .await;
+        let source: Box<dyn WeatherSource> = Box::new(Offline);
+
+        let weather = crate::config::scope(
+            config,
+            crate::recover_future(
+                source.current("Tromsø"),
+                crate::RecoveryContext {
+                    fn_name: "WeatherSource::current".to_string(),
+                    args: vec!["\"Tromsø\"".to_string()],
+                    ..Default::default()
+                },
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(weather.conditions, "snow");
+        assert!(
+            requests.lock().unwrap()[0]
+                .body
+                .contains("no station in Tromsø")
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_map_recovered_from_none() {
+        type Forecast = std::collections::HashMap<String, WeatherData>;
+
+        let (config, _) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({
+                "Oslo": { "temperature": -3.5, "conditions": "snow" },
+                "Lisbon": { "temperature": 18.0, "conditions": "sunny" }
+            })),
+        )])
+        .await;
+
+        let forecast: Option<Forecast> = None;
+        let forecast = crate::config::scope(config, async { try_unwrap_or_ai!(forecast).await })
+            .await
+            .unwrap();
+
+        assert_eq!(forecast.len(), 2);
+        assert_eq!(forecast["Lisbon"].conditions, "sunny");
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_expression_arm_names_target_type() {
+        // The expression arm has no source to send, so the prompt must name the target type
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(
+                &serde_json::json!({ "id": 7, "name": "Recovered Product", "price": 5.0 }),
+            ),
+        )])
+        .await;
+
+        let products: std::collections::HashMap<u32, TestProduct> =
+            std::collections::HashMap::new();
+        let product = crate::config::scope(config, async {
+            try_unwrap_or_ai!(products.get(&7).cloned()).await
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(product.name, "Recovered Product");
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].body.contains("products.get(&7).cloned()"));
+        assert!(
+            requests[0]
+                .body
+                .contains("Expected return type: TestProduct")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallback_used_when_ai_fails() {
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let fallback_user = || TestUser {
+            id: 0,
+            name: "Fallback".to_string(),
+            email: "fallback@example.com".to_string(),
+        };
+
+        let (from_fn, from_expr) = crate::config::scope(config, async {
+            let from_fn = unwrap_or_ai!(get_user_failure(5), fallback = fallback_user()).await;
+            let missing: Option<TestUser> = None;
+            let from_expr = unwrap_or_ai!(missing, fallback = fallback_user()).await;
+            (from_fn, from_expr)
+        })
+        .await;
+
+        assert_eq!(from_fn.name, "Fallback");
+        assert_eq!(from_expr.name, "Fallback");
+    }
+
+    #[tokio::test]
+    async fn test_fallback_is_lazy() {
+        // A present value must not evaluate the fallback expression at all
+        let user = unwrap_or_ai!(
+            get_user_success(9),
+            fallback = panic!("fallback should not be evaluated")
+        )
+        .await;
+
+        assert_eq!(user.id, 9);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recover_without_macro() {
+        let context = crate::RecoveryContext {
+            fn_name: "get_user".to_string(),
+            args: vec!["42".to_string()],
+            source: Some(print_source_of_get_user_success().to_string()),
+            original_error: Some("connection reset".into()),
+            ..Default::default()
+        };
+        let prompt = context.prompt();
+        assert!(prompt.contains("get_user($1)"));
+        assert!(prompt.contains("<argument index=\"1\">\n42\n</argument>"));
+        assert!(prompt.contains("connection reset"));
+
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let error = crate::config::scope(config, crate::recover::<TestUser>(context))
+            .await
+            .expect_err("recovery without providers should fail");
+
+        assert!(matches!(error.cause(), crate::AiError::NoProviders));
+        assert_eq!(
+            std::error::Error::source(&error).map(|source| source.to_string()),
+            Some("connection reset".to_string())
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recovery_events_are_broadcast() {
+        let mut events = crate::subscribe_recovery_events();
+        let config = crate::RecoveryConfig::new().with_providers(vec![unconfigured_provider()]);
+        let context = crate::RecoveryContext {
+            fn_name: "events_test_fn".to_string(),
+            ..Default::default()
+        };
+        let _ = crate::config::scope(config, crate::recover::<TestUser>(context)).await;
+
+        // Other tests may be recovering concurrently; only look at this one's events
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match &event {
+                crate::RecoveryEvent::Started { fn_name, .. }
+                | crate::RecoveryEvent::Finished { fn_name, .. }
+                | crate::RecoveryEvent::Failed { fn_name, .. }
+                    if fn_name.as_deref() == Some("events_test_fn") =>
+                {
+                    seen.push(event)
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(seen.len(), 2);
+        assert!(matches!(
+            &seen[0],
+            crate::RecoveryEvent::Started { provider, .. } if provider == "unconfigured"
+        ));
+        assert!(matches!(
+            &seen[1],
+            crate::RecoveryEvent::Failed { error, .. } if error.contains("UNWRAP_OR_AI_TEST_MISSING_KEY")
+        ));
+    }
+
+    #[unwrap_or_ai_func(model = "openai/gpt-oss-120b")]
+    fn get_discontinued_product(id: u32) -> Option<TestProduct> {
+        let _ = id;
+        None
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_function_model_overrides_provider_model() {
+        assert_eq!(
+            model_of_get_discontinued_product(),
+            Some("openai/gpt-oss-120b")
+        );
+        assert_eq!(model_of_get_user_success(), None);
+
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(
+                &serde_json::json!({ "id": 11, "name": "Archived Widget", "price": 3.0 }),
+            ),
+        )])
+        .await;
+
+        let product = crate::config::scope(config, async {
+            try_unwrap_or_ai!(get_discontinued_product(11)).await
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(product.name, "Archived Widget");
+        // The attribute's model replaced the provider's
+        let body = requests.lock().unwrap()[0].json();
+        assert_eq!(body["model"], "openai/gpt-oss-120b");
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_force_recovery_ignores_successful_value() {
+        let config = crate::RecoveryConfig::new()
+            .with_providers(vec![])
+            .with_force_recovery(true);
+
+        // get_user_success always succeeds, yet the (unavailable) AI is consulted
+        let result = crate::config::scope(config, async {
+            try_unwrap_or_ai!(get_user_success(5)).await
+        })
+        .await;
+        let error = result.expect_err("forced recovery without providers should fail");
+        assert!(matches!(error.cause(), crate::AiError::NoProviders));
+        assert!(error.original_error().is_none());
+        // Nothing failed before the recovery, so there is no source to chain
+        assert!(std::error::Error::source(&error).is_none());
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_error_context_is_sent_with_the_error() {
+        #[derive(Debug)]
+        struct DbError {
+            query: String,
+        }
+
+        impl std::fmt::Display for DbError {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "query timed out")
+            }
+        }
+
+        impl std::error::Error for DbError {}
+
+        impl crate::ErrorContext for DbError {
+            fn error_context(&self) -> serde_json::Value {
+                serde_json::json!({ "query": self.query })
+            }
+        }
+
+        let failed: Result<TestUser, DbError> = Err(DbError {
+            query: "SELECT * FROM users WHERE id = 7".to_string(),
+        });
+        let plain: Result<TestUser, &str> = Err("query timed out");
+        assert_eq!(__unwrap_or_ai_error_context!(plain), None);
+
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({
+                "id": 7,
+                "name": "Recovered",
+                "email": "recovered@example.com"
+            })),
+        )])
+        .await;
+
+        let user = crate::config::scope(config, async { try_unwrap_or_ai!(failed).await })
+            .await
+            .unwrap();
+        assert_eq!(user.id, 7);
+
+        let body: serde_json::Value =
+            serde_json::from_str(&requests.lock().unwrap()[0].body).unwrap();
+        let prompt = body["messages"][1]["content"].as_str().unwrap();
+        assert!(prompt.contains("It failed with: query timed out"));
+        assert!(prompt.contains(r#"Error details: {"query":"SELECT * FROM users WHERE id = 7"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_error_text_only_used_when_displayable() {
+        // Neither Display nor Debug, and certainly not Clone
+        struct OpaqueError;
+
+        let displayable: Result<TestUser, &str> = Err("user table locked");
+        let opaque: Result<TestUser, OpaqueError> = Err(OpaqueError);
+        let missing: Option<TestUser> = None;
+        assert_eq!(
+            __unwrap_or_ai_error_text!(displayable).as_deref(),
+            Some("user table locked")
+        );
+        assert_eq!(__unwrap_or_ai_error_text!(opaque), None);
+        assert_eq!(__unwrap_or_ai_error_text!(missing), None);
+
+        // Recovering an opaque error still works; its text is just left out
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let user = crate::config::scope(config, async {
+            unwrap_or_ai!(
+                opaque,
+                fallback = TestUser {
+                    id: 0,
+                    name: "Opaque".to_string(),
+                    email: "opaque@example.com".to_string(),
+                }
+            )
+            .await
+        })
+        .await;
+        assert_eq!(user.name, "Opaque");
+
+        // And so does the try_ variant, which just has no original error to report
+        let opaque: Result<TestUser, OpaqueError> = Err(OpaqueError);
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let error = crate::config::scope(config, async { try_unwrap_or_ai!(opaque).await })
+            .await
+            .unwrap_err();
+        assert!(error.original_error().is_none());
+    }
+
+    /// Serve one canned `(status, body)` per connection, in order, on a local port.
+    /// Returns the base URL and every request received.
+    async fn stub_server(
+        responses: Vec<(u16, String)>,
+    ) -> (
+        String,
+        std::sync::Arc<std::sync::Mutex<Vec<crate::test_common::Received>>>,
+    ) {
+        let server = crate::test_common::StubServer::start(responses).await;
+        (server.url, server.received)
+    }
+
+    /// A config whose only provider is a [`stub_server`] answering with `responses`.
+    /// Returns the config and every request the server received.
+    async fn stub_config(
+        responses: Vec<(u16, String)>,
+    ) -> (
+        crate::RecoveryConfig,
+        std::sync::Arc<std::sync::Mutex<Vec<crate::test_common::Received>>>,
+    ) {
+        let (base_url, received) = stub_server(responses).await;
+        let provider =
+            crate::Provider::new("stub", base_url, "UNUSED", "stub-model").with_api_key("test-key");
+        (
+            crate::RecoveryConfig::new().with_providers(vec![provider]),
+            received,
+        )
+    }
+
+    /// A chat completions response whose message content is `content`
+    fn completion_body(content: &serde_json::Value) -> String {
+        completion_body_text(&content.to_string())
+    }
+
+    /// Like `completion_body`, for content that isn't valid JSON
+    fn completion_body_text(content: &str) -> String {
+        serde_json::json!({
+            "id": "chatcmpl-stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub-model",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 }
+        })
+        .to_string()
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_schema_rejection_retries_in_json_mode() {
+        let rejection = r#"{"error":{"message":"Invalid schema for response_format 'testuser': 'format' is not permitted","type":"invalid_request_error"}}"#;
+        let (config, requests) = stub_config(vec![
+            (400, rejection.to_string()),
+            (
+                200,
+                completion_body(&serde_json::json!({
+                    "id": 8, "name": "Json Mode", "email": "json@example.com"
+                })),
+            ),
+        ])
+        .await;
+        let user = crate::config::scope(config, async {
+            crate::unwrap_or_ai::call_ai_for_type::<TestUser>("recover user 8".to_string()).await
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(user.name, "Json Mode");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].body.contains("\"json_schema\""));
+        assert!(requests[1].body.contains("\"json_object\""));
+        // The schema moved into the system prompt
+        assert!(
+            requests[1]
+                .body
+                .contains("validates against this JSON schema")
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_quoted_scalars_are_coerced_when_enabled() {
+        let product = || {
+            completion_body(&serde_json::json!({
+                "id": "9", "name": "Quoted", "price": "99.99"
+            }))
+        };
+        let (base_url, _) = stub_server(vec![(200, product()), (200, product())]).await;
+        let provider =
+            crate::Provider::new("stub", base_url, "UNUSED", "stub-model").with_api_key("test-key");
+
+        let strict = crate::RecoveryConfig::new().with_providers(vec![provider.clone()]);
+        let result = crate::config::scope(strict, async {
+            crate::unwrap_or_ai::call_ai_for_type::<TestProduct>("product 9".to_string()).await
+        })
+        .await;
+        let error = result.unwrap_err();
+        match &error {
+            crate::AiError::Deserialize { path, snippet, .. } => {
+                assert_eq!(path, "id");
+                assert_eq!(snippet, "\"9\"");
+            }
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+        assert!(error.to_string().starts_with(
+            "The answer doesn't fit the target type at id: invalid type: string \"9\""
+        ));
+
+        let lenient = crate::RecoveryConfig::new()
+            .with_providers(vec![provider])
+            .with_scalar_coercion(true)
+            .with_response_hook(|mut value, _| {
+                value["name"] = serde_json::json!("Hooked");
+                value
+            });
+        let product = crate::config::scope(lenient, async {
+            crate::unwrap_or_ai::call_ai_for_type::<TestProduct>("product 9".to_string()).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(product.id, 9);
+        assert_eq!(product.price, 99.99);
+        assert_eq!(product.name, "Hooked");
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recovery_builder_retries_rejected_values() {
+        let product = |price: f64| {
+            completion_body(&serde_json::json!({ "id": 4, "name": "Lamp", "price": price }))
+        };
+        let (config, requests) =
+            stub_config(vec![(200, product(-1.0)), (200, product(25.0))]).await;
+
+        let recovery = crate::Recovery::new("The lamp with id 4")
+            .model("builder-model")
+            .temperature(0.0)
+            .system_prompt("Answer with catalog data only.")
+            .retries(1);
+        let lamp = crate::config::scope(config, async {
+            recovery
+                .recover_where(|product: &TestProduct| product.price > 0.0)
+                .await
+        })
+        .await
+        .unwrap();
+        assert_eq!(lamp.price, 25.0);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body["model"], "builder-model");
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(
+            body["messages"][0]["content"],
+            "Answer with catalog data only."
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_only_accepted_answers_are_cached() {
+        let product = |price: f64| {
+            completion_body(&serde_json::json!({ "id": 4, "name": "Lamp", "price": price }))
+        };
+        let reading = |temperature: f64| serde_json::json!({ "temperature": temperature, "conditions": "clear" });
+        let (config, requests) = stub_config(vec![
+            (200, product(-1.0)),
+            (200, product(25.0)),
+            (200, completion_body(&serde_json::json!([reading(1.0)]))),
+            (
+                200,
+                completion_body(&serde_json::json!([reading(1.0), reading(2.0)])),
+            ),
+        ])
+        .await;
+        let config = config.with_cache(std::time::Duration::from_secs(60));
+        let cache = config.cache.clone().unwrap();
+
+        let recovery = crate::Recovery::new("The lamp with id 4").retries(1);
+        let (first, second) = crate::config::scope(config.clone(), async {
+            let positive = |product: &TestProduct| product.price > 0.0;
+            (
+                recovery.recover_where(positive).await.unwrap(),
+                recovery.recover_where(positive).await.unwrap(),
+            )
+        })
+        .await;
+        // The retry asked again instead of reading back the rejected lamp, and the
+        // accepted one served the second call
+        assert_eq!((first.price, second.price), (25.0, 25.0));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert_eq!(cache.len(), 1);
+
+        let readings = crate::config::scope(
+            config,
+            crate::recover_vec::<WeatherData>("Hourly weather in Tromsø".to_string(), 2),
+        )
+        .await
+        .unwrap();
+        assert_eq!(readings.len(), 2);
+        // Only the list of the right length was stored
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recovery_builder_times_out() {
+        // Connections are queued by the OS but never answered
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", silent.local_addr().unwrap());
+        let provider = crate::Provider::new("silent", base_url, "UNUSED", "stub-model")
+            .with_api_key("test-key");
+        let config = crate::RecoveryConfig::new().with_providers(vec![provider]);
+
+        let timeout = std::time::Duration::from_millis(50);
+        let result = crate::config::scope(config, async {
+            crate::Recovery::new("anything")
+                .timeout(timeout)
+                .recover::<TestUser>()
+                .await
+        })
+        .await;
+        assert!(matches!(result, Err(crate::AiError::Timeout(t)) if t == timeout));
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_configured_timeout_covers_every_recovery() {
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", silent.local_addr().unwrap());
+        let provider = crate::Provider::new("silent", base_url, "UNUSED", "stub-model")
+            .with_api_key("test-key");
+        let timeout = std::time::Duration::from_millis(50);
+        let config = crate::RecoveryConfig::new()
+            .with_providers(vec![provider])
+            .with_timeout(timeout);
+
+        let result = crate::config::scope(
+            config,
+            crate::unwrap_or_ai::call_ai_for_type::<TestUser>("anything".to_string()),
+        )
+        .await;
+        assert!(matches!(result, Err(crate::AiError::Timeout(t)) if t == timeout));
+    }
+
+    #[test]
+    fn test_environment_variables_configure_recovery() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        let config = crate::RecoveryConfig::default().with_env(env(&[
+            ("UNWRAP_OR_AI_PROVIDER", "Cerebras"),
+            ("UNWRAP_OR_AI_MODEL", "llama-3.1-8b"),
+            ("UNWRAP_OR_AI_TEMPERATURE", "0.4"),
+            ("UNWRAP_OR_AI_TIMEOUT_MS", "2500"),
+        ]));
+        assert_eq!(config.providers().len(), 1);
+        assert_eq!(config.providers()[0].name(), "cerebras");
+        assert_eq!(config.providers()[0].model(), "llama-3.1-8b");
+        assert_eq!(config.temperature, Some(0.4));
+        assert_eq!(config.timeout, Some(std::time::Duration::from_millis(2500)));
+
+        // Code takes precedence over the environment
+        let config = config.with_temperature(Some(0.9));
+        assert_eq!(config.temperature, Some(0.9));
+
+        // Unparseable and blank values leave the defaults in place
+        let config = crate::RecoveryConfig::default().with_env(env(&[
+            ("UNWRAP_OR_AI_PROVIDER", "openai"),
+            ("UNWRAP_OR_AI_MODEL", " "),
+            ("UNWRAP_OR_AI_TEMPERATURE", "warm"),
+            ("UNWRAP_OR_AI_TIMEOUT_MS", "1.5s"),
+        ]));
+        assert_eq!(config.providers()[0].name(), "groq");
+        assert_eq!(config.providers()[0].model(), crate::models::KIMI_K2);
+        assert_eq!(config.temperature, Some(0.0));
+        assert_eq!(config.timeout, None);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_prompt_hooks_scrub_what_is_sent() {
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({
+                "id": 3, "name": "Ada", "email": "ada@example.com"
+            })),
+        )])
+        .await;
+        let config = config
+            .on_build_prompt(crate::redact::redact_secrets)
+            .on_build_prompt(|prompt| prompt.replace("Ada", "[NAME]"));
+
+        let failed: Result<TestUser, String> =
+            Err("no account for Ada <ada.lovelace@example.com>".to_string());
+        let user =
+            crate::config::scope(config, async { try_unwrap_or_ai!(failed).await.unwrap() }).await;
+        assert_eq!(user.name, "Ada");
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].body.contains("ada.lovelace@example.com"));
+        assert!(
+            requests[0]
+                .body
+                .contains("no account for [NAME] <[REDACTED_EMAIL]>")
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_prompt_prefix_and_suffix_wrap_the_prompt() {
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({ "temperature": 4.0, "conditions": "fog" })),
+        )])
+        .await;
+        let config = config
+            .with_prompt_prefix("You are recovering data for a weather dashboard.")
+            .with_prompt_suffix("Respond with JSON only, no markdown.");
+
+        crate::config::scope(
+            config,
+            crate::unwrap_or_ai::call_ai_for_type::<WeatherData>("Weather in Bergen".to_string()),
+        )
+        .await
+        .unwrap();
+
+        let body: serde_json::Value =
+            serde_json::from_str(&requests.lock().unwrap()[0].body).unwrap();
+        let prompt = body["messages"][1]["content"].as_str().unwrap();
+        assert!(
+            prompt.starts_with(
+                "You are recovering data for a weather dashboard.\n\nWeather in Bergen"
+            )
+        );
+        assert!(prompt.ends_with("\n\nRespond with JSON only, no markdown."));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum DbError {
+        Timeout,
+        InvalidId(u32),
+    }
+
+    impl std::fmt::Display for DbError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                DbError::Timeout => write!(f, "query timed out"),
+                DbError::InvalidId(id) => write!(f, "{id} is not a valid user id"),
+            }
+        }
+    }
+
+    /// Looks up a user, failing with `DbError`
+    #[unwrap_or_ai_func]
+    fn get_user_db(id: u32) -> Result<TestUser, DbError> {
+        if id == 0 {
+            Err(DbError::InvalidId(id))
+        } else {
+            Err(DbError::Timeout)
+        }
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recover_if_only_recovers_accepted_errors() {
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({
+                "id": 4, "name": "Transient", "email": "transient@example.com"
+            })),
+        )])
+        .await;
+
+        let (timed_out, invalid) = crate::config::scope(config, async {
+            let is_transient = |e: &DbError| matches!(e, DbError::Timeout);
+            (
+                try_unwrap_or_ai!(get_user_db(4), recover_if = is_transient).await,
+                try_unwrap_or_ai!(get_user_db(0), recover_if = is_transient).await,
+            )
+        })
+        .await;
+
+        assert_eq!(timed_out.unwrap().unwrap().name, "Transient");
+        assert_eq!(invalid.unwrap().unwrap_err(), DbError::InvalidId(0));
+        // Only the timeout reached the AI
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("query timed out"));
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_provider_request_timeout() {
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", silent.local_addr().unwrap());
+        let provider = crate::Provider::new("silent", base_url, "UNUSED", "stub-model")
+            .with_api_key("test-key")
+            .with_request_timeout(std::time::Duration::from_millis(50));
+        let config = crate::RecoveryConfig::new().with_providers(vec![provider]);
+
+        let missing: Option<TestUser> = None;
+        let error = crate::config::scope(config, async {
+            try_unwrap_or_ai!(missing).await.unwrap_err()
+        })
+        .await;
+
+        // The server never answers, so only the provider's timeout can end the request
+        let crate::AiError::Network { source, .. } = error.cause() else {
+            panic!("expected a network error, got {:?}", error.cause());
+        };
+        assert!(
+            source
+                .downcast_ref::<reqwest::Error>()
+                .unwrap()
+                .is_timeout()
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recover_with_confidence_strips_the_rating() {
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({
+                "value": { "id": 8, "name": "Guess", "email": "guess@example.com" },
+                "_confidence": 0.35
+            })),
+        )])
+        .await;
+
+        let recovered = crate::config::scope(config, async {
+            crate::recover_with_confidence::<TestUser>(crate::RecoveryContext {
+                fn_name: "get_user".to_string(),
+                original_error: Some("user service unavailable".into()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+        })
+        .await;
+
+        assert_eq!(recovered.value.name, "Guess");
+        assert_eq!(recovered.confidence, 0.35);
+        let request: serde_json::Value =
+            serde_json::from_str(&requests.lock().unwrap()[0].body).unwrap();
+        let schema = &request["response_format"]["json_schema"]["schema"];
+        assert_eq!(
+            schema["required"],
+            serde_json::json!(["value", "_confidence"])
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_lenient_json_repairs_malformed_answers() {
+        let malformed = "{'id': 9, 'name': 'Lenient', 'email': 'lenient@example.com',}";
+        let (strict, _) = stub_config(vec![
+            (200, completion_body_text(malformed)),
+            (200, completion_body_text(malformed)),
+        ])
+        .await;
+        let lenient = strict.clone().with_lenient_json(true);
+
+        let missing: Option<TestUser> = None;
+        let error = crate::config::scope(strict, async {
+            try_unwrap_or_ai!(missing.clone()).await.unwrap_err()
+        })
+        .await;
+        assert!(matches!(error.cause(), crate::AiError::Json { .. }));
+
+        let user =
+            crate::config::scope(lenient, async { try_unwrap_or_ai!(missing).await.unwrap() })
+                .await;
+        assert_eq!(user.name, "Lenient");
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_first_valid_candidate_is_used() {
+        let choice = |index: u32, content: &str| {
+            serde_json::json!({
+                "index": index,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop"
+            })
+        };
+        let body = serde_json::json!({
+            "id": "chatcmpl-stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub-model",
+            "choices": [
+                choice(0, r#"{"id": 1, "name": "Missing email"}"#),
+                choice(1, "not json"),
+                choice(2, r#"{"id": 2, "name": "Third", "email": "third@example.com"}"#),
+            ],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 15, "total_tokens": 25 }
+        });
+        let (config, requests) = stub_config(vec![(200, body.to_string())]).await;
+        let config = config.with_candidates(3);
+
+        let missing: Option<TestUser> = None;
+        let user =
+            crate::config::scope(config, async { try_unwrap_or_ai!(missing).await.unwrap() }).await;
+
+        assert_eq!(user.name, "Third");
+        let request: serde_json::Value =
+            serde_json::from_str(&requests.lock().unwrap()[0].body).unwrap();
+        assert_eq!(request["n"], 3);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_rejected_answers_count_against_the_budget() {
+        let (config, _) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({ "nickname": "Ace" })),
+        )])
+        .await;
+
+        let before = crate::stats::TOKENS.spent();
+        let refused = crate::config::scope(config, async {
+            crate::Recovery::new("Load the profile of user 2")
+                .fail_closed()
+                .recover::<Profile>()
+                .await
+        })
+        .await;
+
+        assert!(matches!(refused, Err(crate::AiError::Incomplete { .. })));
+        // Other tests may be spending tokens at the same time
+        assert!(crate::stats::TOKENS.spent() - before >= 15);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_exhausted_token_budget_skips_the_ai() {
+        let (config, requests) = stub_config(vec![]).await;
+        let config = config.with_token_budget(0);
+
+        let failed: Result<TestUser, String> = Err("database offline".to_string());
+        let missing: Option<TestUser> = None;
+        let (error, kept) = crate::config::scope(config, async {
+            (
+                try_unwrap_or_ai!(failed).await.unwrap_err(),
+                option_or_ai!(missing).await.unwrap(),
+            )
+        })
+        .await;
+
+        assert!(matches!(
+            error.cause(),
+            crate::AiError::TokenBudgetExhausted { budget: 0 }
+        ));
+        assert_eq!(
+            error.original_error().unwrap().to_string(),
+            "database offline"
+        );
+        assert!(kept.is_none());
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_source_hash_follows_the_source() {
+        assert_eq!(
+            source_hash_of_get_user_failure(),
+            crate::cache::stable_hash(&[print_source_of_get_user_failure()])
+        );
+        assert_ne!(
+            source_hash_of_get_user_failure(),
+            source_hash_of_get_user_success()
+        );
+
+        let key =
+            |hash| crate::cache::RecoveryCache::versioned_key("model", "User", "prompt", hash);
+        assert_eq!(key(1), key(1));
+        assert_ne!(key(1), key(2));
+        assert_ne!(
+            key(1),
+            crate::cache::RecoveryCache::key("model", "User", "prompt")
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_smart_pointer_targets() {
+        let (config, _) = stub_config(vec![
+            (
+                200,
+                completion_body(&serde_json::json!({
+                    "id": 6, "name": "Shared", "email": "shared@example.com"
+                })),
+            ),
+            (
+                200,
+                completion_body(&serde_json::json!({ "id": 2, "name": "Boxed", "price": 3.5 })),
+            ),
+        ])
+        .await;
+
+        let shared: Option<std::sync::Arc<TestUser>> = None;
+        let boxed: Result<Box<TestProduct>, String> = Err("not in stock".to_string());
+        let (shared, boxed) = crate::config::scope(config, async {
+            (
+                try_unwrap_or_ai!(shared).await.unwrap(),
+                try_unwrap_or_ai!(boxed).await.unwrap(),
+            )
+        })
+        .await;
+
+        assert_eq!(shared.name, "Shared");
+        assert_eq!(boxed.name, "Boxed");
+    }
+
+    // Neither `Clone` nor `Send`: recovery only deserializes and moves the value
+    #[derive(Debug, Deserialize, schemars::JsonSchema)]
+    struct Session {
+        token: String,
+        #[serde(skip)]
+        _not_send: std::marker::PhantomData<std::rc::Rc<()>>,
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_recovers_types_without_clone_or_send() {
+        let (config, _) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({ "token": "abc" })),
+        )])
+        .await;
+
+        let session: Option<std::rc::Rc<Session>> = None;
+        let session =
+            crate::config::scope(config, async { try_unwrap_or_ai!(session).await.unwrap() }).await;
+        assert_eq!(session.token, "abc");
+    }
+
+    #[cfg(feature = "network")]
+    #[tokio::test]
+    async fn test_client_verify_reports_bad_keys() {
+        let (base_url, requests) = stub_server(vec![
+            (200, completion_body(&serde_json::json!("pong"))),
+            (
+                401,
+                r#"{"error":{"message":"Invalid API Key"}}"#.to_string(),
+            ),
+        ])
+        .await;
+        let provider =
+            crate::Provider::new("stub", base_url, "UNUSED", "stub-model").with_api_key("test-key");
+
+        provider.verify().await.unwrap();
+        let error = provider.verify().await.unwrap_err();
+        assert_eq!(error.api_error_kind(), Some(crate::ApiErrorKind::Auth));
+
+        // The provider's own model, which the endpoint actually serves
+        let ping: serde_json::Value =
+            serde_json::from_str(&requests.lock().unwrap()[0].body).unwrap();
+        assert_eq!(ping["max_tokens"], 1);
+        assert_eq!(ping["model"], "stub-model");
+    }
+
+    mod db {
+        use super::TestUser;
+        use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
+
+        /// Loads a user from the accounts table
+        #[unwrap_or_ai_func]
+        pub fn find_user(id: u32) -> Result<TestUser, String> {
+            Err(format!("accounts table unavailable for user {}", id))
+        }
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_path_qualified_call_keeps_source_context() {
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({
+                "id": 5, "name": "Path User", "email": "path@example.com"
+            })),
+        )])
+        .await;
+        let user = crate::config::scope(config, async {
+            try_unwrap_or_ai!(crate::tests::db::find_user(5)).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(user.name, "Path User");
+
+        let requests = requests.lock().unwrap();
+        assert!(
+            requests[0]
+                .body
+                .contains("Loads a user from the accounts table")
+        );
+        assert!(
+            requests[0]
+                .body
+                .contains("accounts table unavailable for user 5")
+        );
+        assert!(requests[0].body.contains("Function name: find_user"));
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_foreign_paths_are_plain_expressions() {
+        struct Settings;
+
+        impl Settings {
+            fn port() -> Option<u16> {
+                None
+            }
+        }
+
+        // Neither can carry #[unwrap_or_ai_func], so both compile as plain expressions
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let (home, port) = crate::config::scope(config, async {
+            (
+                try_unwrap_or_ai!(std::env::var("UNWRAP_OR_AI_TEST_UNSET_VARIABLE")).await,
+                try_unwrap_or_ai!(Settings::port()).await,
+            )
+        })
+        .await;
+
+        assert!(matches!(
+            home.unwrap_err().cause(),
+            crate::AiError::NoProviders
+        ));
+        assert!(matches!(
+            port.unwrap_err().cause(),
+            crate::AiError::NoProviders
+        ));
+    }
+
+    #[derive(Debug, Clone, Deserialize, schemars::JsonSchema)]
+    struct PaymentResult {
+        transaction_id: String,
+        approved: bool,
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_tuple_recovered_from_err() {
+        let (config, requests) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!([
+                { "id": 3, "name": "Tuple User", "email": "tuple@example.com" },
+                { "transaction_id": "tx-3", "approved": true }
+            ])),
+        )])
+        .await;
+        let checkout: Result<(TestUser, PaymentResult), String> =
+            Err("payment gateway timed out".to_string());
+        let (user, payment) =
+            crate::config::scope(config, async { try_unwrap_or_ai!(checkout).await })
+                .await
+                .unwrap();
+
+        assert_eq!(user.name, "Tuple User");
+        assert_eq!(payment.transaction_id, "tx-3");
+        assert!(payment.approved);
+        assert!(requests.lock().unwrap()[0].body.contains("prefixItems"));
+    }
+
+    struct Inventory {
+        products: Vec<TestProduct>,
+    }
+
+    impl Inventory {
+        fn find(&self, id: u32) -> Option<TestProduct> {
+            self.products.iter().find(|p| p.id == id).cloned()
+        }
+
+        fn take(&mut self, id: u32) -> Option<TestProduct> {
+            let index = self.products.iter().position(|p| p.id == id)?;
+            Some(self.products.remove(index))
+        }
+
+        fn into_first(self) -> Option<TestProduct> {
+            self.products.into_iter().next()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_method_call_receivers() {
+        let product = |id, name: &str| TestProduct {
+            id,
+            name: name.to_string(),
+            price: 1.0,
+        };
+        let mut inventory = Inventory {
+            products: vec![product(1, "Bolt"), product(2, "Nut")],
+        };
+
+        // &self: the receiver stays usable afterwards
+        let found = try_unwrap_or_ai!(inventory.find(1)).await.unwrap();
+        assert_eq!(found.name, "Bolt");
+
+        // &mut self: the call runs exactly once, so exactly one product is removed
+        let taken = try_unwrap_or_ai!(inventory.take(1)).await.unwrap();
+        assert_eq!(taken.name, "Bolt");
+        assert_eq!(inventory.products.len(), 1);
+
+        // &mut self on the failure path, recovered through the fallback
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let missing = crate::config::scope(config, async {
+            unwrap_or_ai!(inventory.take(99), fallback = product(0, "Placeholder")).await
+        })
+        .await;
+        assert_eq!(missing.name, "Placeholder");
+        assert_eq!(inventory.products.len(), 1);
+
+        // self by value: the receiver is moved into the call
+        let first = try_unwrap_or_ai!(inventory.into_first()).await.unwrap();
+        assert_eq!(first.name, "Nut");
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_option_or_ai_may_keep_none() {
+        let (config, requests) = stub_config(vec![
+            (
+                200,
+                completion_body(&serde_json::json!({ "found": false, "value": null })),
+            ),
+            (
+                200,
+                completion_body(
+                    &serde_json::json!({ "found": true, "value": { "id": 5, "name": "Gear", "price": 2.5 } }),
+                ),
+            ),
+        ]).await;
+
+        let discontinued: Option<TestProduct> = None;
+        let restocked: Option<TestProduct> = None;
+        let (discontinued, restocked) = crate::config::scope(config, async {
+            (
+                option_or_ai!(discontinued).await.unwrap(),
+                option_or_ai!(restocked).await.unwrap(),
+            )
+        })
+        .await;
+
+        assert!(discontinued.is_none());
+        assert_eq!(
+            restocked.map(|product| product.name).as_deref(),
+            Some("Gear")
+        );
+        // The model is told it may abstain
+        assert!(
+            requests.lock().unwrap()[0]
+                .body
+                .contains(crate::recover::ABSTAIN_INSTRUCTION)
+        );
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_example_skeleton_is_opt_in() {
+        let product = serde_json::json!({ "id": 5, "name": "Gear", "price": 2.5 });
+        let (config, requests) = stub_config(vec![
+            (200, completion_body(&product)),
+            (200, completion_body(&product)),
+        ])
+        .await;
+        let recover =
+            || crate::unwrap_or_ai::call_ai_for_type::<TestProduct>("Product".to_string());
+
+        crate::config::scope(config.clone(), recover())
+            .await
+            .unwrap();
+        crate::config::scope(config.with_example_skeleton(true), recover())
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].body.contains("Example JSON shape"));
+        assert!(requests[1].body.contains("Example JSON shape"));
+    }
+
+    fn default_currency() -> String {
+        "USD".to_string()
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
+    struct PricedItem {
+        sku: String,
+        price: f64,
+        #[serde(default = "default_currency")]
+        currency: String,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_serde_default_fields_may_be_omitted() {
+        // Defaulted fields are optional in the schema, so the model may leave them out
+        let (_, schema) = crate::groq_client::schema_for_type::<PricedItem>().unwrap();
+        let required = schema["required"].as_array().unwrap();
+        assert!(!required.contains(&serde_json::json!("currency")));
+        assert!(!required.contains(&serde_json::json!("tags")));
+
+        let (config, _) = stub_config(vec![(
+            200,
+            completion_body(&serde_json::json!({ "sku": "A-1", "price": 9.5 })),
+        )])
+        .await;
+        let item = crate::config::scope(config, async {
+            crate::unwrap_or_ai::call_ai_for_type::<PricedItem>("recover A-1".to_string()).await
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(item.sku, "A-1");
+        assert_eq!(item.currency, "USD");
+        assert!(item.tags.is_empty());
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_block_recovers_after_any_failed_step() {
+        let steps = std::sync::atomic::AtomicUsize::new(0);
+        let step = |succeed: bool| {
+            steps.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if succeed {
+                Ok(2)
+            } else {
+                Err("inventory service down".to_string())
+            }
+        };
+
+        // Every step succeeds: the block's value is returned as is
+        let total = try_unwrap_or_ai_block! {
+            let a = step(true)?;
+            let b = step(true)?;
+            a + b
+        }
+        .await
+        .unwrap();
+        assert_eq!(total, 4);
+
+        // The second step fails: later steps are skipped and the block is recovered
+        steps.store(0, std::sync::atomic::Ordering::SeqCst);
+        let config = crate::RecoveryConfig::new().with_providers(vec![]);
+        let error = crate::config::scope(config, async {
+            try_unwrap_or_ai_block! {
+                let a = step(true)?;
+                let b = step(false)?;
+                let c = step(true)?;
+                a + b + c
+            }
+            .await
+        })
+        .await
+        .expect_err("recovery without providers should fail");
+
+        assert_eq!(steps.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(matches!(error.cause(), crate::AiError::NoProviders));
+        assert_eq!(
+            error.original_error().map(|e| e.to_string()).as_deref(),
+            Some("inventory service down")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_try_unwrap_or_ai_passes_through_success() {
+        let user = try_unwrap_or_ai!(get_user_success(3)).await.unwrap();
+        assert_eq!(user.id, 3);
+    }
+
+    #[test]
+    fn test_provider_failover_classification() {
+        let server_error = crate::AiError::Api {
+            status: 503,
+            body: "unavailable".to_string(),
+            request_id: None,
+        };
+        let bad_request = crate::AiError::Api {
+            status: 400,
+            body: "bad request".to_string(),
+            request_id: None,
+        };
+
+        assert!(server_error.should_try_next_provider());
+        assert!(!bad_request.should_try_next_provider());
+        assert!(!crate::AiError::NoChoices { request_id: None }.should_try_next_provider());
+
+        let schema_rejection = crate::AiError::Api {
+            status: 400,
+            body: "response_format.json_schema: 'minimum' is not supported".to_string(),
+            request_id: None,
+        };
+        assert!(schema_rejection.is_schema_rejection());
+        assert!(!bad_request.is_schema_rejection());
+        assert!(!server_error.is_schema_rejection());
+
+        let rejected_param = crate::AiError::Api {
+            status: 400,
+            body:
+                r#"{"error":{"message":"Invalid parameter","param":"response_format","code":null}}"#
+                    .to_string(),
+            request_id: None,
+        };
+        assert!(rejected_param.is_schema_rejection());
+        let other_validation = crate::AiError::Api {
+            status: 400,
+            body: r#"{"error":{"message":"messages.0 does not match the schema: 'content' is required","type":"invalid_request_error"}}"#
+                .to_string(),
+            request_id: None,
+        };
+        assert!(!other_validation.is_schema_rejection());
+    }
+
+    #[test]
+    fn test_api_status_classification() {
+        use crate::ApiErrorKind;
+
+        let api_error = |status| crate::AiError::Api {
+            status,
+            body: "{}".to_string(),
+            request_id: None,
+        };
+
+        let cases = [
+            (
+                401,
+                ApiErrorKind::Auth,
+                "authentication failed with status 401",
+            ),
+            (403, ApiErrorKind::Auth, "check the API key"),
+            (
+                429,
+                ApiErrorKind::RateLimited,
+                "Rate limited by the API (status 429)",
+            ),
+            (500, ApiErrorKind::Server, "server error with status 500"),
+            (503, ApiErrorKind::Server, "server error with status 503"),
+            (400, ApiErrorKind::Client, "request failed with status 400"),
+            (404, ApiErrorKind::Client, "request failed with status 404"),
+        ];
+        for (status, kind, message) in cases {
+            let error = api_error(status);
+            assert_eq!(error.api_error_kind(), Some(kind), "status {}", status);
+            assert!(error.to_string().contains(message), "{}", error);
+            assert_eq!(
+                error.should_try_next_provider(),
+                matches!(kind, ApiErrorKind::Server | ApiErrorKind::RateLimited)
+            );
+        }
+
+        assert_eq!(crate::AiError::NoProviders.api_error_kind(), None);
+    }
+
+    #[test]
+    fn test_recoverable_bounds() {
+        // Any type meeting the recovery bounds is Recoverable without deriving it
+        fn assert_recoverable<T: crate::Recoverable>() {}
+
+        assert_recoverable::<TestUser>();
+        assert_recoverable::<TestProduct>();
+    }
+
     #[test]
     fn test_type_constraints() {
         // This test ensures our types implement the required traits
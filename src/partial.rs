@@ -0,0 +1,267 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::AiError;
+use crate::events::{self, RecoveryEvent};
+use crate::recoverable::Recoverable;
+use crate::unwrap_or_ai::{CallOptions, call_ai_for_json};
+
+/// Complete a partially populated value, letting the AI fill only the missing fields.
+///
+/// `partial` is usually a `serde_json::Value` or an instance of `T` built from incomplete
+/// data. Fields that are `null` or an empty string count as missing, at any depth. The
+/// model sees the known fields as context, and every field that was present in `partial`
+/// is kept verbatim in the result, whatever the model returned for it.
+///
+/// The fields that were filled in are logged at debug level and reported as a
+/// [`RecoveryEvent::Filled`], along with their values before and after.
+///
+/// ```no_run
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
+/// # struct User { id: u32, name: String, email: String }
+/// # async fn run() -> Result<(), unwrap_or_ai::AiError> {
+/// let from_db = serde_json::json!({ "id": 42, "name": "Ada Lovelace", "email": null });
+///
+/// let user: User = unwrap_or_ai::partial::recover_partial(
+///     &from_db,
+///     "The users table returned a row without an email".to_string(),
+/// )
+/// .await?;
+/// assert_eq!(user.name, "Ada Lovelace");
+/// # Ok(())
+/// # }
+/// ```
+pub async fn recover_partial<T, P>(partial: &P, prompt: String) -> Result<T, AiError>
+where
+    T: Recoverable,
+    P: Serialize + ?Sized,
+{
+    let partial = serde_json::to_value(partial)?;
+    let missing = missing_fields(&partial);
+
+    // Nothing to fill in: the partial value may already be complete
+    if missing.is_empty()
+        && let Ok(complete) = serde_json::from_value(partial.clone())
+    {
+        return Ok(complete);
+    }
+
+    let prompt = format!(
+        "{}\n\nThe following object was only partially retrieved: {}\nFill in only these missing fields: {}. Copy every other field exactly as given.",
+        prompt,
+        partial,
+        missing.join(", ")
+    );
+
+    let recovered = call_ai_for_json::<T>(prompt, CallOptions::default()).await?;
+    let merged = merge_partial(partial.clone(), recovered.json);
+
+    let changes = diff_fields(&partial, &merged);
+    for change in &changes {
+        log::debug!("AI recovery filled in {}", change);
+    }
+    events::emit(RecoveryEvent::Filled {
+        request_id: recovered.request_id,
+        fn_name: None,
+        changes,
+    });
+
+    Ok(serde_json::from_value(merged)?)
+}
+
+/// A field whose value differs between two versions of an object
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    /// Dotted path, as in [`missing_fields`]; empty when the whole value differs
+    pub path: String,
+    /// `null` when the field was absent
+    pub original: Value,
+    pub recovered: Value,
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}`: {} -> {}",
+            self.path, self.original, self.recovered
+        )
+    }
+}
+
+/// Every field of `recovered` that isn't the same in `original`, at any depth.
+///
+/// Fields only `original` has are not reported, since recovery never removes data.
+pub fn diff_fields(original: &Value, recovered: &Value) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    collect_changes(Some(original), recovered, "", &mut changes);
+    changes
+}
+
+fn collect_changes(
+    original: Option<&Value>,
+    recovered: &Value,
+    path: &str,
+    changes: &mut Vec<FieldChange>,
+) {
+    match (original, recovered) {
+        (Some(Value::Object(before)), Value::Object(after)) => {
+            for (name, field) in after {
+                let field_path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                collect_changes(before.get(name), field, &field_path, changes);
+            }
+        }
+        (Some(before), after) if before == after => {}
+        (before, after) => changes.push(FieldChange {
+            path: path.to_string(),
+            original: before.cloned().unwrap_or(Value::Null),
+            recovered: after.clone(),
+        }),
+    }
+}
+
+/// Dotted paths of every `null` or empty-string field in `value`
+pub fn missing_fields(value: &Value) -> Vec<String> {
+    let mut missing = Vec::new();
+    collect_missing(value, "", &mut missing);
+    missing
+}
+
+fn collect_missing(value: &Value, path: &str, missing: &mut Vec<String>) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                let field_path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                collect_missing(field, &field_path, missing);
+            }
+        }
+        _ if is_missing(value) && !path.is_empty() => missing.push(path.to_string()),
+        _ => {}
+    }
+}
+
+fn is_missing(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+/// Overlay `partial` onto `recovered`: known fields win, missing ones come from the AI.
+/// A `null` field the AI didn't fill either is dropped rather than kept as `null`.
+pub fn merge_partial(partial: Value, recovered: Value) -> Value {
+    match (partial, recovered) {
+        (Value::Object(known), Value::Object(mut filled)) => {
+            let mut merged = Map::new();
+            for (name, field) in known {
+                let value = match filled.remove(&name) {
+                    Some(recovered_field) => merge_partial(field, recovered_field),
+                    // Left out entirely, so `#[serde(default)]` fields get their default
+                    None if field.is_null() => continue,
+                    None => field,
+                };
+                merged.insert(name, value);
+            }
+            // Fields the partial value didn't mention at all are taken from the AI
+            merged.extend(filled);
+            Value::Object(merged)
+        }
+        (known, recovered) if is_missing(&known) => recovered,
+        (known, _) => known,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_missing_fields_are_found_at_any_depth() {
+        let partial = json!({
+            "id": 42,
+            "name": "Ada",
+            "email": null,
+            "address": { "city": "", "zip": "12345" }
+        });
+
+        assert_eq!(missing_fields(&partial), ["address.city", "email"]);
+    }
+
+    #[test]
+    fn test_known_fields_win_when_merging() {
+        let partial = json!({ "id": 42, "name": "Ada", "email": null, "address": { "city": "" } });
+        let recovered = json!({
+            "id": 7,
+            "name": "Someone Else",
+            "email": "ada@example.com",
+            "address": { "city": "London" },
+            "age": 36
+        });
+
+        assert_eq!(
+            merge_partial(partial, recovered),
+            json!({
+                "id": 42,
+                "name": "Ada",
+                "email": "ada@example.com",
+                "address": { "city": "London" },
+                "age": 36
+            })
+        );
+    }
+
+    #[test]
+    fn test_diff_lists_filled_fields() {
+        let partial = json!({ "id": 42, "email": null, "address": { "city": "", "zip": "123" } });
+        let merged = merge_partial(
+            partial.clone(),
+            json!({ "id": 7, "email": "ada@example.com", "address": { "city": "London" }, "age": 36 }),
+        );
+
+        let changes = diff_fields(&partial, &merged);
+        let paths: Vec<_> = changes.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, ["address.city", "age", "email"]);
+        assert_eq!(
+            changes[2],
+            FieldChange {
+                path: "email".to_string(),
+                original: Value::Null,
+                recovered: json!("ada@example.com"),
+            }
+        );
+        assert_eq!(changes[0].to_string(), "`address.city`: \"\" -> \"London\"");
+    }
+
+    #[test]
+    fn test_unfilled_nulls_are_dropped_for_serde_defaults() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Profile {
+            name: String,
+            #[serde(default)]
+            tags: Vec<String>,
+        }
+
+        let merged = merge_partial(
+            json!({ "name": "Ada", "tags": null }),
+            json!({ "name": "Ada" }),
+        );
+        assert_eq!(merged, json!({ "name": "Ada" }));
+
+        let profile: Profile = serde_json::from_value(merged).unwrap();
+        assert_eq!(profile.name, "Ada");
+        assert!(profile.tags.is_empty());
+    }
+}
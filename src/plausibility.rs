@@ -0,0 +1,229 @@
+//! A statistical guard against hallucinated magnitudes, built from example values.
+//!
+//! Register a few real instances of a type with [`register_examples`] and every numeric
+//! field gets a plausible range from them. A recovered value with a number far outside
+//! its field's range, like a $1,000,000 coffee next to examples costing $3-6, is
+//! rejected with [`AiError::Implausible`](crate::AiError::Implausible) instead of being
+//! returned. [`Recovery::retries`](crate::Recovery::retries) asks again after a
+//! rejection.
+//!
+//! Types without registered examples are not checked.
+
+use std::any::TypeId;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, RwLock};
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// How far outside the examples' range a number may fall, in multiples of the range's
+/// scale; see [`FieldRange::allows`]
+pub const DEFAULT_TOLERANCE: f64 = 10.0;
+
+/// The smallest and largest value a numeric field took in the examples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl FieldRange {
+    /// Whether `value` is at most `tolerance` times the range's scale beyond it. The
+    /// scale is the larger of the range's width and its largest magnitude, so a field
+    /// whose examples were all equal still allows some variation.
+    pub fn allows(&self, value: f64, tolerance: f64) -> bool {
+        let scale = (self.max - self.min)
+            .max(self.min.abs())
+            .max(self.max.abs());
+        let slack = scale * tolerance;
+        value >= self.min - slack && value <= self.max + slack
+    }
+
+    fn include(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+struct Ranges {
+    fields: BTreeMap<String, FieldRange>,
+    tolerance: f64,
+}
+
+static RANGES: LazyLock<RwLock<HashMap<TypeId, Ranges>>> = LazyLock::new(Default::default);
+
+/// Check recovered `T`s against ranges learned from `examples`, with
+/// [`DEFAULT_TOLERANCE`]. Registering again replaces the previous examples.
+pub fn register_examples<T: Serialize + 'static>(examples: &[T]) {
+    register_examples_with_tolerance(examples, DEFAULT_TOLERANCE);
+}
+
+/// Like [`register_examples`], allowing numbers up to `tolerance` times the range's
+/// scale outside it
+pub fn register_examples_with_tolerance<T: Serialize + 'static>(examples: &[T], tolerance: f64) {
+    let mut fields = BTreeMap::new();
+    for example in examples {
+        if let Ok(value) = serde_json::to_value(example) {
+            collect_ranges(&value, String::new(), &mut fields);
+        }
+    }
+
+    RANGES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(TypeId::of::<T>(), Ranges { fields, tolerance });
+}
+
+/// The range learned for each numeric field of `T`, by path (`price`,
+/// `items[].quantity`); `None` if no examples were registered
+pub fn field_ranges<T: ?Sized + 'static>() -> Option<BTreeMap<String, FieldRange>> {
+    RANGES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&TypeId::of::<T>())
+        .map(|ranges| ranges.fields.clone())
+}
+
+/// Stop checking recovered `T`s
+pub fn forget_examples<T: ?Sized + 'static>() {
+    RANGES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&TypeId::of::<T>());
+}
+
+// Every number in `value` outside its field's range, described for an error message
+pub(crate) fn implausible_fields<T: ?Sized + 'static>(value: &Value) -> Vec<String> {
+    let registry = RANGES.read().unwrap_or_else(|e| e.into_inner());
+    let Some(ranges) = registry.get(&TypeId::of::<T>()) else {
+        return Vec::new();
+    };
+
+    let mut numbers = Vec::new();
+    collect_numbers(value, String::new(), &mut numbers);
+    numbers
+        .into_iter()
+        .filter_map(|(path, number)| {
+            let range = ranges.fields.get(&path)?;
+            (!range.allows(number, ranges.tolerance)).then(|| {
+                format!(
+                    "{} = {} (examples range from {} to {})",
+                    display_path(&path),
+                    number,
+                    range.min,
+                    range.max
+                )
+            })
+        })
+        .collect()
+}
+
+fn collect_ranges(value: &Value, path: String, fields: &mut BTreeMap<String, FieldRange>) {
+    let mut numbers = Vec::new();
+    collect_numbers(value, path, &mut numbers);
+    for (path, number) in numbers {
+        fields
+            .entry(path)
+            .or_insert(FieldRange {
+                min: number,
+                max: number,
+            })
+            .include(number);
+    }
+}
+
+// Array elements share one path, so `items[].price` covers every item
+fn collect_numbers(value: &Value, path: String, numbers: &mut Vec<(String, f64)>) {
+    match value {
+        Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                numbers.push((path, number));
+            }
+        }
+        Value::Object(object) => {
+            for (key, field) in object {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                collect_numbers(field, path, numbers);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_numbers(item, format!("{}[]", path), numbers);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() { "value" } else { path }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Serialize)]
+    struct LineItem {
+        sku: String,
+        quantity: u32,
+    }
+
+    #[derive(Serialize)]
+    struct Order {
+        coffee_price: f64,
+        items: Vec<LineItem>,
+    }
+
+    #[test]
+    fn test_ranges_from_examples() {
+        let order = |coffee_price, quantities: &[u32]| Order {
+            coffee_price,
+            items: quantities
+                .iter()
+                .map(|&quantity| LineItem {
+                    sku: "BEAN-1".to_string(),
+                    quantity,
+                })
+                .collect(),
+        };
+        register_examples(&[order(3.5, &[1, 2]), order(6.0, &[4])]);
+
+        let ranges = field_ranges::<Order>().unwrap();
+        assert_eq!(ranges["coffee_price"], FieldRange { min: 3.5, max: 6.0 });
+        assert_eq!(
+            ranges["items[].quantity"],
+            FieldRange { min: 1.0, max: 4.0 }
+        );
+
+        let plausible = json!({ "coffee_price": 7.25, "items": [{ "sku": "X", "quantity": 12 }] });
+        assert!(implausible_fields::<Order>(&plausible).is_empty());
+
+        let hallucinated = json!({ "coffee_price": 1_000_000.0, "items": [{ "quantity": 3 }] });
+        assert_eq!(
+            implausible_fields::<Order>(&hallucinated),
+            ["coffee_price = 1000000 (examples range from 3.5 to 6)"]
+        );
+
+        forget_examples::<Order>();
+        assert!(implausible_fields::<Order>(&hallucinated).is_empty());
+    }
+
+    #[test]
+    fn test_tolerance() {
+        let range = FieldRange {
+            min: 10.0,
+            max: 10.0,
+        };
+        assert!(range.allows(100.0, 10.0));
+        assert!(!range.allows(200.0, 10.0));
+        assert!(!range.allows(20.5, 1.0));
+        assert!(range.allows(0.5, 1.0));
+        assert!(!range.allows(-0.5, 1.0));
+    }
+}
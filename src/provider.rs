@@ -0,0 +1,435 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::AiError;
+use crate::groq_client::{GroqClient, MessageRoles, PrefillStyle, models};
+use crate::rate_limit::{RateLimitState, RateLimiter};
+
+/// How long a key fetched through [`Provider::with_api_key_provider`] is reused by default
+pub const DEFAULT_API_KEY_TTL: Duration = Duration::from_secs(300);
+
+type KeyFuture = Pin<Box<dyn Future<Output = Result<String, BoxError>> + Send>>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// A user callback producing API keys, plus the last key it returned. Tasks that need a
+// key at the same time (concurrent recoveries at startup) wait for one fetch instead of
+// each calling the key service, while a still valid key is handed out without waiting.
+#[derive(Clone)]
+struct KeySource {
+    fetch: Arc<dyn Fn() -> KeyFuture + Send + Sync>,
+    cached: Arc<Mutex<Option<(String, Instant)>>>,
+    fetching: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeySource").finish_non_exhaustive()
+    }
+}
+
+impl KeySource {
+    async fn get(&self, ttl: Duration) -> Result<String, BoxError> {
+        if let Some(key) = self.fresh(ttl) {
+            return Ok(key);
+        }
+
+        let _fetching = self.fetching.lock().await;
+        // Another task may have fetched it while this one waited
+        if let Some(key) = self.fresh(ttl) {
+            return Ok(key);
+        }
+        let key = (self.fetch)().await?;
+        *self.cached.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some((key.clone(), Instant::now()));
+        Ok(key)
+    }
+
+    fn fresh(&self, ttl: Duration) -> Option<String> {
+        match &*self.cached.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some((key, fetched_at)) if fetched_at.elapsed() < ttl => Some(key.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// An OpenAI-compatible chat completions endpoint that recovery requests can be sent to
+#[derive(Debug, Clone)]
+pub struct Provider {
+    pub(crate) name: String,
+    pub(crate) base_url: String,
+    pub(crate) completions_path: Option<String>,
+    pub(crate) api_key_env: String,
+    pub(crate) api_key: Option<String>,
+    key_source: Option<KeySource>,
+    api_key_ttl: Duration,
+    pub(crate) model: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) roles: MessageRoles,
+    pub(crate) prefill_style: PrefillStyle,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    // Built once on first use, even when many recoveries start at the same time, and
+    // shared by clones, so the provider's recoveries reuse connections
+    #[cfg(feature = "network")]
+    http_client: Arc<tokio::sync::OnceCell<reqwest::Client>>,
+    rate_limiter: RateLimiter,
+    #[cfg(all(test, feature = "network"))]
+    client_builds: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl Provider {
+    /// Create a provider from its name, base URL, API key environment variable and model
+    pub fn new(
+        name: impl Into<String>,
+        base_url: impl Into<String>,
+        api_key_env: impl Into<String>,
+        model: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            base_url: base_url.into(),
+            completions_path: None,
+            api_key_env: api_key_env.into(),
+            api_key: None,
+            key_source: None,
+            api_key_ttl: DEFAULT_API_KEY_TTL,
+            model: model.into(),
+            headers: Vec::new(),
+            roles: MessageRoles::openai(),
+            prefill_style: PrefillStyle::Message,
+            connect_timeout: None,
+            request_timeout: None,
+            #[cfg(feature = "network")]
+            http_client: Arc::default(),
+            rate_limiter: RateLimiter::new(),
+            #[cfg(all(test, feature = "network"))]
+            client_builds: Arc::default(),
+        }
+    }
+
+    /// Groq, keyed by `GROQ_API` and using Kimi K2 for structured output
+    pub fn groq() -> Self {
+        Self::new(
+            "groq",
+            "https://api.groq.com/openai/v1",
+            "GROQ_API",
+            models::KIMI_K2,
+        )
+    }
+
+    /// Cerebras, keyed by `CEREBRAS_API_KEY`
+    pub fn cerebras() -> Self {
+        Self::new(
+            "cerebras",
+            "https://api.cerebras.ai/v1",
+            "CEREBRAS_API_KEY",
+            "llama-3.3-70b",
+        )
+    }
+
+    // The preset called `name`, for `UNWRAP_OR_AI_PROVIDER`
+    pub(crate) fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "groq" => Some(Self::groq()),
+            "cerebras" => Some(Self::cerebras()),
+            _ => None,
+        }
+    }
+
+    /// Use a different model on this provider
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Post completions to `path` under the base URL instead of `/chat/completions`
+    pub fn with_completions_path(mut self, path: impl Into<String>) -> Self {
+        self.completions_path = Some(path.into());
+        self
+    }
+
+    /// Use this API key instead of reading the provider's environment variable
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Add a header sent with every request to this provider, e.g. for gateway routing
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Give up connecting to this provider after `timeout`
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self.reset_http_client();
+        self
+    }
+
+    /// Give up on a request to this provider that hasn't been answered after `timeout`.
+    ///
+    /// Unlike [`RecoveryConfig::with_timeout`](crate::RecoveryConfig::with_timeout), which
+    /// bounds the whole recovery, a request that times out here counts as a network error,
+    /// so the next provider is tried.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self.reset_http_client();
+        self
+    }
+
+    // Clones made before a setting changed keep their own pool
+    fn reset_http_client(&mut self) {
+        #[cfg(feature = "network")]
+        {
+            self.http_client = Arc::default();
+        }
+    }
+
+    /// Rename message roles, or move the system prompt out of `messages`, for providers
+    /// that don't follow OpenAI's conventions
+    pub fn with_message_roles(mut self, roles: MessageRoles) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// How this provider takes an
+    /// [assistant prefill](crate::RecoveryConfig::with_assistant_prefill), e.g.
+    /// [`PrefillStyle::PrefixFlag`] for Mistral or DeepSeek
+    pub fn with_prefill_style(mut self, style: PrefillStyle) -> Self {
+        self.prefill_style = style;
+        self
+    }
+
+    /// Fetch the API key on demand, e.g. from Vault or AWS Secrets Manager.
+    ///
+    /// The callback replaces the environment variable lookup and its key is reused for
+    /// [`DEFAULT_API_KEY_TTL`]; see [`Provider::with_api_key_ttl`]. A sync source can
+    /// simply return a ready future: `|| async { read_key_file() }`.
+    pub fn with_api_key_provider<F, Fut, E>(mut self, fetch: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, E>> + Send + 'static,
+        E: Into<BoxError>,
+    {
+        let fetch = Arc::new(move || -> KeyFuture {
+            let fut = fetch();
+            Box::pin(async move { fut.await.map_err(Into::into) })
+        });
+        self.key_source = Some(KeySource {
+            fetch,
+            cached: Arc::new(Mutex::new(None)),
+            fetching: Arc::default(),
+        });
+        self
+    }
+
+    /// How long a key from [`Provider::with_api_key_provider`] is reused before fetching
+    /// again. May be set before or after the key callback.
+    pub fn with_api_key_ttl(mut self, ttl: Duration) -> Self {
+        self.api_key_ttl = ttl;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// The `x-ratelimit-*` headers this provider last sent, shared by all its clones
+    pub fn rate_limit_state(&self) -> Option<RateLimitState> {
+        self.rate_limiter.state()
+    }
+
+    /// The explicitly configured key, then the key callback, then the environment variable
+    pub(crate) async fn resolve_api_key(&self) -> Result<String, AiError> {
+        if let Some(api_key) = &self.api_key {
+            return Ok(api_key.clone());
+        }
+
+        if let Some(source) = &self.key_source {
+            return source
+                .get(self.api_key_ttl)
+                .await
+                .map_err(|source| AiError::ApiKeyProvider {
+                    provider: self.name.clone(),
+                    source,
+                });
+        }
+
+        std::env::var(&self.api_key_env).map_err(|_| AiError::MissingApiKey {
+            provider: self.name.clone(),
+            env_var: self.api_key_env.clone(),
+        })
+    }
+
+    /// Check that the provider can be reached and accepts the key, by pinging its model
+    /// with a one-token completion
+    pub async fn verify(&self) -> Result<(), AiError> {
+        self.client().await?.verify(&self.model).await
+    }
+
+    /// Build a client pointed at this provider
+    pub(crate) async fn client(&self) -> Result<GroqClient, AiError> {
+        let mut client = GroqClient::new(self.resolve_api_key().await?)
+            .with_base_url(&self.base_url)
+            .with_message_roles(self.roles.clone())
+            .with_rate_limiter(self.rate_limiter.clone());
+        if let Some(path) = &self.completions_path {
+            client = client.with_completions_path(path);
+        }
+        #[cfg(feature = "network")]
+        {
+            client = client.with_http_client(self.http_client().await?);
+        }
+
+        Ok(self.headers.iter().fold(client, |client, (name, value)| {
+            client.with_header(name, value)
+        }))
+    }
+
+    // Proxies come from the usual `HTTPS_PROXY`-style environment variables
+    #[cfg(feature = "network")]
+    async fn http_client(&self) -> Result<reqwest::Client, AiError> {
+        let client = self
+            .http_client
+            .get_or_try_init(|| async {
+                #[cfg(test)]
+                self.client_builds
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut builder = reqwest::Client::builder();
+                if let Some(timeout) = self.connect_timeout {
+                    builder = builder.connect_timeout(timeout);
+                }
+                if let Some(timeout) = self.request_timeout {
+                    builder = builder.timeout(timeout);
+                }
+                builder.build()
+            })
+            .await?;
+        Ok(client.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_api_key_provider_is_cached() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = Provider::groq().with_api_key_provider(move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, String>("vault-key".to_string())
+            }
+        });
+
+        assert_eq!(provider.resolve_api_key().await.unwrap(), "vault-key");
+        assert_eq!(provider.resolve_api_key().await.unwrap(), "vault-key");
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // A zero TTL fetches again every time
+        let provider = provider.with_api_key_ttl(Duration::ZERO);
+        provider.resolve_api_key().await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_ttl_set_before_the_key_provider() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = Provider::groq()
+            .with_api_key_ttl(Duration::ZERO)
+            .with_api_key_provider(move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, String>("vault-key".to_string())
+                }
+            });
+
+        provider.resolve_api_key().await.unwrap();
+        provider.resolve_api_key().await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(all(feature = "network", not(feature = "no-ai")))]
+    #[tokio::test]
+    async fn test_concurrent_first_recoveries_share_one_client_and_key() {
+        #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+        struct Reading {
+            celsius: f64,
+        }
+
+        const TASKS: usize = 16;
+        let answer = serde_json::json!({
+            "id": "chatcmpl-stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub-model",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": r#"{"celsius": 9.0}"# },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 }
+        })
+        .to_string();
+        let server = crate::test_common::StubServer::start(vec![(200, answer); TASKS]).await;
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = Provider::new("stub", &server.url, "UNUSED", "stub-model")
+            .with_api_key_provider(move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    // Slow enough that every task asks for a key before it arrives
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok::<_, String>("test-key".to_string())
+                }
+            });
+        let config = crate::RecoveryConfig::new().with_providers(vec![provider.clone()]);
+
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|i| {
+                tokio::spawn(crate::config::scope(config.clone(), async move {
+                    crate::Recovery::new(format!("Temperature at station {}", i))
+                        .recover::<Reading>()
+                        .await
+                }))
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap().celsius, 9.0);
+        }
+
+        assert_eq!(server.requests(), TASKS);
+        assert_eq!(provider.client_builds.load(Ordering::SeqCst), 1);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_provider_errors_are_reported() {
+        let provider = Provider::groq()
+            .with_api_key_provider(|| async { Err::<String, _>("vault sealed".to_string()) });
+
+        let err = provider.resolve_api_key().await.unwrap_err();
+        assert!(matches!(err, AiError::ApiKeyProvider { .. }));
+        assert!(err.to_string().contains("vault sealed"));
+    }
+}
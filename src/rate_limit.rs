@@ -0,0 +1,264 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "network")]
+use reqwest::header::HeaderMap;
+
+/// Below this fraction of the limit, requests are spread over the rest of the window
+pub const LOW_BUDGET_FRACTION: f64 = 0.1;
+
+/// Rate limit budget reported by a provider's `x-ratelimit-*` response headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitState {
+    pub limit_requests: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    /// Time until the request budget is fully restored
+    pub reset_requests: Option<Duration>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    /// Time until the token budget is fully restored
+    pub reset_tokens: Option<Duration>,
+    /// `retry-after`, sent along with a 429
+    pub retry_after: Option<Duration>,
+    /// When the response carrying these headers arrived
+    pub observed_at: Instant,
+}
+
+impl RateLimitState {
+    /// Read the rate limit headers of a response; `None` if it has none
+    #[cfg(feature = "network")]
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let number = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok();
+        let duration = |name: &str| parse_reset(headers.get(name)?.to_str().ok()?);
+
+        let state = Self {
+            limit_requests: number("x-ratelimit-limit-requests"),
+            remaining_requests: number("x-ratelimit-remaining-requests"),
+            reset_requests: duration("x-ratelimit-reset-requests"),
+            limit_tokens: number("x-ratelimit-limit-tokens"),
+            remaining_tokens: number("x-ratelimit-remaining-tokens"),
+            reset_tokens: duration("x-ratelimit-reset-tokens"),
+            retry_after: number("retry-after").map(Duration::from_secs),
+            observed_at: Instant::now(),
+        };
+
+        let reported = state.remaining_requests.is_some()
+            || state.remaining_tokens.is_some()
+            || state.retry_after.is_some();
+        reported.then_some(state)
+    }
+
+    // The earliest a request may go out, and how far apart the following ones should be
+    fn pacing(&self) -> (Option<Instant>, Option<Duration>) {
+        let mut earliest = self.retry_after.map(|wait| self.observed_at + wait);
+        let mut spacing = None;
+
+        for (limit, remaining, reset) in [
+            (
+                self.limit_requests,
+                self.remaining_requests,
+                self.reset_requests,
+            ),
+            (self.limit_tokens, self.remaining_tokens, self.reset_tokens),
+        ] {
+            let (Some(remaining), Some(reset)) = (remaining, reset) else {
+                continue;
+            };
+            if remaining == 0 {
+                earliest = earliest.max(Some(self.observed_at + reset));
+            } else if is_low(remaining, limit) {
+                spacing = spacing.max(Some(reset / remaining.min(u32::MAX as u64) as u32));
+            }
+        }
+        (earliest, spacing)
+    }
+}
+
+fn is_low(remaining: u64, limit: Option<u64>) -> bool {
+    match limit {
+        Some(limit) if limit > 0 => (remaining as f64) < limit as f64 * LOW_BUDGET_FRACTION,
+        _ => false,
+    }
+}
+
+/// Parse reset times in the `1m30.5s` / `250ms` format Groq and OpenAI send
+pub fn parse_reset(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|&len| len > 0)?;
+        let (number, after) = rest.split_at(number_len);
+        let number: f64 = number.parse().ok()?;
+
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        total += number
+            * match unit {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                "ms" => 0.001,
+                _ => return None,
+            };
+        rest = after;
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+/// Paces requests to one provider using the rate limit headers it last sent.
+///
+/// Cloning shares the state, so every client built for a [`Provider`](crate::Provider)
+/// waits its turn together. When the budget is nearly used up, requests are spaced out
+/// so the remainder lasts until the window resets; when it is exhausted (or the provider
+/// sent `retry-after`), requests wait for the reset instead of collecting 429s.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<LimiterState>>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    last: Option<RateLimitState>,
+    next_slot: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The rate limit headers from the most recent response that had any
+    pub fn state(&self) -> Option<RateLimitState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).last
+    }
+
+    /// Record the rate limit headers of a response
+    #[cfg(feature = "network")]
+    pub fn observe(&self, headers: &HeaderMap) {
+        if let Some(state) = RateLimitState::from_headers(headers) {
+            let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            inner.last = Some(state);
+        }
+    }
+
+    /// Wait until the next request may be sent
+    pub async fn wait(&self) {
+        if let Some(slot) = self.reserve(Instant::now())
+            && slot > Instant::now()
+        {
+            tokio::time::sleep_until(slot.into()).await;
+        }
+    }
+
+    // Claim the next send slot at or after `now`; `None` when no pacing is needed
+    fn reserve(&self, now: Instant) -> Option<Instant> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (earliest, spacing) = inner.last.as_ref()?.pacing();
+        if earliest.is_none() && spacing.is_none() {
+            inner.next_slot = None;
+            return None;
+        }
+
+        let slot = [Some(now), earliest, inner.next_slot]
+            .into_iter()
+            .flatten()
+            .max()?;
+        inner.next_slot = spacing.map(|spacing| slot + spacing);
+        Some(slot)
+    }
+}
+
+#[cfg(all(test, feature = "network"))]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_reset() {
+        assert_eq!(parse_reset("7.66s"), Some(Duration::from_millis(7660)));
+        assert_eq!(parse_reset("2m59.5s"), Some(Duration::from_millis(179_500)));
+        assert_eq!(parse_reset("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_reset("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_reset("3"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_reset("soon"), None);
+        assert_eq!(parse_reset("5x"), None);
+    }
+
+    #[test]
+    fn test_state_from_groq_headers() {
+        let state = RateLimitState::from_headers(&headers(&[
+            ("x-ratelimit-limit-requests", "14400"),
+            ("x-ratelimit-remaining-requests", "14370"),
+            ("x-ratelimit-reset-requests", "2m59.56s"),
+            ("x-ratelimit-limit-tokens", "18000"),
+            ("x-ratelimit-remaining-tokens", "17997"),
+            ("x-ratelimit-reset-tokens", "7.66s"),
+        ]))
+        .unwrap();
+
+        assert_eq!(state.remaining_requests, Some(14370));
+        assert_eq!(state.limit_tokens, Some(18000));
+        assert_eq!(state.reset_tokens, Some(Duration::from_millis(7660)));
+        assert_eq!(state.retry_after, None);
+        assert!(RateLimitState::from_headers(&headers(&[("content-type", "json")])).is_none());
+    }
+
+    #[test]
+    fn test_plenty_of_budget_is_not_paced() {
+        let limiter = RateLimiter::new();
+        assert_eq!(limiter.reserve(Instant::now()), None);
+
+        limiter.observe(&headers(&[
+            ("x-ratelimit-limit-requests", "100"),
+            ("x-ratelimit-remaining-requests", "50"),
+            ("x-ratelimit-reset-requests", "10s"),
+        ]));
+        assert_eq!(limiter.reserve(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_low_budget_spreads_requests_over_the_window() {
+        let limiter = RateLimiter::new();
+        limiter.observe(&headers(&[
+            ("x-ratelimit-limit-tokens", "1000"),
+            ("x-ratelimit-remaining-tokens", "5"),
+            ("x-ratelimit-reset-tokens", "10s"),
+        ]));
+
+        let now = Instant::now();
+        let first = limiter.reserve(now).unwrap();
+        let second = limiter.reserve(now).unwrap();
+        assert_eq!(first, now);
+        assert_eq!(second - first, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_exhausted_budget_waits_for_reset() {
+        let limiter = RateLimiter::new();
+        limiter.observe(&headers(&[
+            ("x-ratelimit-limit-requests", "30"),
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-reset-requests", "3s"),
+            ("retry-after", "1"),
+        ]));
+
+        let state = limiter.state().unwrap();
+        let slot = limiter.reserve(state.observed_at).unwrap();
+        assert_eq!(slot - state.observed_at, Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,159 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+
+/// Every type `unwrap_or_ai!` can produce from an AI response implements this trait.
+///
+/// It is implemented automatically for any type meeting the bounds; use
+/// `#[derive(Recoverable)]` to have missing bounds reported on the type definition.
+///
+/// Only what recovery uses is required: deserializing the response and describing the
+/// type as a schema. Recovered values are moved out, never cloned, so `Clone` isn't
+/// needed, and a type that isn't `Send` simply makes the recovering future `!Send`.
+///
+/// `Box<T>`, `Rc<T>` and `Arc<T>` are recoverable whenever `T` is: the model is asked for
+/// a `T`, which is then wrapped.
+///
+/// ```
+/// use schemars::JsonSchema;
+/// use serde::Deserialize;
+/// use unwrap_or_ai::Recoverable;
+///
+/// #[derive(Debug, Deserialize, JsonSchema, Recoverable)]
+/// struct Settings {
+///     theme: String,
+///     notifications: bool,
+/// }
+///
+/// fn assert_recoverable<T: Recoverable>() {}
+/// assert_recoverable::<Settings>();
+/// ```
+///
+/// Leaving out one of the derives names the missing trait on the type:
+///
+/// ```compile_fail
+/// use serde::Deserialize;
+/// use unwrap_or_ai::Recoverable;
+///
+/// // error: `Settings` cannot be recovered by unwrap_or_ai: it does not implement `JsonSchema`
+/// #[derive(Debug, Deserialize, Recoverable)]
+/// struct Settings {
+///     theme: String,
+/// }
+/// ```
+///
+/// Recovery quality depends on documentation. `#[recoverable(warn_missing_docs)]` warns
+/// when neither the type nor any of its fields or variants has a doc comment:
+///
+/// ```compile_fail
+/// #![deny(deprecated)]
+/// use schemars::JsonSchema;
+/// use serde::Deserialize;
+/// use unwrap_or_ai::Recoverable;
+///
+/// // error: `Settings` has no doc comments; document the type or its fields so AI
+/// // recovery knows what the values mean
+/// #[derive(Debug, Deserialize, JsonSchema, Recoverable)]
+/// #[recoverable(warn_missing_docs)]
+/// struct Settings {
+///     theme: String,
+/// }
+/// ```
+///
+/// ```
+/// #![deny(deprecated)]
+/// use schemars::JsonSchema;
+/// use serde::Deserialize;
+/// use unwrap_or_ai::Recoverable;
+///
+/// #[derive(Debug, Deserialize, JsonSchema, Recoverable)]
+/// #[recoverable(warn_missing_docs)]
+/// struct Settings {
+///     /// Name of the color scheme, e.g. "dark" or "solarized"
+///     theme: String,
+/// }
+/// ```
+///
+/// Recovered values are deserialized with `DeserializeOwned`, so they can't borrow. A
+/// field such as `&str` is reported on the field, with owned types to use instead:
+///
+/// ```compile_fail
+/// use schemars::JsonSchema;
+/// use serde::Deserialize;
+/// use unwrap_or_ai::Recoverable;
+///
+/// // error: unwrap_or_ai can't recover a borrowed type: ... Use an owned type instead,
+/// // such as `String` for `&str` or `Vec<T>` for `&[T]`
+/// #[derive(Debug, Deserialize, JsonSchema, Recoverable)]
+/// struct Settings<'a> {
+///     theme: &'a str,
+/// }
+/// ```
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be recovered by unwrap_or_ai",
+    label = "`{Self}` is missing one of the traits required for AI recovery",
+    note = "recovered types need `Deserialize` and `JsonSchema`; add `#[derive(Recoverable)]` to `{Self}` to see which one is missing"
+)]
+pub trait Recoverable: DeserializeOwned + JsonSchema + 'static {}
+
+impl<T> Recoverable for T where T: DeserializeOwned + JsonSchema + 'static {}
+
+/// A type's Rust definition as written, doc comments included; implemented by
+/// `#[derive(Recoverable)]`
+pub trait TypeSource {
+    const SOURCE: &'static str;
+}
+
+static TYPE_SOURCES: LazyLock<RwLock<HashMap<TypeId, &'static str>>> =
+    LazyLock::new(Default::default);
+
+/// Show the model `T`'s Rust definition, next to its schema, whenever a `T` is recovered.
+///
+/// Field doc comments and names often say more about the intended values than the
+/// schema does. Costs prompt tokens for every recovery of `T`, so it is opt-in per type.
+///
+/// ```
+/// use schemars::JsonSchema;
+/// use serde::Deserialize;
+/// use unwrap_or_ai::Recoverable;
+///
+/// #[derive(Deserialize, JsonSchema, Recoverable)]
+/// struct Settings {
+///     /// Name of the color scheme, e.g. "dark" or "solarized"
+///     theme: String,
+/// }
+///
+/// unwrap_or_ai::recoverable::include_type_source::<Settings>();
+/// ```
+pub fn include_type_source<T: TypeSource + 'static>() {
+    TYPE_SOURCES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(TypeId::of::<T>(), T::SOURCE);
+}
+
+// The definition registered for `T` with `include_type_source`
+pub(crate) fn included_type_source<T: ?Sized + 'static>() -> Option<&'static str> {
+    TYPE_SOURCES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&TypeId::of::<T>())
+        .copied()
+}
+
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be recovered by unwrap_or_ai: it does not implement `Deserialize`",
+    note = "add `serde::Deserialize` to the derives of `{Self}`"
+)]
+pub trait RequiresDeserialize {}
+impl<T: DeserializeOwned> RequiresDeserialize for T {}
+
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be recovered by unwrap_or_ai: it does not implement `JsonSchema`",
+    note = "add `schemars::JsonSchema` to the derives of `{Self}`"
+)]
+pub trait RequiresJsonSchema {}
+impl<T: JsonSchema> RequiresJsonSchema for T {}
@@ -0,0 +1,184 @@
+/// Fix the almost-JSON some models produce so that `serde_json` accepts it.
+///
+/// Handles Markdown code fences around the answer, single-quoted strings, trailing
+/// commas, unquoted object keys and Python's `True`/`False`/`None`. Text that is already
+/// valid JSON comes back unchanged; anything else that is wrong is left for the parser
+/// to report.
+pub fn repair_json(text: &str) -> String {
+    let chars: Vec<char> = strip_code_fence(text.trim()).chars().collect();
+    let mut out = String::with_capacity(chars.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '"' | '\'' => i = copy_string(&chars, i, &mut out),
+            ',' if matches!(next_significant(&chars, i + 1), Some('}' | ']')) => i += 1,
+            c if c.is_alphabetic() || c == '_' => {
+                let end = chars[i..]
+                    .iter()
+                    .position(|c| !c.is_alphanumeric() && *c != '_')
+                    .map_or(chars.len(), |len| i + len);
+                let word: String = chars[i..end].iter().collect();
+                match word.as_str() {
+                    "True" => out.push_str("true"),
+                    "False" => out.push_str("false"),
+                    "None" => out.push_str("null"),
+                    "true" | "false" | "null" => out.push_str(&word),
+                    _ if next_significant(&chars, end) == Some(':') => {
+                        out.push('"');
+                        out.push_str(&word);
+                        out.push('"');
+                    }
+                    _ => out.push_str(&word),
+                }
+                i = end;
+            }
+            _ => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// The JSON in a model's answer, without the Markdown fence or prose around it.
+///
+/// Answers that are already JSON come back trimmed. Otherwise the first fenced block is
+/// taken, if there is one, and within it everything from the first `{` or `[` to the
+/// last matching `}` or `]`. `None` if there is no such span; the result isn't checked
+/// any further, so pass it to [`repair_json`] or the parser.
+///
+/// ```
+/// use unwrap_or_ai::repair::extract_json;
+///
+/// let answer = "Sure! Here is the user:\n```json\n{\"id\": 7}\n```\nAnything else?";
+/// assert_eq!(extract_json(answer), Some("{\"id\": 7}"));
+/// ```
+pub fn extract_json(content: &str) -> Option<&str> {
+    let content = content.trim();
+    if serde_json::from_str::<serde::de::IgnoredAny>(content).is_ok() {
+        return Some(content);
+    }
+
+    let text = fenced_block(content).unwrap_or(content);
+    let start = text.find(['{', '['])?;
+    let close = if text[start..].starts_with('{') {
+        '}'
+    } else {
+        ']'
+    };
+    let end = text.rfind(close).filter(|&end| end > start)?;
+    Some(&text[start..=end])
+}
+
+// The body of the first "```" block, which may have prose before and after it
+fn fenced_block(text: &str) -> Option<&str> {
+    let (_, fenced) = text.split_once("```")?;
+    let (_, body) = fenced.split_once('\n')?;
+    Some(body.split_once("```").map_or(body, |(body, _)| body).trim())
+}
+
+// Copy the string starting at `chars[start]` as a double-quoted JSON string, returning
+// the index after its closing quote
+fn copy_string(chars: &[char], start: usize, out: &mut String) -> usize {
+    let quote = chars[start];
+    out.push('"');
+
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                // `\'` is only valid inside our own single-quoted strings
+                if chars[i + 1] != '\'' {
+                    out.push('\\');
+                }
+                out.push(chars[i + 1]);
+                i += 2;
+                continue;
+            }
+            c if c == quote => break,
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    out.push('"');
+    i + 1
+}
+
+fn next_significant(chars: &[char], from: usize) -> Option<char> {
+    chars
+        .get(from..)?
+        .iter()
+        .copied()
+        .find(|c| !c.is_whitespace())
+}
+
+// "```json\n{...}\n```" → "{...}"
+fn strip_code_fence(text: &str) -> &str {
+    let Some(fenced) = text.strip_prefix("```") else {
+        return text;
+    };
+    let body = fenced.split_once('\n').map_or("", |(_, body)| body);
+    body.trim_end().strip_suffix("```").unwrap_or(body).trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn parse(text: &str) -> Value {
+        serde_json::from_str(&repair_json(text)).unwrap()
+    }
+
+    #[test]
+    fn test_common_mistakes_are_repaired() {
+        assert_eq!(
+            parse(r#"{"name": "Ada", "tags": ["a", "b",],}"#),
+            json!({ "name": "Ada", "tags": ["a", "b"] })
+        );
+        assert_eq!(
+            parse(r#"{'name': 'O\'Brien', 'quote': 'say "hi"'}"#),
+            json!({ "name": "O'Brien", "quote": "say \"hi\"" })
+        );
+        assert_eq!(
+            parse("{id: 7, active: True, manager: None}"),
+            json!({ "id": 7, "active": true, "manager": null })
+        );
+        assert_eq!(parse("```json\n{\"id\": 1}\n```"), json!({ "id": 1 }));
+    }
+
+    #[test]
+    fn test_json_is_extracted() {
+        let fenced = "```json\n{\"id\": 1, \"tags\": [\"a\"]}\n```";
+        assert_eq!(extract_json(fenced), Some("{\"id\": 1, \"tags\": [\"a\"]}"));
+
+        let wrapped = "The user is {\"id\": 2, \"name\": \"Ada\"}. Hope that helps!";
+        assert_eq!(
+            extract_json(wrapped),
+            Some("{\"id\": 2, \"name\": \"Ada\"}")
+        );
+        assert_eq!(
+            extract_json("Products:\n```\n[{\"id\": 3}]\n```\nDone."),
+            Some("[{\"id\": 3}]")
+        );
+
+        assert_eq!(extract_json("  \"Oslo\"\n"), Some("\"Oslo\""));
+        assert_eq!(extract_json("{\"id\": 4}"), Some("{\"id\": 4}"));
+        assert_eq!(extract_json("no JSON here"), None);
+        assert_eq!(extract_json("cut off: {\"id\": 5, \"na"), None);
+    }
+
+    #[test]
+    fn test_valid_json_is_unchanged() {
+        let text =
+            r#"{"note": "commas, } and 'quotes' True", "path": "C:\\temp", "n": [1, 2.5e3]}"#;
+        assert_eq!(repair_json(text), text);
+        // Beyond repair: still rejected by the parser
+        assert!(serde_json::from_str::<Value>(&repair_json("{\"id\": }")).is_err());
+    }
+}
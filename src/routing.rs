@@ -0,0 +1,47 @@
+//! Per-type model routing, for cost control without annotating every call site.
+//!
+//! ```
+//! # #[derive(serde::Deserialize, schemars::JsonSchema)]
+//! # struct User { name: String }
+//! # #[derive(serde::Deserialize, schemars::JsonSchema)]
+//! # struct WeatherData { temperature: f64 }
+//! use unwrap_or_ai::route_type;
+//!
+//! route_type::<User>("openai/gpt-oss-120b");
+//! route_type::<WeatherData>("llama-3.1-8b-instant");
+//! ```
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+static ROUTES: LazyLock<RwLock<HashMap<TypeId, String>>> = LazyLock::new(Default::default);
+
+/// Recover every `T` with `model` instead of each provider's own model.
+///
+/// A model picked for the function, e.g. with `#[unwrap_or_ai_func(model = "...")]` or
+/// [`Recovery::model`](crate::Recovery::model), still takes precedence. Routing `T` again
+/// replaces the previous model.
+pub fn route_type<T: ?Sized + 'static>(model: impl Into<String>) {
+    ROUTES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(TypeId::of::<T>(), model.into());
+}
+
+/// Stop routing `T`, so its recoveries use each provider's own model again
+pub fn unroute_type<T: ?Sized + 'static>() {
+    ROUTES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&TypeId::of::<T>());
+}
+
+/// The model `T` was routed to with [`route_type`], if any
+pub fn routed_model<T: ?Sized + 'static>() -> Option<String> {
+    ROUTES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&TypeId::of::<T>())
+        .cloned()
+}
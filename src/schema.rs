@@ -0,0 +1,882 @@
+use serde_json::{Map, Value, json};
+
+// Recursive types would otherwise expand forever
+const MAX_SKELETON_DEPTH: usize = 8;
+
+/// Build an example value shaped like `schema`, with placeholder leaves.
+///
+/// Strings become `"string"`, numbers `0`/`0.0`, booleans `false`, arrays hold a single
+/// example element and objects list every property. `$ref`s are resolved against the
+/// schema's `$defs`, and the first alternative of `anyOf`/`oneOf` that isn't `null` is used.
+pub fn skeleton_from_schema(schema: &Value) -> Value {
+    skeleton(schema, schema, 0)
+}
+
+/// An example value shaped like `T`, built from the schema recovery sends for it (see
+/// [`schema_for_type`](crate::groq_client::schema_for_type)) with placeholder leaves as
+/// in [`skeleton_from_schema`]. Recovery prompts include the same example when
+/// [`with_example_skeleton(true)`](crate::RecoveryConfig::with_example_skeleton) is set.
+///
+/// ```
+/// #[derive(schemars::JsonSchema)]
+/// struct Point {
+///     x: f64,
+///     label: Option<String>,
+/// }
+///
+/// assert_eq!(
+///     unwrap_or_ai::schema_skeleton::<Point>(),
+///     serde_json::json!({ "x": 0.0, "label": "string" })
+/// );
+/// ```
+pub fn schema_skeleton<T: schemars::JsonSchema + 'static>() -> Value {
+    match crate::groq_client::recovery_schema::<T>() {
+        Ok((_, schema)) => skeleton_from_schema(&schema),
+        Err(_) => Value::Null,
+    }
+}
+
+fn skeleton(node: &Value, root: &Value, depth: usize) -> Value {
+    if depth > MAX_SKELETON_DEPTH {
+        return Value::Null;
+    }
+
+    let Some(node) = node.as_object() else {
+        return Value::Null;
+    };
+
+    if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+        return match resolve_ref(root, reference) {
+            Some(target) => skeleton(target, root, depth + 1),
+            None => Value::Null,
+        };
+    }
+    if let Some(constant) = node.get("const") {
+        return constant.clone();
+    }
+    if let Some(first) = node
+        .get("enum")
+        .and_then(Value::as_array)
+        .and_then(|v| v.first())
+    {
+        return first.clone();
+    }
+    for key in ["anyOf", "oneOf", "allOf"] {
+        if let Some(variants) = node.get(key).and_then(Value::as_array) {
+            let variant = variants
+                .iter()
+                .find(|v| v.get("type").and_then(Value::as_str) != Some("null"))
+                .or(variants.first());
+            if let Some(variant) = variant {
+                return skeleton(variant, root, depth + 1);
+            }
+        }
+    }
+
+    match primary_type(node) {
+        Some("object") => object_skeleton(node, root, depth),
+        Some("array") => array_skeleton(node, root, depth),
+        Some("string") => json!("string"),
+        Some("integer") => json!(0),
+        Some("number") => json!(0.0),
+        Some("boolean") => json!(false),
+        _ if node.contains_key("properties") => object_skeleton(node, root, depth),
+        _ => Value::Null,
+    }
+}
+
+// `type` may be a list such as `["string", "null"]` for an Option
+fn primary_type(node: &Map<String, Value>) -> Option<&str> {
+    match node.get("type")? {
+        Value::String(t) => Some(t),
+        Value::Array(types) => types
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|t| *t != "null")
+            .or(Some("null")),
+        _ => None,
+    }
+}
+
+fn object_skeleton(node: &Map<String, Value>, root: &Value, depth: usize) -> Value {
+    let mut object = Map::new();
+
+    if let Some(properties) = node.get("properties").and_then(Value::as_object) {
+        for (name, property) in properties {
+            object.insert(name.clone(), skeleton(property, root, depth + 1));
+        }
+    } else if let Some(values) = node.get("additionalProperties").filter(|v| v.is_object()) {
+        object.insert("key".to_string(), skeleton(values, root, depth + 1));
+    }
+
+    Value::Object(object)
+}
+
+fn array_skeleton(node: &Map<String, Value>, root: &Value, depth: usize) -> Value {
+    if let Some(prefix) = node.get("prefixItems").and_then(Value::as_array) {
+        return Value::Array(
+            prefix
+                .iter()
+                .map(|item| skeleton(item, root, depth + 1))
+                .collect(),
+        );
+    }
+
+    match node.get("items") {
+        Some(items) => Value::Array(vec![skeleton(items, root, depth + 1)]),
+        None => Value::Array(Vec::new()),
+    }
+}
+
+/// Tokens budgeted per leaf value (key, value and punctuation) by [`estimate_max_tokens`]
+pub const TOKENS_PER_FIELD: u32 = 16;
+
+/// Elements assumed per array or map by [`estimate_max_tokens`]
+pub const ESTIMATED_COLLECTION_LEN: u32 = 8;
+
+/// Fixed overhead added by [`estimate_max_tokens`] for braces, whitespace and any
+/// preamble the model writes
+pub const BASE_TOKENS: u32 = 128;
+
+/// Upper bound of [`estimate_max_tokens`]
+pub const MAX_ESTIMATED_TOKENS: u32 = 8_192;
+
+/// Guess how many completion tokens an answer shaped like `schema` needs.
+///
+/// Every leaf value (string, number, boolean, enum) counts [`TOKENS_PER_FIELD`]; arrays
+/// and maps are assumed to hold [`ESTIMATED_COLLECTION_LEN`] elements, tuples count each
+/// position once, and `anyOf`/`oneOf` count their largest variant. The total plus
+/// [`BASE_TOKENS`] is capped at [`MAX_ESTIMATED_TOKENS`], so a `HealthCheck` gets a few
+/// hundred tokens while a `Vec<DeploymentInfo>` gets a few thousand.
+pub fn estimate_max_tokens(schema: &Value) -> u32 {
+    estimated_leaves(schema, schema, 0)
+        .saturating_mul(TOKENS_PER_FIELD)
+        .saturating_add(BASE_TOKENS)
+        .min(MAX_ESTIMATED_TOKENS)
+}
+
+fn estimated_leaves(node: &Value, root: &Value, depth: usize) -> u32 {
+    let Some(node) = node.as_object().filter(|_| depth <= MAX_SKELETON_DEPTH) else {
+        return 1;
+    };
+
+    if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+        return match resolve_ref(root, reference) {
+            Some(target) => estimated_leaves(target, root, depth + 1),
+            None => 1,
+        };
+    }
+    for key in ["anyOf", "oneOf", "allOf"] {
+        if let Some(variants) = node.get(key).and_then(Value::as_array) {
+            return variants
+                .iter()
+                .map(|variant| estimated_leaves(variant, root, depth + 1))
+                .max()
+                .unwrap_or(1);
+        }
+    }
+
+    let collection = |items: &Value| {
+        estimated_leaves(items, root, depth + 1).saturating_mul(ESTIMATED_COLLECTION_LEN)
+    };
+    if let Some(properties) = node.get("properties").and_then(Value::as_object) {
+        return properties
+            .values()
+            .map(|property| estimated_leaves(property, root, depth + 1))
+            .fold(0, u32::saturating_add)
+            .max(1);
+    }
+    if let Some(values) = node.get("additionalProperties").filter(|v| v.is_object()) {
+        return collection(values);
+    }
+    if let Some(prefix) = node.get("prefixItems").and_then(Value::as_array) {
+        return prefix
+            .iter()
+            .map(|item| estimated_leaves(item, root, depth + 1))
+            .fold(0, u32::saturating_add)
+            .max(1);
+    }
+    match node.get("items") {
+        Some(items) => collection(items),
+        None => 1,
+    }
+}
+
+/// Every property name declared anywhere in `schema`, including its `$defs`, once each
+pub fn property_names(schema: &Value) -> Vec<String> {
+    let mut names = Vec::new();
+    collect_property_names(schema, &mut names, 0);
+    names
+}
+
+fn collect_property_names(node: &Value, names: &mut Vec<String>, depth: usize) {
+    if depth > MAX_SKELETON_DEPTH {
+        return;
+    }
+    match node {
+        Value::Object(object) => {
+            if let Some(properties) = object.get("properties").and_then(Value::as_object) {
+                for name in properties.keys() {
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            }
+            for child in object.values() {
+                collect_property_names(child, names, depth + 1);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_property_names(item, names, depth + 1);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Paths of the properties `schema` declares that `value` leaves out, such as
+/// `address.city` or `items[0].sku`.
+///
+/// Optional properties count too: a missing one would deserialize as `None` or its
+/// `#[serde(default)]`. A property given as `null` is present. For `anyOf`/`oneOf`, the
+/// alternative `value` comes closest to is used.
+pub fn missing_properties(value: &Value, schema: &Value) -> Vec<String> {
+    let mut missing = Vec::new();
+    collect_missing(value, schema, schema, "", &mut missing, false, 0);
+    missing
+}
+
+/// Like [`missing_properties`], but only for properties listed as `required`: the ones
+/// `value` can't deserialize without, and what
+/// [fail-closed](crate::RecoveryConfig::with_fail_closed) recovery refuses to go without
+pub fn missing_required(value: &Value, schema: &Value) -> Vec<String> {
+    let mut missing = Vec::new();
+    collect_missing(value, schema, schema, "", &mut missing, true, 0);
+    missing
+}
+
+fn collect_missing(
+    value: &Value,
+    node: &Value,
+    root: &Value,
+    path: &str,
+    missing: &mut Vec<String>,
+    required_only: bool,
+    depth: usize,
+) {
+    let Some(node) = node.as_object().filter(|_| depth <= MAX_SKELETON_DEPTH) else {
+        return;
+    };
+    if value.is_null() {
+        return;
+    }
+
+    if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+        if let Some(target) = resolve_ref(root, reference) {
+            collect_missing(value, target, root, path, missing, required_only, depth + 1);
+        }
+        return;
+    }
+
+    let alternatives = node
+        .get("anyOf")
+        .or_else(|| node.get("oneOf"))
+        .and_then(Value::as_array);
+    if let Some(alternatives) = alternatives {
+        let closest = alternatives
+            .iter()
+            .map(|alternative| {
+                let mut found = Vec::new();
+                collect_missing(
+                    value,
+                    alternative,
+                    root,
+                    path,
+                    &mut found,
+                    required_only,
+                    depth + 1,
+                );
+                found
+            })
+            .min_by_key(Vec::len);
+        missing.extend(closest.unwrap_or_default());
+    }
+
+    match value {
+        Value::Object(object) => {
+            let Some(properties) = node.get("properties").and_then(Value::as_object) else {
+                return;
+            };
+            let required = node.get("required").and_then(Value::as_array);
+            let is_required = |key: &str| {
+                required.is_some_and(|required| required.iter().any(|name| name == key))
+            };
+            for (key, property) in properties {
+                let field_path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+                match object.get(key) {
+                    Some(field) => collect_missing(
+                        field,
+                        property,
+                        root,
+                        &field_path,
+                        missing,
+                        required_only,
+                        depth + 1,
+                    ),
+                    None if required_only && !is_required(key) => {}
+                    None => missing.push(field_path),
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = node.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    let item_path = format!("{}[{}]", path, i);
+                    collect_missing(
+                        item,
+                        item_schema,
+                        root,
+                        &item_path,
+                        missing,
+                        required_only,
+                        depth + 1,
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Repair object keys in a model's output that don't match `schema` exactly.
+///
+/// Models sometimes answer with the Rust field name (`created_at`) instead of the
+/// serde-renamed key (`createdAt`), or nest a `#[serde(flatten)]`ed struct under its own
+/// field name. Unknown keys that match a property once case, `_` and `-` are ignored are
+/// renamed, and unknown objects whose keys all belong to the parent are merged into it.
+/// Tuples answered as an object (`{"items": [...]}` or `{"0": ..., "1": ...}`) are turned
+/// back into arrays. Anything that already fits is left alone.
+pub fn conform_to_schema(value: Value, schema: &Value) -> Value {
+    conform(value, schema, schema, 0)
+}
+
+fn conform(value: Value, node: &Value, root: &Value, depth: usize) -> Value {
+    if depth > MAX_SKELETON_DEPTH {
+        return value;
+    }
+    let Some(node) = node.as_object() else {
+        return value;
+    };
+
+    if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+        return match resolve_ref(root, reference) {
+            Some(target) => conform(value, target, root, depth + 1),
+            None => value,
+        };
+    }
+
+    let value = match value {
+        Value::Object(object) if node.contains_key("prefixItems") => tuple_from_object(object),
+        other => other,
+    };
+
+    match value {
+        Value::Object(object) => match node.get("properties").and_then(Value::as_object) {
+            Some(properties) => Value::Object(conform_object(object, properties, root, depth)),
+            // A map: every value follows the same schema
+            None => match node.get("additionalProperties").filter(|v| v.is_object()) {
+                Some(values) => Value::Object(
+                    object
+                        .into_iter()
+                        .map(|(key, field)| (key, conform(field, values, root, depth + 1)))
+                        .collect(),
+                ),
+                None => Value::Object(object),
+            },
+        },
+        Value::Array(items) => match (node.get("prefixItems"), node.get("items")) {
+            // A tuple: each position has its own schema
+            (Some(Value::Array(positions)), _) => Value::Array(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, item)| match positions.get(i) {
+                        Some(position) => conform(item, position, root, depth + 1),
+                        None => item,
+                    })
+                    .collect(),
+            ),
+            (_, Some(item_schema)) => Value::Array(
+                items
+                    .into_iter()
+                    .map(|item| conform(item, item_schema, root, depth + 1))
+                    .collect(),
+            ),
+            _ => Value::Array(items),
+        },
+        other => other,
+    }
+}
+
+// JSON mode only allows an object at the top level, so a tuple tends to come back as
+// `{"items": [...]}` or `{"0": ..., "1": ...}`; turn either form back into the array
+fn tuple_from_object(object: Map<String, Value>) -> Value {
+    if object.len() == 1 && object.values().all(Value::is_array) {
+        return object
+            .into_iter()
+            .next()
+            .map(|(_, items)| items)
+            .unwrap_or_default();
+    }
+
+    let mut positional = Vec::with_capacity(object.len());
+    for (key, item) in &object {
+        match key.parse::<usize>() {
+            Ok(index) => positional.push((index, item.clone())),
+            Err(_) => return Value::Object(object),
+        }
+    }
+    positional.sort_by_key(|(index, _)| *index);
+    Value::Array(positional.into_iter().map(|(_, item)| item).collect())
+}
+
+/// Convert scalars the model quoted into the type `schema` asks for.
+///
+/// Lenient models answer `"99.99"` for an `f64` or `"true"` for a `bool`. Strings are
+/// parsed where the schema expects an integer, number or boolean; anything that doesn't
+/// parse cleanly is left for deserialization to reject.
+pub fn coerce_scalars(value: Value, schema: &Value) -> Value {
+    coerce(value, schema, schema, 0)
+}
+
+fn coerce(value: Value, node: &Value, root: &Value, depth: usize) -> Value {
+    let Some(node) = node.as_object().filter(|_| depth <= MAX_SKELETON_DEPTH) else {
+        return value;
+    };
+
+    if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+        return match resolve_ref(root, reference) {
+            Some(target) => coerce(value, target, root, depth + 1),
+            None => value,
+        };
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(variants) = node.get(key).and_then(Value::as_array) {
+            let variants = variants
+                .iter()
+                .filter(|v| v.get("type").and_then(Value::as_str) != Some("null"));
+            // The first variant that changes a quoted scalar wins; anything else follows
+            // the first non-null variant
+            if value.is_string() {
+                for variant in variants {
+                    let coerced = coerce(value.clone(), variant, root, depth + 1);
+                    if !coerced.is_string() {
+                        return coerced;
+                    }
+                }
+                return value;
+            }
+            return match variants.into_iter().next() {
+                Some(variant) => coerce(value, variant, root, depth + 1),
+                None => value,
+            };
+        }
+    }
+
+    match value {
+        Value::String(text) => coerce_string(text, primary_type(node)),
+        Value::Object(object) => {
+            let properties = node.get("properties").and_then(Value::as_object);
+            let values = node.get("additionalProperties").filter(|v| v.is_object());
+            Value::Object(
+                object
+                    .into_iter()
+                    .map(|(key, field)| {
+                        let field_schema = properties.and_then(|p| p.get(&key)).or(values);
+                        let field = match field_schema {
+                            Some(field_schema) => coerce(field, field_schema, root, depth + 1),
+                            None => field,
+                        };
+                        (key, field)
+                    })
+                    .collect(),
+            )
+        }
+        Value::Array(items) => {
+            let positions = node.get("prefixItems").and_then(Value::as_array);
+            let item_schema = node.get("items");
+            Value::Array(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .map(
+                        |(i, item)| match positions.and_then(|p| p.get(i)).or(item_schema) {
+                            Some(schema) => coerce(item, schema, root, depth + 1),
+                            None => item,
+                        },
+                    )
+                    .collect(),
+            )
+        }
+        other => other,
+    }
+}
+
+fn coerce_string(text: String, expected: Option<&str>) -> Value {
+    let trimmed = text.trim();
+    let coerced = match expected {
+        Some("integer") => trimmed
+            .parse::<i64>()
+            .map(Value::from)
+            .or_else(|_| trimmed.parse::<u64>().map(Value::from))
+            .ok(),
+        Some("number") => trimmed
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number),
+        Some("boolean") => match trimmed.to_ascii_lowercase().as_str() {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        _ => None,
+    };
+    coerced.unwrap_or(Value::String(text))
+}
+
+fn conform_object(
+    object: Map<String, Value>,
+    properties: &Map<String, Value>,
+    root: &Value,
+    depth: usize,
+) -> Map<String, Value> {
+    let mut conformed = Map::new();
+    let mut unknown = Vec::new();
+
+    for (key, field) in object {
+        if properties.contains_key(&key) {
+            conformed.insert(key, field);
+        } else {
+            unknown.push((key, field));
+        }
+    }
+
+    for (key, field) in unknown {
+        if let Some(property) = matching_property(properties, &key)
+            && !conformed.contains_key(property)
+        {
+            conformed.insert(property.clone(), field);
+            continue;
+        }
+
+        // A flattened struct returned nested under its Rust field name
+        match field {
+            Value::Object(nested)
+                if !nested.is_empty()
+                    && nested
+                        .keys()
+                        .all(|k| matching_property(properties, k).is_some()) =>
+            {
+                for (nested_key, nested_field) in nested {
+                    if let Some(property) = matching_property(properties, &nested_key) {
+                        conformed.entry(property.clone()).or_insert(nested_field);
+                    }
+                }
+            }
+            field => {
+                conformed.insert(key, field);
+            }
+        }
+    }
+
+    for (key, field) in conformed.iter_mut() {
+        if let Some(property) = properties.get(key) {
+            *field = conform(field.take(), property, root, depth + 1);
+        }
+    }
+
+    conformed
+}
+
+fn matching_property<'a>(properties: &'a Map<String, Value>, key: &str) -> Option<&'a String> {
+    if let Some((property, _)) = properties.get_key_value(key) {
+        return Some(property);
+    }
+    let key = normalize_key(key);
+    properties
+        .keys()
+        .find(|property| normalize_key(property) == key)
+}
+
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// The one JSON type every answer for `schema` has, e.g. `object` for a struct; `None`
+// for nullable or union types
+pub(crate) fn root_type(schema: &Value) -> Option<&str> {
+    let node = match schema.get("$ref").and_then(Value::as_str) {
+        Some(reference) => resolve_ref(schema, reference)?,
+        None => schema,
+    };
+    node.get("type")?.as_str()
+}
+
+pub(crate) fn resolve_ref<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Deserialize, schemars::JsonSchema)]
+    #[allow(dead_code)]
+    struct Address {
+        city: String,
+        zip: Option<u32>,
+    }
+
+    #[derive(serde::Deserialize, schemars::JsonSchema)]
+    #[allow(dead_code)]
+    struct Customer {
+        id: u32,
+        name: String,
+        balance: f64,
+        active: bool,
+        tags: Vec<String>,
+        address: Address,
+    }
+
+    #[test]
+    fn test_skeleton_of_nested_struct() {
+        let schema = serde_json::to_value(schemars::schema_for!(Customer)).unwrap();
+
+        assert_eq!(
+            skeleton_from_schema(&schema),
+            json!({
+                "id": 0,
+                "name": "string",
+                "balance": 0.0,
+                "active": false,
+                "tags": ["string"],
+                "address": { "city": "string", "zip": 0 }
+            })
+        );
+    }
+
+    #[test]
+    fn test_schema_skeleton_of_nested_struct() {
+        let skeleton = schema_skeleton::<Customer>();
+
+        let mut keys: Vec<_> = skeleton.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["active", "address", "balance", "id", "name", "tags"]);
+        assert_eq!(skeleton["address"], json!({ "city": "string", "zip": 0 }));
+        assert_eq!(schema_skeleton::<Vec<Address>>()[0]["city"], "string");
+    }
+
+    #[derive(Debug, serde::Deserialize, schemars::JsonSchema, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct AuditInfo {
+        created_at: String,
+        created_by: String,
+    }
+
+    #[derive(Debug, serde::Deserialize, schemars::JsonSchema, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct Invoice {
+        invoice_id: u32,
+        #[serde(rename = "total")]
+        total_cents: u64,
+        line_items: Vec<LineItem>,
+        #[serde(flatten)]
+        audit: AuditInfo,
+    }
+
+    #[derive(Debug, serde::Deserialize, schemars::JsonSchema, PartialEq)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    struct LineItem {
+        unit_price: u32,
+    }
+
+    #[test]
+    fn test_conform_repairs_rust_names_and_nested_flatten() {
+        let schema = serde_json::to_value(schemars::schema_for!(Invoice)).unwrap();
+        let from_model = json!({
+            "invoice_id": 7,
+            "total": 1250,
+            "line_items": [{ "unit_price": 1250 }],
+            "audit": { "created_at": "2024-01-01", "createdBy": "ada" }
+        });
+        assert!(serde_json::from_value::<Invoice>(from_model.clone()).is_err());
+
+        let invoice: Invoice =
+            serde_json::from_value(conform_to_schema(from_model, &schema)).unwrap();
+        assert_eq!(
+            invoice,
+            Invoice {
+                invoice_id: 7,
+                total_cents: 1250,
+                line_items: vec![LineItem { unit_price: 1250 }],
+                audit: AuditInfo {
+                    created_at: "2024-01-01".to_string(),
+                    created_by: "ada".to_string(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_properties_are_found() {
+        let schema = schemars::schema_for!(Vec<Customer>).to_value();
+        let answer = serde_json::json!([
+            {
+                "id": 1, "name": "Ada", "balance": 0.0, "active": true, "tags": [],
+                "address": { "city": "London", "zip": null }
+            },
+            {
+                "id": 2, "balance": 0.0, "active": true, "tags": [],
+                "address": { "city": "Paris" }
+            }
+        ]);
+
+        assert_eq!(
+            missing_properties(&answer, &schema),
+            ["[1].address.zip", "[1].name"]
+        );
+        assert!(
+            missing_properties(&answer[0], &schemars::schema_for!(Customer).to_value()).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_missing_required_skips_optional_properties() {
+        let schema = schemars::schema_for!(Vec<Customer>).to_value();
+        let answer = serde_json::json!([{
+            "id": 2, "balance": 0.0, "active": true, "tags": [],
+            "address": { "city": "Paris" }
+        }]);
+
+        assert_eq!(missing_required(&answer, &schema), ["[0].name"]);
+    }
+
+    #[test]
+    fn test_conform_leaves_matching_output_alone() {
+        let schema = serde_json::to_value(schemars::schema_for!(Invoice)).unwrap();
+        let from_model = json!({
+            "invoiceId": 7,
+            "total": 1250,
+            "lineItems": [{ "UNIT_PRICE": 1250 }],
+            "createdAt": "2024-01-01",
+            "createdBy": "ada"
+        });
+
+        assert_eq!(conform_to_schema(from_model.clone(), &schema), from_model);
+    }
+
+    #[derive(Debug, serde::Deserialize, schemars::JsonSchema, PartialEq)]
+    struct Listing {
+        price: f64,
+        stock: u32,
+        active: bool,
+        label: String,
+        discount: Option<f64>,
+        warehouse: Option<Address>,
+    }
+
+    #[test]
+    fn test_coerce_quoted_scalars() {
+        let schema = serde_json::to_value(schemars::schema_for!(Listing)).unwrap();
+        let from_model = json!({
+            "price": "99.99",
+            "stock": " 3 ",
+            "active": "TRUE",
+            "label": "42",
+            "discount": "0.5",
+            "warehouse": { "city": "Oslo", "zip": "150" }
+        });
+
+        let listing: Listing = serde_json::from_value(coerce_scalars(from_model, &schema)).unwrap();
+        assert_eq!(
+            listing,
+            Listing {
+                price: 99.99,
+                stock: 3,
+                active: true,
+                label: "42".to_string(),
+                discount: Some(0.5),
+                warehouse: Some(Address {
+                    city: "Oslo".to_string(),
+                    zip: Some(150),
+                }),
+            }
+        );
+
+        // Unparseable values are left alone
+        let unparseable = json!({ "price": "cheap" });
+        assert_eq!(coerce_scalars(unparseable.clone(), &schema), unparseable);
+    }
+
+    #[test]
+    fn test_max_tokens_grow_with_the_shape() {
+        let customer = serde_json::to_value(schemars::schema_for!(Customer)).unwrap();
+        // id, name, balance, active, 8 tags and the address' city and zip
+        assert_eq!(
+            estimate_max_tokens(&customer),
+            BASE_TOKENS + 14 * TOKENS_PER_FIELD
+        );
+
+        let many = serde_json::to_value(schemars::schema_for!(Vec<Customer>)).unwrap();
+        assert_eq!(
+            estimate_max_tokens(&many),
+            (BASE_TOKENS + 14 * 8 * TOKENS_PER_FIELD).min(MAX_ESTIMATED_TOKENS)
+        );
+
+        let nested = serde_json::to_value(schemars::schema_for!(Vec<Vec<Customer>>)).unwrap();
+        assert_eq!(estimate_max_tokens(&nested), MAX_ESTIMATED_TOKENS);
+    }
+
+    #[test]
+    fn test_conform_tuples_by_position() {
+        let schema = serde_json::to_value(schemars::schema_for!((LineItem, AuditInfo))).unwrap();
+        let expected = (
+            LineItem { unit_price: 5 },
+            AuditInfo {
+                created_at: "2024-01-01".to_string(),
+                created_by: "ada".to_string(),
+            },
+        );
+
+        let as_array = json!([
+            { "unit_price": 5 },
+            { "created_at": "2024-01-01", "created_by": "ada" }
+        ]);
+        let conformed = conform_to_schema(as_array.clone(), &schema);
+        assert_eq!(
+            serde_json::from_value::<(LineItem, AuditInfo)>(conformed).unwrap(),
+            expected
+        );
+
+        // JSON mode answers with an object
+        for wrapped in [
+            json!({ "items": as_array.clone() }),
+            json!({ "1": as_array[1].clone(), "0": as_array[0].clone() }),
+        ] {
+            let conformed = conform_to_schema(wrapped, &schema);
+            assert_eq!(
+                serde_json::from_value::<(LineItem, AuditInfo)>(conformed).unwrap(),
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Percentiles are computed over at most this many of the most recent recoveries
+pub const MAX_LATENCY_SAMPLES: usize = 10_000;
+
+/// Aggregate latency of the recoveries made by this process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecoveryStats {
+    /// Recoveries attempted, successful or not
+    pub count: u64,
+    /// Recoveries that ended in an error
+    pub failures: u64,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+    /// `total_tokens` reported by providers for every response, including answers that
+    /// were rejected or asked for again; this is what
+    /// [`RecoveryConfig::with_token_budget`](crate::RecoveryConfig::with_token_budget)
+    /// is checked against
+    pub total_tokens: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LatencyTracker {
+    inner: Mutex<TrackerState>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    count: u64,
+    failures: u64,
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyTracker {
+    pub(crate) const fn new() -> Self {
+        Self {
+            inner: Mutex::new(TrackerState {
+                count: 0,
+                failures: 0,
+                samples: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn record(&self, latency: Duration, succeeded: bool) {
+        let mut state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        state.count += 1;
+        if !succeeded {
+            state.failures += 1;
+        }
+        if state.samples.len() == MAX_LATENCY_SAMPLES {
+            state.samples.pop_front();
+        }
+        state.samples.push_back(latency);
+    }
+
+    pub(crate) fn snapshot(&self) -> RecoveryStats {
+        let state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut sorted: Vec<Duration> = state.samples.iter().copied().collect();
+        sorted.sort();
+
+        let mean = if sorted.is_empty() {
+            Duration::ZERO
+        } else {
+            sorted.iter().sum::<Duration>() / sorted.len() as u32
+        };
+
+        RecoveryStats {
+            count: state.count,
+            failures: state.failures,
+            mean,
+            p50: percentile(&sorted, 50),
+            p95: percentile(&sorted, 95),
+            max: sorted.last().copied().unwrap_or_default(),
+            total_tokens: 0,
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.inner.lock().unwrap_or_else(|e| e.into_inner()) = TrackerState::default();
+    }
+}
+
+// Nearest-rank percentile over already sorted samples
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TokenCounter {
+    spent: AtomicU64,
+}
+
+impl TokenCounter {
+    pub(crate) const fn new() -> Self {
+        Self {
+            spent: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn spent(&self) -> u64 {
+        self.spent.load(Ordering::Relaxed)
+    }
+
+    // Whether these tokens are the ones that used up `budget`
+    pub(crate) fn add(&self, tokens: u64, budget: Option<u64>) -> bool {
+        let before = self.spent.fetch_add(tokens, Ordering::Relaxed);
+        budget.is_some_and(|budget| before < budget && before.saturating_add(tokens) >= budget)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.spent.store(0, Ordering::Relaxed);
+    }
+}
+
+pub(crate) static LATENCY: LatencyTracker = LatencyTracker::new();
+
+pub(crate) static TOKENS: TokenCounter = TokenCounter::new();
+
+/// Latency and token statistics for every `call_ai_for_type` made so far
+pub fn recovery_stats() -> RecoveryStats {
+    RecoveryStats {
+        total_tokens: TOKENS.spent(),
+        ..LATENCY.snapshot()
+    }
+}
+
+/// Forget all recorded recoveries, which also restores an exhausted token budget
+pub fn reset_recovery_stats() {
+    LATENCY.reset();
+    TOKENS.reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let tracker = LatencyTracker::new();
+        for ms in 1..=100 {
+            tracker.record(Duration::from_millis(ms), ms % 10 != 0);
+        }
+
+        let stats = tracker.snapshot();
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.failures, 10);
+        assert_eq!(stats.p50, Duration::from_millis(50));
+        assert_eq!(stats.p95, Duration::from_millis(95));
+        assert_eq!(stats.max, Duration::from_millis(100));
+        assert_eq!(stats.mean, Duration::from_micros(50_500));
+    }
+
+    #[test]
+    fn test_token_budget_is_crossed_once() {
+        let tokens = TokenCounter::new();
+        assert!(!tokens.add(60, Some(100)));
+        assert!(tokens.add(60, Some(100)));
+        assert!(!tokens.add(60, Some(100)));
+        assert!(!tokens.add(60, None));
+        assert_eq!(tokens.spent(), 240);
+    }
+
+    #[test]
+    fn test_empty_tracker_reports_zero() {
+        assert_eq!(LatencyTracker::new().snapshot(), RecoveryStats::default());
+    }
+}
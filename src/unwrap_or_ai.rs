@@ -1,128 +1,1239 @@
-use crate::groq_client::{GroqClient, models};
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::cache::RecoveryCache;
+use crate::capabilities::model_capabilities;
+use crate::config::{self, CacheKeyStrategy, MaxTokens};
+use crate::error::{AiError, AiRecoveryError};
+use crate::events::{self, RecoveryEvent};
+use crate::groq_client::{GroqClient, GroqUsage, StructuredResponse, recovery_schema};
+use crate::plausibility;
+use crate::prompt;
+use crate::provider::Provider;
+use crate::recover::{ErrorContext, RecoveryContext, recover, recover_optional};
+use crate::recoverable::Recoverable;
+use crate::routing;
+use crate::schema;
+use crate::stats;
 
 // Helper trait to extract the inner type and handle AI recovery
 #[allow(async_fn_in_trait)]
 pub trait UnwrapOrAi<T> {
-    async fn unwrap_or_ai_impl(self, prompt: String) -> T;
+    async fn unwrap_or_ai_impl(self, context: RecoveryContext) -> T;
+
+    // Evaluates `fallback` instead of panicking when the AI call fails
+    async fn unwrap_or_ai_or_else<F>(self, context: RecoveryContext, fallback: F) -> T
+    where
+        F: FnOnce() -> T;
 }
 
 impl<T, E> UnwrapOrAi<T> for Result<T, E>
 where
-    T: serde::de::DeserializeOwned + schemars::JsonSchema + Unpin + Clone + Send + Sync + 'static,
+    T: Recoverable,
 {
-    async fn unwrap_or_ai_impl(self, prompt: String) -> T {
+    async fn unwrap_or_ai_impl(self, context: RecoveryContext) -> T {
         match self {
-            Ok(val) => val,
-            Err(_) => {
-                println!("Result error detected, calling AI for recovery...");
+            Ok(val) if !config::force_recovery() => val,
+            _ => {
+                log::debug!("Result error detected, calling AI for recovery...");
                 // Call AI for recovery
-                match call_ai_for_type::<T>(prompt).await {
+                match recover::<T>(context).await {
                     Ok(ai_result) => ai_result,
-                    Err(ai_error) => {
-                        panic!("AI recovery failed: {}", ai_error);
+                    Err(recovery_error) => {
+                        panic!("{}", recovery_error);
                     }
                 }
             }
         }
     }
+
+    async fn unwrap_or_ai_or_else<F>(self, context: RecoveryContext, fallback: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        match self {
+            Ok(val) if !config::force_recovery() => val,
+            _ => {
+                log::debug!("Result error detected, calling AI for recovery...");
+                recover_or_fallback(context, fallback).await
+            }
+        }
+    }
 }
 
 impl<T> UnwrapOrAi<T> for Option<T>
 where
-    T: serde::de::DeserializeOwned + schemars::JsonSchema + Unpin + Clone + Send + Sync + 'static,
+    T: Recoverable,
 {
-    async fn unwrap_or_ai_impl(self, prompt: String) -> T {
+    async fn unwrap_or_ai_impl(self, context: RecoveryContext) -> T {
         match self {
-            Some(val) => val,
-            None => {
-                println!("Option is None, calling AI for recovery...");
+            Some(val) if !config::force_recovery() => val,
+            _ => {
+                log::debug!("Option is None, calling AI for recovery...");
                 // Call AI for recovery
-                match call_ai_for_type::<T>(prompt).await {
+                match recover::<T>(context).await {
                     Ok(ai_result) => {
-                        println!("AI recovery successful!");
+                        log::debug!("AI recovery successful!");
                         ai_result
                     }
-                    Err(ai_error) => {
-                        panic!("AI recovery failed: {}", ai_error);
+                    Err(recovery_error) => {
+                        panic!("{}", recovery_error);
                     }
                 }
             }
         }
     }
+
+    async fn unwrap_or_ai_or_else<F>(self, context: RecoveryContext, fallback: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        match self {
+            Some(val) if !config::force_recovery() => val,
+            _ => {
+                log::debug!("Option is None, calling AI for recovery...");
+                recover_or_fallback(context, fallback).await
+            }
+        }
+    }
+}
+
+async fn recover_or_fallback<T, F>(context: RecoveryContext, fallback: F) -> T
+where
+    T: Recoverable,
+    F: FnOnce() -> T,
+{
+    match recover::<T>(context).await {
+        Ok(ai_result) => ai_result,
+        Err(recovery_error) => {
+            log::debug!("{}, using fallback value", recovery_error);
+            fallback()
+        }
+    }
+}
+
+// Like UnwrapOrAi, but a failed recovery is returned instead of panicking
+#[allow(async_fn_in_trait)]
+pub trait TryUnwrapOrAi<T> {
+    async fn try_unwrap_or_ai_impl(self, context: RecoveryContext) -> Result<T, AiRecoveryError>;
+}
+
+// Like UnwrapOrAi, no bound on `E`: the macros fill in the error's text when it has one
+impl<T, E> TryUnwrapOrAi<T> for Result<T, E>
+where
+    T: Recoverable,
+{
+    async fn try_unwrap_or_ai_impl(self, context: RecoveryContext) -> Result<T, AiRecoveryError> {
+        match self {
+            Ok(val) if !config::force_recovery() => Ok(val),
+            _ => {
+                log::debug!("Result error detected, calling AI for recovery...");
+                recover::<T>(context).await
+            }
+        }
+    }
+}
+
+impl<T> TryUnwrapOrAi<T> for Option<T>
+where
+    T: Recoverable,
+{
+    async fn try_unwrap_or_ai_impl(self, context: RecoveryContext) -> Result<T, AiRecoveryError> {
+        match self {
+            Some(val) if !config::force_recovery() => Ok(val),
+            _ => {
+                log::debug!("Option is None, calling AI for recovery...");
+                let ai_result = recover::<T>(context).await?;
+                log::debug!("AI recovery successful!");
+                Ok(ai_result)
+            }
+        }
+    }
+}
+
+// Like TryUnwrapOrAi, but only errors `should_recover` accepts are recovered; the others
+// come back untouched in the inner Result
+#[allow(async_fn_in_trait)]
+pub trait TryUnwrapOrAiIf<T, E> {
+    async fn try_unwrap_or_ai_if<P>(
+        self,
+        context: RecoveryContext,
+        should_recover: P,
+    ) -> Result<Result<T, E>, AiRecoveryError>
+    where
+        P: FnOnce(&E) -> bool;
+}
+
+impl<T, E> TryUnwrapOrAiIf<T, E> for Result<T, E>
+where
+    T: Recoverable,
+{
+    async fn try_unwrap_or_ai_if<P>(
+        self,
+        context: RecoveryContext,
+        should_recover: P,
+    ) -> Result<Result<T, E>, AiRecoveryError>
+    where
+        P: FnOnce(&E) -> bool,
+    {
+        match self {
+            Ok(val) if !config::force_recovery() => Ok(Ok(val)),
+            Err(error) if !should_recover(&error) => Ok(Err(error)),
+            _ => {
+                log::debug!("Result error detected, calling AI for recovery...");
+                recover::<T>(context).await.map(Ok)
+            }
+        }
+    }
+}
+
+// Recovers a None into Some only when the AI finds a plausible value
+#[allow(async_fn_in_trait)]
+pub trait RecoverOptionWithAi<T> {
+    async fn recover_option_impl(
+        self,
+        context: RecoveryContext,
+    ) -> Result<Option<T>, AiRecoveryError>;
+}
+
+impl<T> RecoverOptionWithAi<T> for Option<T>
+where
+    T: Recoverable,
+{
+    async fn recover_option_impl(
+        self,
+        context: RecoveryContext,
+    ) -> Result<Option<T>, AiRecoveryError> {
+        match self {
+            Some(val) if !config::force_recovery() => Ok(Some(val)),
+            _ => {
+                log::debug!("Option is None, asking AI whether a value exists...");
+                recover_optional::<T>(context).await
+            }
+        }
+    }
+}
+
+// Autoref specialization, so the macros can describe an `Err` whose type implements
+// Display without requiring it: `(&&ErrorText(&result)).error_text()` picks the
+// Display impl when it applies and falls back to `None` otherwise.
+#[doc(hidden)]
+pub struct ErrorText<'a, R>(pub &'a R);
+
+#[doc(hidden)]
+pub trait DisplayErrorText {
+    fn error_text(&self) -> Option<String>;
+}
+
+impl<T, E: std::fmt::Display> DisplayErrorText for &ErrorText<'_, Result<T, E>> {
+    fn error_text(&self) -> Option<String> {
+        self.0.as_ref().err().map(ToString::to_string)
+    }
 }
 
+#[doc(hidden)]
+pub trait NoErrorText {
+    fn error_text(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<R> NoErrorText for ErrorText<'_, R> {}
+
+// The same for structured details: `(&&ErrorText(&result)).error_context()` uses the
+// error's `ErrorContext` impl when it has one
+#[doc(hidden)]
+pub trait StructuredErrorContext {
+    fn error_context(&self) -> Option<serde_json::Value>;
+}
+
+impl<T, E: ErrorContext> StructuredErrorContext for &ErrorText<'_, Result<T, E>> {
+    fn error_context(&self) -> Option<serde_json::Value> {
+        self.0.as_ref().err().map(ErrorContext::error_context)
+    }
+}
+
+#[doc(hidden)]
+pub trait NoErrorContext {
+    fn error_context(&self) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+impl<R> NoErrorContext for ErrorText<'_, R> {}
+
+const SYSTEM_PROMPT: &str = "You are an AI error recovery assistant. When given an error message and program context, your task is to infer the most likely intended response or output. Do not explain the error—directly provide the corrected or plausible output as if the error had not occurred.";
+
 // Helper function to call AI and deserialize to specific type T
-pub async fn call_ai_for_type<T>(prompt: String) -> Result<T, Box<dyn std::error::Error>>
+// Every call's latency is recorded for `stats::recovery_stats`.
+pub async fn call_ai_for_type<T>(prompt: String) -> Result<T, AiError>
+where
+    T: Recoverable,
+{
+    call_ai_for_json::<T>(prompt, CallOptions::default())
+        .await
+        .map(|recovered| recovered.value)
+}
+
+/// Like [`call_ai_for_type`], but gives up as soon as `cancel` is triggered.
+///
+/// The in-flight HTTP request is dropped, which aborts it, and [`AiError::Cancelled`]
+/// is returned. Useful to stop paying for recoveries whose client has disconnected.
+pub async fn call_ai_for_type_with_cancel<T>(
+    prompt: String,
+    cancel: &CancellationToken,
+) -> Result<T, AiError>
+where
+    T: Recoverable,
+{
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(AiError::Cancelled),
+        result = call_ai_for_type::<T>(prompt) => result,
+    }
+}
+
+/// Like [`call_ai_for_type`], also returning the JSON text the model produced.
+///
+/// The text is kept verbatim, so it may hold fields that `T` drops. When the value came
+/// from the recovery cache, the cached JSON is returned instead.
+pub async fn call_ai_for_type_raw<T>(prompt: String) -> Result<(T, String), AiError>
 where
-    T: serde::de::DeserializeOwned + schemars::JsonSchema + Unpin + Clone + Send + Sync + 'static,
+    T: Recoverable,
 {
-    let api_key = std::env::var("GROQ_API").map_err(|_| "GROQ_API environment variable not set")?;
+    call_ai_for_json::<T>(prompt, CallOptions::default())
+        .await
+        .map(|recovered| (recovered.value, recovered.raw))
+}
+
+// Per-call settings layered over the current config
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CallOptions<'a> {
+    // Only used to label recovery events
+    pub(crate) fn_name: Option<&'a str>,
+    // Replaces the model of every provider, e.g. from #[unwrap_or_ai_func(model = "...")]
+    pub(crate) model: Option<&'a str>,
+    pub(crate) temperature: Option<f32>,
+    // Replaces SYSTEM_PROMPT
+    pub(crate) system_prompt: Option<&'a str>,
+    // Versions cache entries and is reported in events
+    pub(crate) source_hash: Option<u64>,
+    // Like RecoveryConfig::with_fail_closed, for this call only
+    pub(crate) fail_closed: bool,
+    // The error that triggered recovery, for CacheKeyStrategy::ErrorMessage
+    pub(crate) error: Option<&'a (dyn std::error::Error + Send + Sync)>,
+    pub(crate) cache: CacheUse,
+}
 
-    // Create Groq client using our direct HTTP client
-    let groq = GroqClient::new(api_key);
+// How a call uses the configured cache. Callers that check answers themselves store
+// them with `remember` once accepted, so a rejected answer is never served again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum CacheUse {
+    // Serve hits and store new answers
+    #[default]
+    ReadWrite,
+    // Serve hits, but leave storing new answers to the caller
+    ReadOnly,
+    // Always ask a provider, leaving storing to the caller
+    Bypass,
+}
 
-    let ai_response: T = groq.chat_completion_typed(
-        models::KIMI_K2, // Use a model that supports structured output
-        vec![
-            ("system", "You are an AI error recovery assistant. When given an error message and program context, your task is to infer the most likely intended response or output. Do not explain the error—directly provide the corrected or plausible output as if the error had not occurred."),
-            ("user", &prompt)
-        ]
-    ).await?;
+// A recovered value together with the JSON it was deserialized from
+pub(crate) struct Recovered<T> {
+    pub(crate) value: T,
+    pub(crate) json: serde_json::Value,
+    // The model's content before any key repair
+    pub(crate) raw: String,
+    // `None` when the value was served from the cache, else summed over every response,
+    // including incomplete answers that were asked for again
+    usage: Option<GroqUsage>,
+    // Shared by every provider attempt of the recovery, as in its events
+    pub(crate) request_id: String,
+    // Where a new answer goes once accepted, for calls that don't store it themselves
+    cache_key: Option<String>,
+}
 
-    Ok(ai_response)
+// Store an answer the caller has accepted, for calls made with `CacheUse::ReadOnly` or
+// `CacheUse::Bypass`
+pub(crate) fn remember<T>(recovered: &Recovered<T>) {
+    if let (Some(key), Some(cache)) = (&recovered.cache_key, &config::current().cache) {
+        cache.insert(key.clone(), recovered.json.clone());
+    }
 }
 
+pub(crate) async fn call_ai_for_json<T>(
+    prompt: String,
+    options: CallOptions<'_>,
+) -> Result<Recovered<T>, AiError>
+where
+    T: Recoverable,
+{
+    let started = Instant::now();
+    // One ID per recovery, shared by every provider attempt, so all of them can be traced
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let call = call_providers_for_type::<T>(prompt, &request_id, options);
+    let result = match config::current().timeout {
+        Some(timeout) => tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or(Err(AiError::Timeout(timeout))),
+        None => call.await,
+    };
+    stats::LATENCY.record(started.elapsed(), result.is_ok());
+    result
+}
+
+// Providers from the current config are tried in order; connection failures and
+// server errors move on to the next one, any other error is returned immediately.
+async fn call_providers_for_type<T>(
+    prompt: String,
+    request_id: &str,
+    options: CallOptions<'_>,
+) -> Result<Recovered<T>, AiError>
+where
+    T: Recoverable,
+{
+    // There is nothing to fabricate for `()`: recovering it just means pretending the
+    // call succeeded
+    if TypeId::of::<T>() == TypeId::of::<()>() {
+        log::warn!(
+            "AI recovery suppressed a failure of {} without asking a model, since it returns `()`",
+            options.fn_name.unwrap_or("an expression")
+        );
+        return Ok(Recovered {
+            value: serde_json::from_value(serde_json::Value::Null)?,
+            json: serde_json::Value::Null,
+            raw: "null".to_string(),
+            usage: None,
+            request_id: request_id.to_string(),
+            cache_key: None,
+        });
+    }
+
+    if cfg!(any(feature = "no-ai", not(feature = "network"))) || config::current().ai_disabled {
+        return Err(AiError::Disabled);
+    }
+
+    let config = config::current();
+    if let Some(budget) = config.token_budget
+        && stats::TOKENS.spent() >= budget
+    {
+        return Err(AiError::TokenBudgetExhausted { budget });
+    }
+    let (schema_name, schema) = recovery_schema::<T>()?;
+    #[cfg(feature = "chrono")]
+    let schema = crate::dates::with_format_hints(schema);
+
+    let mut prompt = prompt::with_target_type::<T>(&prompt);
+    prompt = prompt::with_type_source::<T>(&prompt);
+    if config.example_skeleton {
+        prompt = prompt::with_example_skeleton(&prompt, &schema);
+    }
+    if config.unit_hints {
+        prompt = prompt::with_unit_hints(&prompt, &schema);
+    }
+    if let Some(prefix) = &config.prompt_prefix {
+        prompt = format!("{}\n\n{}", prefix, prompt);
+    }
+    if let Some(suffix) = &config.prompt_suffix {
+        prompt = format!("{}\n\n{}", prompt, suffix);
+    }
+    for hook in &config.prompt_hooks {
+        prompt = hook(prompt);
+    }
+
+    let mut failures = Vec::new();
+
+    let fn_name = options.fn_name;
+    let routed_model = routing::routed_model::<T>();
+    let model = options.model.or(routed_model.as_deref());
+
+    for provider in config.providers() {
+        let provider = match model {
+            Some(model) => Cow::Owned(provider.clone().with_model(model)),
+            None => Cow::Borrowed(provider),
+        };
+        let started = Instant::now();
+        events::emit(RecoveryEvent::Started {
+            request_id: request_id.to_string(),
+            fn_name: fn_name.map(str::to_string),
+            source_hash: options.source_hash,
+            provider: provider.name().to_string(),
+            model: provider.model().to_string(),
+        });
+
+        let attempt = call_provider_for_type::<T>(
+            &provider,
+            &prompt,
+            &schema_name,
+            &schema,
+            config.cache.as_deref(),
+            request_id,
+            options,
+        );
+        let attempt = attempt.await;
+
+        events::emit(match &attempt {
+            Ok(attempt) => RecoveryEvent::Finished {
+                request_id: request_id.to_string(),
+                fn_name: fn_name.map(str::to_string),
+                provider: provider.name().to_string(),
+                model: provider.model().to_string(),
+                latency: started.elapsed(),
+                usage: attempt.usage,
+            },
+            Err(ai_error) => RecoveryEvent::Failed {
+                request_id: request_id.to_string(),
+                fn_name: fn_name.map(str::to_string),
+                provider: provider.name().to_string(),
+                model: provider.model().to_string(),
+                latency: started.elapsed(),
+                error: ai_error.to_string(),
+            },
+        });
+
+        match attempt {
+            Ok(attempt) => return Ok(attempt),
+            Err(ai_error) if ai_error.should_try_next_provider() => {
+                failures.push((provider.name().to_string(), ai_error));
+            }
+            Err(ai_error) => return Err(ai_error),
+        }
+    }
+
+    // With a single provider its own error is more useful than a one-entry aggregate
+    match failures.len() {
+        0 => Err(AiError::NoProviders),
+        1 => Err(failures.remove(0).1),
+        _ => Err(AiError::AllProvidersFailed(failures)),
+    }
+}
+
+async fn call_provider_for_type<T>(
+    provider: &Provider,
+    prompt: &str,
+    schema_name: &str,
+    schema: &serde_json::Value,
+    cache: Option<&RecoveryCache>,
+    request_id: &str,
+    options: CallOptions<'_>,
+) -> Result<Recovered<T>, AiError>
+where
+    T: Recoverable,
+{
+    let type_name = std::any::type_name::<T>();
+    let config = config::current();
+    // Answers asked for with another system prompt or temperature are different answers
+    let keyed_prompt = match (options.system_prompt, options.temperature) {
+        (None, None) => Cow::Borrowed(prompt),
+        (system_prompt, temperature) => Cow::Owned(format!(
+            "{}\n\nSystem prompt: {}\nTemperature: {:?}",
+            prompt,
+            system_prompt.unwrap_or(SYSTEM_PROMPT),
+            temperature
+        )),
+    };
+    let cache_key = match (config.cache_key, options.error, options.source_hash) {
+        (CacheKeyStrategy::ErrorMessage, Some(error), _) => {
+            RecoveryCache::error_key(provider.model(), type_name, &error.to_string())
+        }
+        (_, _, Some(source_hash)) => {
+            RecoveryCache::versioned_key(provider.model(), type_name, &keyed_prompt, source_hash)
+        }
+        (_, _, None) => RecoveryCache::key(provider.model(), type_name, &keyed_prompt),
+    };
+
+    let fail_closed = options.fail_closed || config.fail_closed;
+
+    // An entry that no longer fits T (the type changed since it was stored) is a miss
+    if let Some(cached) = cache
+        .filter(|_| options.cache != CacheUse::Bypass)
+        .and_then(|cache| cache.get(&cache_key))
+        && (!fail_closed || schema::missing_required(&cached, schema).is_empty())
+        && plausibility::implausible_fields::<T>(&cached).is_empty()
+        && let Ok(ai_result) = serde_json::from_value(cached.clone())
+    {
+        return Ok(Recovered {
+            value: ai_result,
+            raw: cached.to_string(),
+            json: cached,
+            usage: None,
+            request_id: request_id.to_string(),
+            cache_key: None,
+        });
+    }
+
+    let mut client = provider
+        .client()
+        .await?
+        .with_header("X-Request-Id", request_id);
+    let max_tokens = match config.max_tokens {
+        MaxTokens::ProviderDefault => None,
+        MaxTokens::Estimated => Some(schema::estimate_max_tokens(schema)),
+        MaxTokens::Fixed(max_tokens) => Some(max_tokens),
+    };
+    if let Some(temperature) = options.temperature.or(config.temperature) {
+        client = client.with_temperature(temperature);
+    }
+    #[cfg(feature = "chrono")]
+    {
+        client = client.with_response_hook(crate::dates::normalize_dates);
+    }
+    if config.scalar_coercion {
+        client = client.with_scalar_coercion();
+    }
+    if config.lenient_json {
+        client = client.with_lenient_json();
+    }
+    if config.strict_schema {
+        client = client.with_strict_schema(true);
+    }
+    if let Some(candidates) = config.candidates {
+        client = client.with_candidates(candidates);
+    }
+    if let Some(runtime) = &config.runtime {
+        client = client.with_runtime(runtime.clone());
+    }
+    // Lists, scalars and nullable types don't start with `{`
+    if let Some(prefill) = &config.assistant_prefill
+        && schema::root_type(schema) == Some("object")
+    {
+        client = client.with_assistant_prefill(prefill.clone(), provider.prefill_style);
+    }
+    for hook in &config.response_hooks {
+        client = client.with_shared_response_hook(Arc::clone(hook));
+    }
+    if let Some(max_tokens) = max_tokens {
+        let max_context = model_capabilities(provider.model()).max_context;
+        client =
+            client.with_max_tokens(max_tokens.min(u32::try_from(max_context).unwrap_or(u32::MAX)));
+    }
+
+    // Models without structured output get JSON mode straight away. A provider that
+    // rejects the schema itself gets one more try in JSON mode, where it isn't enforced.
+    let mut json_mode = !model_capabilities(provider.model()).supports_json_schema;
+    let system_prompt = options.system_prompt.unwrap_or(SYSTEM_PROMPT);
+
+    // Every response counts, including incomplete answers that are asked for again and
+    // answers that are then rejected
+    let spent = Arc::new(std::sync::Mutex::new(None::<GroqUsage>));
+    let token_budget = config.token_budget;
+    let client = client.with_usage_observer({
+        let spent = Arc::clone(&spent);
+        Arc::new(move |usage: GroqUsage| {
+            if stats::TOKENS.add(u64::from(usage.total_tokens), token_budget) {
+                log::warn!(
+                    "AI recovery token budget of {} is used up; further recoveries are skipped",
+                    token_budget.unwrap_or_default()
+                );
+            }
+            let mut spent = spent
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            *spent = Some(match *spent {
+                Some(spent) => GroqUsage {
+                    prompt_tokens: spent.prompt_tokens + usage.prompt_tokens,
+                    completion_tokens: spent.completion_tokens + usage.completion_tokens,
+                    total_tokens: spent.total_tokens + usage.total_tokens,
+                },
+                None => usage,
+            });
+        })
+    });
+
+    // An answer that leaves out required fields is asked for again, naming them
+    let mut retry_prompt = None;
+    let mut retries_left = config.required_field_retries;
+    let response = loop {
+        let attempt = retry_prompt
+            .take()
+            .map_or(Cow::Borrowed(prompt), Cow::Owned);
+        let request = |json_mode| {
+            request_completion::<T>(
+                &client,
+                provider,
+                system_prompt,
+                &attempt,
+                schema_name,
+                schema,
+                json_mode,
+            )
+        };
+        let response = match request(json_mode).await {
+            Err(ai_error) if !json_mode && ai_error.is_schema_rejection() => {
+                json_mode = true;
+                request(json_mode).await
+            }
+            response => response,
+        };
+
+        let missing = match &response {
+            Err(AiError::Deserialize { value, .. })
+                if fail_closed || config.required_field_retries > 0 =>
+            {
+                schema::missing_required(value, schema)
+            }
+            _ => Vec::new(),
+        };
+        if missing.is_empty() {
+            break response.map_err(|ai_error| ai_error.with_request_id(request_id))?;
+        }
+        if retries_left == 0 {
+            return Err(AiError::Incomplete {
+                missing,
+                request_id: Some(request_id.to_string()),
+            });
+        }
+        retries_left -= 1;
+        retry_prompt = Some(prompt::with_missing_fields(prompt, &missing));
+    };
+    let StructuredResponse {
+        parsed: value,
+        json,
+        content,
+        usage,
+    } = response;
+    let usage = spent
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .unwrap_or(usage);
+
+    if fail_closed {
+        let missing = schema::missing_required(&json, schema);
+        if !missing.is_empty() {
+            return Err(AiError::Incomplete {
+                missing,
+                request_id: Some(request_id.to_string()),
+            });
+        }
+    }
+    let implausible = plausibility::implausible_fields::<T>(&json);
+    if !implausible.is_empty() {
+        return Err(AiError::Implausible {
+            fields: implausible,
+            request_id: Some(request_id.to_string()),
+        });
+    }
+    let cache_key = match (cache, options.cache) {
+        (None, _) => None,
+        (Some(cache), CacheUse::ReadWrite) => {
+            cache.insert(cache_key, json.clone());
+            None
+        }
+        (Some(_), CacheUse::ReadOnly | CacheUse::Bypass) => Some(cache_key),
+    };
+
+    Ok(Recovered {
+        value,
+        json,
+        raw: content,
+        usage: Some(usage),
+        request_id: request_id.to_string(),
+        cache_key,
+    })
+}
+
+async fn request_completion<T: Recoverable>(
+    client: &GroqClient,
+    provider: &Provider,
+    system_prompt: &str,
+    prompt: &str,
+    schema_name: &str,
+    schema: &serde_json::Value,
+    json_mode: bool,
+) -> Result<StructuredResponse<T>, AiError> {
+    if !json_mode {
+        return client
+            .chat_completion_structured_response(
+                provider.model(),
+                vec![("system", system_prompt), ("user", prompt)],
+                schema_name,
+                schema.clone(),
+            )
+            .await;
+    }
+
+    // JSON mode only guarantees an object, so the schema goes in the instructions
+    let system_prompt = format!(
+        "{}\n\nRespond with a single JSON object that validates against this JSON schema: {}",
+        system_prompt, schema
+    );
+    client
+        .chat_completion_json_object_response(
+            provider.model(),
+            vec![("system", &system_prompt), ("user", prompt)],
+            schema,
+        )
+        .await
+}
+
+/// Recover a failed `Result`/`Option` by asking the AI for a plausible value.
+///
+/// Any other expression, such as a method call on a `&self`, `&mut self` or owned
+/// receiver, is evaluated exactly once and recovered without source context.
+///
+/// A zero-argument async closure, `|| async { ... }` or `|| async move { ... }`, is
+/// called and awaited, and the `Result`/`Option` it yields is recovered. With no
+/// function to annotate, the closure's code is sent as the source of a function named
+/// `async_closure`:
+///
+/// ```no_run
+/// # use unwrap_or_ai::unwrap_or_ai;
+/// # async fn fetch_rate(from: &str, to: &str) -> Result<f64, String> { Err("offline".into()) }
+/// # async fn run() {
+/// let rate: f64 = unwrap_or_ai!(|| async { fetch_rate("EUR", "NOK").await }).await;
+/// # }
+/// ```
+///
+/// Calling a function by name, or by a path starting at `crate`, `self` or `super`
+/// (`crate::db::get_user(1)`), requires it to be annotated with `#[unwrap_or_ai_func]`,
+/// which captures its source for the prompt. Forgetting the attribute is reported as a
+/// missing `unwrap_or_ai_requires_<fn>_to_be_annotated_with_unwrap_or_ai_func`:
+///
+/// ```compile_fail
+/// use unwrap_or_ai::unwrap_or_ai;
+///
+/// fn lookup(id: u32) -> Option<String> {
+///     None
+/// }
+///
+/// # async fn run() {
+/// // error[E0425]: cannot find function
+/// // `unwrap_or_ai_requires_lookup_to_be_annotated_with_unwrap_or_ai_func`
+/// let name = unwrap_or_ai!(lookup(1)).await;
+/// # }
+/// ```
+///
+/// Other paths, like `std::env::var("HOME")` or `Config::load()`, are recovered as plain
+/// expressions.
+///
+/// # Target type inference
+///
+/// The recovered type is the `T` of the `Result<T, _>`/`Option<T>` being recovered, so
+/// it can come from the call site rather than the function: a generic function such as
+/// `fn read_cached<T: DeserializeOwned>(key: &str) -> Result<T, String>` is recovered as
+/// whatever type it is bound to, e.g. `let weather: WeatherData = unwrap_or_ai!(...)`.
+/// Return types written as aliases are recovered as the aliased type.
+///
+/// `T` must be known by the time the value is used, as with any other generic call.
+/// Using the result before anything fixes its type doesn't compile, and neither does
+/// recovering a function that returns `impl Trait`, since there is no concrete type to
+/// deserialize into:
+///
+/// ```compile_fail
+/// use unwrap_or_ai::unwrap_or_ai;
+/// use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
+///
+/// #[unwrap_or_ai_func]
+/// fn read_cached<T: serde::de::DeserializeOwned>(key: &str) -> Result<T, String> {
+///     Err(format!("nothing cached under {key}"))
+/// }
+///
+/// # async fn run() {
+/// // error[E0282]: type annotations needed
+/// let weather = unwrap_or_ai!(read_cached("oslo")).await;
+/// println!("{}", weather.len());
+/// # }
+/// ```
+///
+/// A function returning `impl Trait` is rejected where it is annotated, with an error
+/// asking for a concrete return type:
+///
+/// ```compile_fail
+/// use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
+///
+/// // error: unwrap_or_ai can't recover an `impl Trait` return type: ...
+/// #[unwrap_or_ai_func]
+/// fn latest_reading() -> Result<impl serde::Serialize, String> {
+///     Ok(21.5)
+/// }
+/// ```
+///
+/// So is one returning a borrowed value, which can't be deserialized from the model's
+/// answer; return an owned type such as `String` instead:
+///
+/// ```compile_fail
+/// use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
+///
+/// // error: unwrap_or_ai can't recover a borrowed type: ...
+/// #[unwrap_or_ai_func]
+/// fn config_value(key: &str) -> Option<&'static str> {
+///     (key == "region").then_some("eu-north-1")
+/// }
+/// ```
 #[macro_export]
 macro_rules! unwrap_or_ai {
-    ($fn_name:ident($($args:expr),*)) => {{
-        use $crate::unwrap_or_ai::UnwrapOrAi;
-        use $crate::paste;
+    // A fallback expression, evaluated only if the AI call fails too
+    ($fn_name:ident($($args:expr),*), fallback = $fallback:expr) => {
+        $crate::__unwrap_or_ai_recover!(unwrap_or_ai_or_else(|| $fallback); $fn_name($($args),*))
+    };
+
+    (|| async $($move:ident)? $body:block, fallback = $fallback:expr) => {
+        $crate::__unwrap_or_ai_recover!(
+            unwrap_or_ai_or_else(|| $fallback); || async $($move)? $body
+        )
+    };
+
+    ($fn_call:expr, fallback = $fallback:expr) => {
+        $crate::__unwrap_or_ai_recover!(unwrap_or_ai_or_else(|| $fallback); $fn_call)
+    };
+
+    ($($input:tt)*) => {
+        $crate::__unwrap_or_ai_recover!(unwrap_or_ai_impl(); $($input)*)
+    };
+}
+
+/// The code `unwrap_or_ai!` generates for the same input, as a `&'static str`.
+///
+/// Nothing is evaluated. Useful when an `unwrap_or_ai!` call fails to compile: the string
+/// shows which arm matched (a plain function call, which needs `#[unwrap_or_ai_func]`,
+/// or any other expression) and how the [`RecoveryContext`](crate::RecoveryContext) is
+/// filled in. `__paste!` invocations and `$crate` are shown unexpanded.
+///
+/// ```
+/// use unwrap_or_ai::unwrap_or_ai_expand;
+///
+/// let expansion = unwrap_or_ai_expand!(lookup(1));
+/// assert!(expansion.contains("params_of_ lookup"));
+/// println!("{expansion}");
+/// ```
+#[macro_export]
+macro_rules! unwrap_or_ai_expand {
+    ($fn_name:ident($($args:expr),*), fallback = $fallback:expr) => {
+        $crate::__unwrap_or_ai_expansion!(
+            __unwrap_or_ai_stringify; unwrap_or_ai_or_else(|| $fallback); $fn_name($($args),*)
+        )
+    };
+
+    (|| async $($move:ident)? $body:block, fallback = $fallback:expr) => {
+        $crate::__unwrap_or_ai_expansion!(
+            __unwrap_or_ai_stringify; unwrap_or_ai_or_else(|| $fallback); || async $($move)? $body
+        )
+    };
+
+    ($fn_call:expr, fallback = $fallback:expr) => {
+        $crate::__unwrap_or_ai_expansion!(
+            __unwrap_or_ai_stringify; unwrap_or_ai_or_else(|| $fallback); $fn_call
+        )
+    };
+
+    ($($input:tt)*) => {
+        $crate::__unwrap_or_ai_expansion!(__unwrap_or_ai_stringify; unwrap_or_ai_impl(); $($input)*)
+    };
+}
+
+// For an Option: evaluates to Result<Option<T>, AiRecoveryError>, keeping None when the
+// AI judges that no sensible value exists
+#[macro_export]
+macro_rules! option_or_ai {
+    ($($input:tt)*) => {
+        $crate::__unwrap_or_ai_recover!(recover_option_impl(); $($input)*)
+    };
+}
+
+/// Same as `unwrap_or_ai!`, but evaluates to `Result<T, AiRecoveryError>` instead of
+/// panicking.
+///
+/// With `recover_if = predicate`, only errors the predicate accepts are recovered, so
+/// transient failures can be smoothed over while bad input still propagates. The result
+/// is then `Result<Result<T, E>, AiRecoveryError>`: the inner `Err` is the original
+/// error, returned without calling the AI.
+///
+/// ```no_run
+/// # use unwrap_or_ai::{try_unwrap_or_ai, unwrap_or_ai_func};
+/// # #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+/// # struct User { name: String }
+/// #[derive(Debug)]
+/// enum DbError {
+///     Timeout,
+///     InvalidId,
+/// }
+///
+/// #[unwrap_or_ai_func]
+/// fn get_user(id: u32) -> Result<User, DbError> {
+///     Err(DbError::Timeout)
+/// }
+///
+/// # async fn run() {
+/// let user = try_unwrap_or_ai!(get_user(1), recover_if = |e| matches!(e, DbError::Timeout))
+///     .await
+///     .expect("AI recovery failed");
+/// assert!(matches!(user, Ok(_) | Err(DbError::InvalidId)));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! try_unwrap_or_ai {
+    ($fn_name:ident($($args:expr),*), recover_if = $should_recover:expr) => {
+        $crate::__unwrap_or_ai_recover!(
+            try_unwrap_or_ai_if($should_recover); $fn_name($($args),*)
+        )
+    };
+
+    (|| async $($move:ident)? $body:block, recover_if = $should_recover:expr) => {
+        $crate::__unwrap_or_ai_recover!(
+            try_unwrap_or_ai_if($should_recover); || async $($move)? $body
+        )
+    };
+
+    ($fn_call:expr, recover_if = $should_recover:expr) => {
+        $crate::__unwrap_or_ai_recover!(try_unwrap_or_ai_if($should_recover); $fn_call)
+    };
+
+    ($($input:tt)*) => {
+        $crate::__unwrap_or_ai_recover!(try_unwrap_or_ai_impl(); $($input)*)
+    };
+}
+
+/// Run a block of fallible steps and recover its final value if any `?` fails.
+///
+/// The block runs as an async block returning `Result<_, Box<dyn Error + Send + Sync>>`,
+/// so `?` works on any `Result` whose error converts into a boxed error (including
+/// `String` and `&str`), and `.await` is allowed. For an `Option`, use
+/// `.ok_or("reason")?`. The first failure stops the block; its message goes into the
+/// prompt, and the AI is asked for the block's final value.
+///
+/// `?` inside `unwrap_or_ai!(...)` itself is not supported, since it would have to
+/// return from the surrounding function; this macro is the way to combine several calls.
+///
+/// ```no_run
+/// # use unwrap_or_ai::unwrap_or_ai_block;
+/// # fn load_config() -> Result<String, String> { Err("missing".into()) }
+/// # fn parse_port(config: &str) -> Result<u16, std::num::ParseIntError> { config.parse() }
+/// # async fn run() {
+/// let port: u16 = unwrap_or_ai_block! {
+///     let config = load_config()?;
+///     parse_port(&config)?
+/// }
+/// .await;
+/// # }
+/// ```
+#[macro_export]
+macro_rules! unwrap_or_ai_block {
+    ($($body:tt)*) => {
+        $crate::__unwrap_or_ai_block!(unwrap_or_ai_impl(); $($body)*)
+    };
+}
+
+// Same as unwrap_or_ai_block!, but evaluates to Result<T, AiRecoveryError>
+#[macro_export]
+macro_rules! try_unwrap_or_ai_block {
+    ($($body:tt)*) => {
+        $crate::__unwrap_or_ai_block!(try_unwrap_or_ai_impl(); $($body)*)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_block {
+    ($method:ident(); $($body:tt)*) => {{
+        #[allow(unused_imports)]
+        use $crate::unwrap_or_ai::{TryUnwrapOrAi, UnwrapOrAi};
 
         async {
-            // Call the original function
-            let result = $fn_name($($args),*);
-
-            let source_code = paste::paste! { [<print_source_of_ $fn_name>]() };
-            // Prepare the prompt for the AI with function context
-            let prompt = format!(
-                "The following function call failed: {}({})
-                Function name: {}
-                Parameters: {:?}
-                Source code: {}
-                
-                This function should return the appropriate type. Generate a reasonable response as valid JSON.",
-                stringify!($fn_name),
-                stringify!($($args),*),
-                stringify!($fn_name),
-                stringify!($($args),*),
-                source_code
-            );
-
-            // Use the trait method to handle AI recovery with proper type inference
-            result.unwrap_or_ai_impl(prompt).await
+            let result: ::core::result::Result<_, ::std::boxed::Box<dyn ::std::error::Error + Send + Sync>> =
+                async { ::core::result::Result::Ok({ $($body)* }) }.await;
+
+            let mut context = $crate::RecoveryContext::new(stringify!({ $($body)* }));
+            context.original_error = $crate::__unwrap_or_ai_error_text!(result).map(Into::into);
+            context.error_context = $crate::__unwrap_or_ai_error_context!(result);
+
+            result.$method(context).await
         }
     }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_recover {
+    ($($input:tt)*) => {
+        $crate::__unwrap_or_ai_expansion!(__unwrap_or_ai_emit; $($input)*)
+    };
+}
+
+// Builds the recovery code and hands it to `$emit`, which either emits it or, for
+// unwrap_or_ai_expand!, turns it into a string, so both always agree
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_expansion {
+    ($emit:ident; $method:ident($($extra:expr),*); $fn_name:ident($($args:expr),*)) => {
+        $crate::__unwrap_or_ai_function!($emit; $method($($extra),*); [] $fn_name($($args),*))
+    };
+
+    // `crate::module::function(...)`, or one starting at `self` or `super`: the helpers
+    // live next to the function, so the path is split into its module and the function
+    // name. Other paths, such as `std::env::var(...)` or `Type::new(...)`, can't carry
+    // the attribute and are recovered as plain expressions below.
+    (
+        $emit:ident; $method:ident($($extra:expr),*);
+        crate $(:: $rest:ident)+ ($($args:expr),*)
+    ) => {
+        $crate::__unwrap_or_ai_split_path!(
+            [$emit; $method($($extra),*); ($($args),*)] [] crate $(:: $rest)+
+        )
+    };
+    (
+        $emit:ident; $method:ident($($extra:expr),*);
+        self $(:: $rest:ident)+ ($($args:expr),*)
+    ) => {
+        $crate::__unwrap_or_ai_split_path!(
+            [$emit; $method($($extra),*); ($($args),*)] [] self $(:: $rest)+
+        )
+    };
+    (
+        $emit:ident; $method:ident($($extra:expr),*);
+        super $(:: $rest:ident)+ ($($args:expr),*)
+    ) => {
+        $crate::__unwrap_or_ai_split_path!(
+            [$emit; $method($($extra),*); ($($args),*)] [] super $(:: $rest)+
+        )
+    };
+
+    // `|| async { ... }`: an ad-hoc async closure, awaited before recovery. There is no
+    // function to annotate, so the closure's own code is sent as the source
+    ($emit:ident; $method:ident($($extra:expr),*); || async $($body:tt)+) => {
+        $crate::$emit! {{
+            #[allow(unused_imports)]
+            use $crate::unwrap_or_ai::{
+                RecoverOptionWithAi, TryUnwrapOrAi, TryUnwrapOrAiIf, UnwrapOrAi,
+            };
+
+            async {
+                let closure = || async $($body)+;
+                let result = closure().await;
+
+                let mut context = $crate::RecoveryContext::new("async_closure")
+                    .with_source(stringify!(|| async $($body)+));
+                context.original_error = $crate::__unwrap_or_ai_error_text!(result).map(Into::into);
+                context.error_context = $crate::__unwrap_or_ai_error_context!(result);
+
+                result.$method(context $(, $extra)*).await
+            }
+        }}
+    };
 
     // Fallback for other expressions
-    ($fn_call:expr) => {{
-        use $crate::unwrap_or_ai::UnwrapOrAi;
+    ($emit:ident; $method:ident($($extra:expr),*); $fn_call:expr) => {
+        $crate::$emit! {{
+            #[allow(unused_imports)]
+            use $crate::unwrap_or_ai::{
+                RecoverOptionWithAi, TryUnwrapOrAi, TryUnwrapOrAiIf, UnwrapOrAi,
+            };
 
-        async {
-            // Call the original function
-            let result = $fn_call;
+            async {
+                // Call the original function
+                let result = $fn_call;
 
-            // Prepare the prompt for the AI
-            let prompt = format!(
-                "The following function call failed: {}
-                
-                Generate a reasonable response as valid JSON that matches the expected return type.",
-                stringify!($fn_call)
-            );
+                // No source is available here; call_ai_for_type adds the target type name
+                let mut context = $crate::RecoveryContext::new(stringify!($fn_call));
+                context.original_error = $crate::__unwrap_or_ai_error_text!(result).map(Into::into);
+                context.error_context = $crate::__unwrap_or_ai_error_context!(result);
 
-            println!("Prompt for AI: {}", prompt);
+                // Use the trait method to handle AI recovery with proper type inference
+                result.$method(context $(, $extra)*).await
+            }
+        }}
+    };
+}
 
-            // Use the trait method to handle AI recovery with proper type inference
-            result.unwrap_or_ai_impl(prompt).await
-        }
+// A call to a function annotated with #[unwrap_or_ai_func], found at `$($module)::*`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_function {
+    (
+        $emit:ident; $method:ident($($extra:expr),*);
+        [$($module:ident ::)*] $fn_name:ident($($args:expr),*)
+    ) => {
+        $crate::$emit! {{
+            #[allow(unused_imports)]
+            use $crate::unwrap_or_ai::{
+                RecoverOptionWithAi, TryUnwrapOrAi, TryUnwrapOrAiIf, UnwrapOrAi,
+            };
+
+            async {
+                // Call the original function
+                let result = $($module ::)* $fn_name($($args),*);
+
+                // Resolves only if $fn_name carries #[unwrap_or_ai_func]; otherwise the
+                // "cannot find value" error names the missing attribute
+                let source_code = $crate::__paste! {
+                    $($module ::)*
+                        [<unwrap_or_ai_requires_ $fn_name _to_be_annotated_with_unwrap_or_ai_func>]()
+                };
+                let mut context = $crate::RecoveryContext::new(stringify!($fn_name))
+                    .with_args([$(stringify!($args)),*])
+                    .with_params(
+                        $crate::__paste! { $($module ::)* [<params_of_ $fn_name>]() }
+                            .iter()
+                            .copied(),
+                    )
+                    .with_source(source_code)
+                    .with_docs($crate::__paste! { $($module ::)* [<doc_of_ $fn_name>]() })
+                    .with_source_hash(
+                        $crate::__paste! { $($module ::)* [<source_hash_of_ $fn_name>]() }
+                    );
+                context.model = $crate::__paste! { $($module ::)* [<model_of_ $fn_name>]() }
+                    .map(str::to_string);
+                context.original_error = $crate::__unwrap_or_ai_error_text!(result).map(Into::into);
+                context.error_context = $crate::__unwrap_or_ai_error_context!(result);
+
+                // Use the trait method to handle AI recovery with proper type inference
+                result.$method(context $(, $extra)*).await
+            }
+        }}
+    };
+}
+
+// Moves path segments into the module list until only the function name is left
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_split_path {
+    (
+        [$emit:ident; $method:ident($($extra:expr),*); ($($args:expr),*)]
+        [$($module:ident ::)*] $fn_name:ident
+    ) => {
+        $crate::__unwrap_or_ai_function!(
+            $emit; $method($($extra),*); [$($module ::)*] $fn_name($($args),*)
+        )
+    };
+
+    ($call:tt [$($module:ident ::)*] $segment:ident :: $($rest:tt)+) => {
+        $crate::__unwrap_or_ai_split_path!($call [$($module ::)* $segment ::] $($rest)+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_emit {
+    ($($tokens:tt)*) => { $($tokens)* };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_stringify {
+    ($($tokens:tt)*) => { stringify!($($tokens)*) };
+}
+
+// The Display text of `$result`'s error, or None for an Option or a non-Display error
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_error_text {
+    ($result:ident) => {{
+        #[allow(unused_imports)]
+        use $crate::unwrap_or_ai::{DisplayErrorText, NoErrorText};
+        (&&$crate::unwrap_or_ai::ErrorText(&$result)).error_text()
+    }};
+}
+
+// The `ErrorContext` details of `$result`'s error, or None when it has none
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unwrap_or_ai_error_context {
+    ($result:ident) => {{
+        #[allow(unused_imports)]
+        use $crate::unwrap_or_ai::{NoErrorContext, StructuredErrorContext};
+        (&&$crate::unwrap_or_ai::ErrorText(&$result)).error_context()
     }};
 }
@@ -0,0 +1,287 @@
+//! End-to-end recoveries against a local server standing in for Groq's
+//! `chat/completions` endpoint, so the HTTP path runs without an API key.
+#![cfg(all(feature = "network", not(feature = "no-ai")))]
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
+use unwrap_or_ai::{
+    AiError, ApiErrorKind, GroqClient, Provider, Recovery, RecoveryConfig, auto_recover, config,
+    register_schema,
+};
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct Weather {
+    city: String,
+    temperature: f64,
+}
+
+mod common;
+
+use common::StubServer;
+
+// Recoveries and clients pointed at the stub, under the path Groq's API lives at
+fn provider(server: &StubServer) -> Provider {
+    Provider::new("stub", base_url(server), "UNUSED", "stub-model").with_api_key("test-key")
+}
+
+fn client(server: &StubServer) -> GroqClient {
+    GroqClient::new("test-key".to_string()).with_base_url(base_url(server))
+}
+
+fn base_url(server: &StubServer) -> String {
+    format!("{}/openai/v1", server.url)
+}
+
+/// A chat completions response with `content` as the message
+fn completion(content: &str, finish_reason: &str) -> String {
+    json!({
+        "id": "chatcmpl-stub",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "stub-model",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": finish_reason
+        }],
+        "usage": { "prompt_tokens": 20, "completion_tokens": 8, "total_tokens": 28 }
+    })
+    .to_string()
+}
+
+fn oslo() -> String {
+    completion(r#"{"city": "Oslo", "temperature": -3.5}"#, "stop")
+}
+
+const RATE_LIMITED: &str = r#"{"error":{"message":"Rate limit reached for model `stub-model`","type":"tokens","code":"rate_limit_exceeded"}}"#;
+
+#[tokio::test]
+async fn test_structured_completion_round_trip() {
+    let server = StubServer::start(vec![(200, oslo())]).await;
+
+    let weather: Weather = client(&server)
+        .chat_completion_structured(
+            "stub-model",
+            vec![("user", "Weather in Oslo?")],
+            "weather",
+            schemars::schema_for!(Weather).to_value(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(weather.city, "Oslo");
+    assert_eq!(weather.temperature, -3.5);
+
+    let received = server.received.lock().unwrap();
+    let request = &received[0];
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/openai/v1/chat/completions");
+    assert_eq!(request.authorization.as_deref(), Some("Bearer test-key"));
+    let body = request.json();
+    assert_eq!(body["model"], "stub-model");
+    assert_eq!(body["messages"][0]["content"], "Weather in Oslo?");
+    assert_eq!(body["response_format"]["json_schema"]["name"], "weather");
+}
+
+#[tokio::test]
+async fn test_typed_completion_sends_the_registered_schema() {
+    #[derive(Debug, Deserialize, JsonSchema)]
+    struct Forecast {
+        city: String,
+    }
+
+    let schema = json!({
+        "type": "object",
+        "properties": { "city": { "type": "string", "enum": ["Oslo", "Bergen"] } },
+        "required": ["city"]
+    });
+    register_schema::<Forecast>(schema.clone());
+    let server = StubServer::start(vec![(200, completion(r#"{"city": "Oslo"}"#, "stop"))]).await;
+
+    let forecast: Forecast = client(&server)
+        .chat_completion_typed("stub-model", vec![("user", "Forecast?")])
+        .await
+        .unwrap();
+    assert_eq!(forecast.city, "Oslo");
+
+    let body = server.received.lock().unwrap()[0].json();
+    assert_eq!(body["response_format"]["json_schema"]["schema"], schema);
+}
+
+#[tokio::test]
+async fn test_json_is_extracted_from_prose() {
+    let wrapped = completion(
+        "Here is the weather:\n```json\n{\"city\": \"Oslo\", \"temperature\": -3.5}\n```",
+        "stop",
+    );
+    let server = StubServer::start(vec![(200, wrapped)]).await;
+
+    let weather: Weather = client(&server)
+        .chat_completion_structured(
+            "stub-model",
+            vec![("user", "Weather in Oslo?")],
+            "weather",
+            schemars::schema_for!(Weather).to_value(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(weather.city, "Oslo");
+}
+
+#[tokio::test]
+async fn test_rate_limited_then_success() {
+    let server = StubServer::start(vec![(429, RATE_LIMITED.to_string()), (200, oslo())]).await;
+    let config = RecoveryConfig::new().with_providers(vec![provider(&server)]);
+
+    let weather = config::scope(config, async {
+        Recovery::new("Weather in Oslo?")
+            .retries(1)
+            .recover::<Weather>()
+            .await
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(weather.city, "Oslo");
+    assert_eq!(server.requests(), 2);
+}
+
+#[tokio::test]
+async fn test_rate_limit_is_reported() {
+    let server = StubServer::start(vec![(429, RATE_LIMITED.to_string())]).await;
+    let config = RecoveryConfig::new().with_providers(vec![provider(&server)]);
+
+    let error = config::scope(config, async {
+        Recovery::new("Weather in Oslo?").recover::<Weather>().await
+    })
+    .await
+    .unwrap_err();
+
+    assert_eq!(error.api_error_kind(), Some(ApiErrorKind::RateLimited));
+    assert!(
+        error.to_string().contains("Rate limit reached"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn test_truncated_answer_is_retried() {
+    let truncated = completion(r#"{"city": "Oslo", "tempera"#, "length");
+    let server = StubServer::start(vec![
+        (200, truncated.clone()),
+        (200, truncated),
+        (200, oslo()),
+    ])
+    .await;
+    let config = RecoveryConfig::new().with_providers(vec![provider(&server)]);
+
+    let weather = config::scope(config, async {
+        let once = Recovery::new("Weather in Oslo?").recover::<Weather>().await;
+        assert!(matches!(once, Err(AiError::Json { .. })));
+
+        Recovery::new("Weather in Oslo?")
+            .retries(1)
+            .recover::<Weather>()
+            .await
+    })
+    .await
+    .unwrap();
+
+    // Truncated once more, then answered in full
+    assert_eq!(weather.temperature, -3.5);
+    assert_eq!(server.requests(), 3);
+}
+
+#[tokio::test]
+async fn test_malformed_json_needs_lenient_parsing() {
+    let malformed = completion("```json\n{'city': 'Oslo', temperature: -3.5,}\n```", "stop");
+    let server = StubServer::start(vec![(200, malformed.clone()), (200, malformed)]).await;
+
+    let strict = config::scope(
+        RecoveryConfig::new().with_providers(vec![provider(&server)]),
+        async { Recovery::new("Weather in Oslo?").recover::<Weather>().await },
+    )
+    .await;
+    assert!(matches!(strict, Err(AiError::Json { .. })));
+
+    let lenient = RecoveryConfig::new()
+        .with_providers(vec![provider(&server)])
+        .with_lenient_json(true);
+    let weather = config::scope(lenient, async {
+        Recovery::new("Weather in Oslo?").recover::<Weather>().await
+    })
+    .await
+    .unwrap();
+    assert_eq!(weather.city, "Oslo");
+    assert_eq!(weather.temperature, -3.5);
+}
+
+#[tokio::test]
+async fn test_gateway_with_custom_completions_path() {
+    let server = StubServer::start(vec![(200, oslo())]).await;
+    let gateway = provider(&server).with_completions_path("/v2/generate");
+    let config = RecoveryConfig::new().with_providers(vec![gateway]);
+
+    let weather = config::scope(config, async {
+        Recovery::new("Weather in Oslo?").recover::<Weather>().await
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(weather.city, "Oslo");
+    assert_eq!(
+        server.received.lock().unwrap()[0].path,
+        "/openai/v1/v2/generate"
+    );
+}
+
+/// Reads the latest observation for `city` from the station cache
+#[auto_recover]
+async fn cached_weather(city: &str) -> Result<Weather, String> {
+    tokio::task::yield_now().await;
+    Err(format!("no observation for {city} in the cache"))
+}
+
+#[auto_recover]
+async fn cached_city(id: u32) -> Option<String> {
+    (id == 1).then(|| "Oslo".to_string())
+}
+
+#[tokio::test]
+async fn test_auto_recover_recovers_inside_the_function() {
+    let server = StubServer::start(vec![
+        (200, oslo()),
+        (400, r#"{"error":{"message":"bad request"}}"#.to_string()),
+    ])
+    .await;
+    let config = RecoveryConfig::new().with_providers(vec![provider(&server)]);
+
+    let (recovered, unrecovered) = config::scope(config, async {
+        (cached_weather("Oslo").await, cached_weather("Bergen").await)
+    })
+    .await;
+
+    assert_eq!(recovered.unwrap().temperature, -3.5);
+    // When recovery fails too, the function's own error comes back
+    assert_eq!(
+        unrecovered.unwrap_err(),
+        "no observation for Bergen in the cache"
+    );
+
+    let received = server.received.lock().unwrap();
+    let body = received[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("no observation for Oslo in the cache"));
+    assert!(prompt.contains("Reads the latest observation for `city` from the station cache"));
+}
+
+#[tokio::test]
+async fn test_auto_recover_leaves_values_alone() {
+    let server = StubServer::start(Vec::new()).await;
+    let config = RecoveryConfig::new().with_providers(vec![provider(&server)]);
+
+    let city = config::scope(config, cached_city(1)).await;
+    assert_eq!(city.as_deref(), Some("Oslo"));
+    assert_eq!(server.requests(), 0);
+}
@@ -0,0 +1,72 @@
+//! `#[unwrap_or_ai_func(learn)]` as used from outside the crate.
+
+use serde::Serialize;
+use unwrap_or_ai::RecoveryContext;
+use unwrap_or_ai::learn::example_for;
+use unwrap_or_ai_proc_macro::unwrap_or_ai_func;
+
+#[derive(Debug, Serialize)]
+struct Invoice {
+    number: String,
+    total_cents: u64,
+}
+
+/// Looks an invoice up in the billing service
+#[unwrap_or_ai_func(learn)]
+fn find_invoice(id: u32) -> Result<Invoice, String> {
+    if id == 0 {
+        return Err("invoice ids start at 1".to_string());
+    }
+    let total_cents = format!("{}00", id)
+        .parse::<u64>()
+        .map_err(|e| e.to_string())?;
+    Ok(Invoice {
+        number: format!("INV-{:04}", id),
+        total_cents,
+    })
+}
+
+#[unwrap_or_ai_func(learn)]
+async fn latest_tag(repo: &str) -> Option<String> {
+    tokio::task::yield_now().await;
+    repo.strip_prefix("known/")
+        .map(|name| format!("{name}-v1.2.0"))
+}
+
+#[test]
+fn test_successful_calls_become_examples() {
+    assert!(find_invoice(0).is_err());
+    assert_eq!(
+        example_for("find_invoice", source_hash_of_find_invoice()),
+        None
+    );
+
+    assert_eq!(find_invoice(7).unwrap().number, "INV-0007");
+    let example = example_for("find_invoice", source_hash_of_find_invoice()).unwrap();
+    assert_eq!(example, r#"{"number":"INV-0007","total_cents":700}"#);
+
+    let prompt = RecoveryContext::new("find_invoice")
+        .with_source(print_source_of_find_invoice())
+        .with_source_hash(source_hash_of_find_invoice())
+        .prompt();
+    assert!(prompt.contains(&format!("A recent successful call returned: {}", example)));
+
+    // A different version of the function doesn't get this one's example
+    let changed = RecoveryContext::new("find_invoice")
+        .with_source_hash(source_hash_of_find_invoice() ^ 1)
+        .prompt();
+    assert!(!changed.contains("A recent successful call returned"));
+}
+
+#[tokio::test]
+async fn test_async_functions_learn_too() {
+    assert_eq!(latest_tag("unknown/repo").await, None);
+    assert_eq!(
+        latest_tag("known/serde").await.as_deref(),
+        Some("serde-v1.2.0")
+    );
+    assert_eq!(
+        example_for("latest_tag", source_hash_of_latest_tag()).as_deref(),
+        Some(r#""serde-v1.2.0""#)
+    );
+}
@@ -0,0 +1,34 @@
+//! `#[derive(Recoverable)]` capturing the type's definition for recovery prompts.
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use unwrap_or_ai::Recoverable;
+use unwrap_or_ai::recoverable::TypeSource;
+
+/// A reading from one of the greenhouse sensors
+#[derive(Debug, Deserialize, JsonSchema, Recoverable)]
+#[allow(dead_code)]
+struct SensorReading {
+    /// Air temperature in degrees Celsius
+    temperature: f64,
+    /// Relative humidity from 0 to 100
+    humidity: u8,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Recoverable)]
+#[allow(dead_code)]
+struct Page<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+}
+
+#[test]
+fn test_derive_captures_the_definition() {
+    let source = SensorReading::SOURCE;
+    assert!(source.contains("struct SensorReading"), "{}", source);
+    assert!(source.contains("A reading from one of the greenhouse sensors"));
+    assert!(source.contains("Air temperature in degrees Celsius"));
+    assert!(source.contains("humidity : u8"));
+
+    assert!(<Page<SensorReading>>::SOURCE.contains("next_cursor : Option < String >"));
+}
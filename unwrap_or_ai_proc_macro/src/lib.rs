@@ -1,33 +1,571 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{ItemFn, parse_macro_input};
+use quote::{quote, quote_spanned};
+use syn::{DeriveInput, ItemFn, parse_macro_input, spanned::Spanned};
 
+/// Capture a function's source so `unwrap_or_ai!` can send it along with a recovery.
+///
+/// `#[unwrap_or_ai_func(model = "openai/gpt-oss-120b")]` also picks the model used when
+/// recovering calls to this function, instead of each provider's default.
+///
+/// `#[unwrap_or_ai_func(learn)]` remembers the value of each successful call and shows
+/// the last one to the model when recovering a later failure; see `unwrap_or_ai::learn`.
+/// The function must return a `Result` or `Option` whose value implements `Serialize`.
 #[proc_macro_attribute]
-pub fn unwrap_or_ai_func(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn unwrap_or_ai_func(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut model: Option<syn::LitStr> = None;
+    let mut learn = false;
+    let attr_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("model") {
+            model = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("learn") {
+            learn = true;
+            Ok(())
+        } else {
+            Err(meta.error("unsupported unwrap_or_ai_func option, expected `model` or `learn`"))
+        }
+    });
+    parse_macro_input!(attr with attr_parser);
+
     let input = parse_macro_input!(item as ItemFn);
 
+    // Recovery deserializes into the return type, which `impl Trait` hides; without this
+    // the caller gets an unreadable trait-bound error at the `unwrap_or_ai!` call instead
+    if let Some(recovered) = recovered_type(&input.sig) {
+        if let Some(opaque) = find_impl_trait(recovered) {
+            return opaque_error(opaque).to_compile_error().into();
+        }
+        if let Some(borrowed) = find_borrow(recovered) {
+            return borrow_error(borrowed).to_compile_error().into();
+        }
+    }
+
     let fn_name = &input.sig.ident;
     let helper_fn_name = syn::Ident::new(&format!("print_source_of_{}", fn_name), fn_name.span());
+    let model_fn_name = syn::Ident::new(&format!("model_of_{}", fn_name), fn_name.span());
+    let doc_fn_name = syn::Ident::new(&format!("doc_of_{}", fn_name), fn_name.span());
+    let params_fn_name = syn::Ident::new(&format!("params_of_{}", fn_name), fn_name.span());
+    let hash_fn_name = syn::Ident::new(&format!("source_hash_of_{}", fn_name), fn_name.span());
+    let params = param_names(&input.sig);
+    let model = match model {
+        Some(model) => quote! { ::core::option::Option::Some(#model) },
+        None => quote! { ::core::option::Option::None },
+    };
+    // unwrap_or_ai! looks the source up through this name, so calling it on a function
+    // without the attribute fails with an error that spells out what is missing
+    let marker_name = syn::Ident::new(
+        &format!(
+            "unwrap_or_ai_requires_{}_to_be_annotated_with_unwrap_or_ai_func",
+            fn_name
+        ),
+        fn_name.span(),
+    );
 
     // Collect all attributes (this includes doc comments)
     let attrs = &input.attrs;
     let sig = &input.sig;
     let block = &input.block;
 
+    let doc = doc_text(attrs);
+
     // Reconstruct with attributes so docs are preserved
     let src_string = quote! {
         #(#attrs)*
         #sig #block
     }
     .to_string();
+    let source_hash = stable_hash(&src_string);
+
+    let function = if learn {
+        match remembering_successes(&input, source_hash) {
+            Ok(function) => function,
+            Err(error) => return error.to_compile_error().into(),
+        }
+    } else {
+        input.clone()
+    };
 
     let expanded = quote! {
-        #input
+        #function
 
         pub fn #helper_fn_name() -> &'static str {
             #src_string
         }
+
+        pub fn #model_fn_name() -> ::core::option::Option<&'static str> {
+            #model
+        }
+
+        pub fn #doc_fn_name() -> &'static str {
+            #doc
+        }
+
+        pub fn #params_fn_name() -> &'static [&'static str] {
+            &[#(#params),*]
+        }
+
+        pub fn #hash_fn_name() -> u64 {
+            #source_hash
+        }
+
+        #[doc(hidden)]
+        #[allow(non_upper_case_globals)]
+        pub const #marker_name: fn() -> &'static str = #helper_fn_name;
+    };
+
+    expanded.into()
+}
+
+/// Recover every failure of an `async fn` inside the function itself, so callers get a
+/// recovered value without wrapping each call in `unwrap_or_ai!`.
+///
+/// An `Err` the body returns is recovered with `unwrap_or_ai::recover`, an empty `None`
+/// with `unwrap_or_ai::recover_optional`, sending the function's source, docs and the
+/// error along. If recovery fails as well, the original `Err` or `None` is returned.
+/// `#[auto_recover(model = "...")]` picks the model, as with `unwrap_or_ai_func`.
+///
+/// The function already carries its own context, so it doesn't need
+/// `#[unwrap_or_ai_func]` too. Adding it (below `#[auto_recover]`, so it sees the
+/// original body) still provides the `print_source_of_<fn>` helpers, e.g. for `learn`,
+/// but recovering such a function with `unwrap_or_ai!` as well only asks again for a
+/// value the function already tried to recover.
+#[proc_macro_attribute]
+pub fn auto_recover(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut model: Option<syn::LitStr> = None;
+    let attr_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("model") {
+            model = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported auto_recover option, expected `model`"))
+        }
+    });
+    parse_macro_input!(attr with attr_parser);
+
+    let input = parse_macro_input!(item as ItemFn);
+    match recovering_failures(&input, model) {
+        Ok(function) => quote! { #function }.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+// `function` with its body wrapped so that an `Err` or `None` is recovered before it is
+// returned
+fn recovering_failures(function: &ItemFn, model: Option<syn::LitStr>) -> syn::Result<ItemFn> {
+    if function.sig.asyncness.is_none() {
+        return Err(syn::Error::new(
+            function.sig.fn_token.span(),
+            "`auto_recover` needs an `async fn`, since recovery waits for a model; recover \
+             calls to a sync function with `unwrap_or_ai!` instead",
+        ));
+    }
+    let syn::ReturnType::Type(_, output) = &function.sig.output else {
+        return Err(syn::Error::new(
+            function.sig.span(),
+            "`auto_recover` needs a function returning a `Result` or `Option`",
+        ));
+    };
+    let wrapper = match &**output {
+        syn::Type::Path(path) => path.path.segments.last().map(|segment| &segment.ident),
+        _ => None,
+    };
+    let is_option = match wrapper {
+        Some(ident) if ident == "Result" => false,
+        Some(ident) if ident == "Option" => true,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                output,
+                "`auto_recover` needs a function returning a `Result` or `Option`",
+            ));
+        }
+    };
+    if let Some(recovered) = recovered_type(&function.sig) {
+        if let Some(opaque) = find_impl_trait(recovered) {
+            return Err(opaque_error(opaque));
+        }
+        if let Some(borrowed) = find_borrow(recovered) {
+            return Err(borrow_error(borrowed));
+        }
+    }
+
+    let fn_name = function.sig.ident.to_string();
+    let attrs = &function.attrs;
+    let sig = &function.sig;
+    let block = &function.block;
+    let source = quote! {
+        #(#attrs)*
+        #sig #block
+    }
+    .to_string();
+    let source_hash = stable_hash(&source);
+    let doc = doc_text(attrs);
+    let docs = if doc.is_empty() {
+        quote! { ::core::option::Option::None }
+    } else {
+        quote! { ::core::option::Option::Some(#doc.to_string()) }
+    };
+    let model = match model {
+        Some(model) => quote! { ::core::option::Option::Some(#model.to_string()) },
+        None => quote! { ::core::option::Option::None },
+    };
+    let context = quote! {
+        {
+            let mut context = ::unwrap_or_ai::RecoveryContext::new(#fn_name)
+                .with_source(#source)
+                .with_source_hash(#source_hash);
+            context.docs = #docs;
+            context.model = #model;
+            context.original_error = __unwrap_or_ai_error.map(::core::convert::Into::into);
+            context.error_context = __unwrap_or_ai_error_context;
+            context
+        }
+    };
+    let recovery = if is_option {
+        quote! {
+            match __unwrap_or_ai_result {
+                ::core::option::Option::Some(value) => ::core::option::Option::Some(value),
+                ::core::option::Option::None => {
+                    ::unwrap_or_ai::recover_optional(#context).await.ok().flatten()
+                }
+            }
+        }
+    } else {
+        quote! {
+            match __unwrap_or_ai_result {
+                ::core::result::Result::Ok(value) => ::core::result::Result::Ok(value),
+                ::core::result::Result::Err(error) => {
+                    ::unwrap_or_ai::recover(#context).await.map_err(|_| error)
+                }
+            }
+        }
+    };
+
+    let mut function = function.clone();
+    function.block = syn::parse_quote! {{
+        let __unwrap_or_ai_result: #output = async move #block.await;
+        let __unwrap_or_ai_error = ::unwrap_or_ai::__unwrap_or_ai_error_text!(__unwrap_or_ai_result);
+        let __unwrap_or_ai_error_context =
+            ::unwrap_or_ai::__unwrap_or_ai_error_context!(__unwrap_or_ai_result);
+        #recovery
+    }};
+    Ok(function)
+}
+
+// FNV-1a, the same as `unwrap_or_ai::cache::stable_hash(&[source])`, so the value
+// only changes when the source does
+fn stable_hash(source: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    source
+        .bytes()
+        .chain(std::iter::once(0xff))
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(PRIME)
+        })
+}
+
+// `function` with its body wrapped so that a successful result is stored as an example
+// before it is returned
+fn remembering_successes(function: &ItemFn, source_hash: u64) -> syn::Result<ItemFn> {
+    let syn::ReturnType::Type(_, output) = &function.sig.output else {
+        return Err(syn::Error::new(
+            function.sig.span(),
+            "`learn` needs a function returning a `Result` or `Option`",
+        ));
+    };
+    let fn_name = function.sig.ident.to_string();
+    let block = &function.block;
+
+    // `return` and `?` in the body keep working, as they leave the closure/async block
+    let call = match function.sig.asyncness {
+        Some(_) => quote! { async move #block.await },
+        None => quote! { (move || -> #output #block)() },
+    };
+
+    let mut function = function.clone();
+    function.block = syn::parse_quote! {{
+        #[allow(clippy::redundant_closure_call)]
+        let __unwrap_or_ai_result: #output = #call;
+        ::unwrap_or_ai::__private::remember_success(
+            #fn_name,
+            #source_hash,
+            &__unwrap_or_ai_result,
+        );
+        __unwrap_or_ai_result
+    }};
+    Ok(function)
+}
+
+// The `T` of a `Result<T, _>` or `Option<T>` return type, or the whole return type
+fn recovered_type(sig: &syn::Signature) -> Option<&syn::Type> {
+    let syn::ReturnType::Type(_, output) = &sig.output else {
+        return None;
+    };
+    let recovered = match &**output {
+        syn::Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .filter(|segment| segment.ident == "Result" || segment.ident == "Option")
+            .and_then(|segment| match &segment.arguments {
+                syn::PathArguments::AngleBracketed(args) => args.args.first(),
+                _ => None,
+            })
+            .and_then(|arg| match arg {
+                syn::GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .unwrap_or(output),
+        _ => output,
+    };
+    Some(recovered)
+}
+
+fn find_impl_trait(ty: &syn::Type) -> Option<&syn::TypeImplTrait> {
+    match ty {
+        syn::Type::ImplTrait(opaque) => Some(opaque),
+        syn::Type::Path(path) => {
+            path.path
+                .segments
+                .iter()
+                .find_map(|segment| match &segment.arguments {
+                    syn::PathArguments::AngleBracketed(args) => {
+                        args.args.iter().find_map(|arg| match arg {
+                            syn::GenericArgument::Type(ty) => find_impl_trait(ty),
+                            _ => None,
+                        })
+                    }
+                    _ => None,
+                })
+        }
+        syn::Type::Tuple(tuple) => tuple.elems.iter().find_map(find_impl_trait),
+        syn::Type::Array(array) => find_impl_trait(&array.elem),
+        syn::Type::Slice(slice) => find_impl_trait(&slice.elem),
+        syn::Type::Reference(reference) => find_impl_trait(&reference.elem),
+        syn::Type::Paren(paren) => find_impl_trait(&paren.elem),
+        syn::Type::Group(group) => find_impl_trait(&group.elem),
+        _ => None,
+    }
+}
+
+// A reference, or a type with a lifetime argument, anywhere in `ty`. `Cow` is left alone
+// since serde always deserializes it owned.
+fn find_borrow(ty: &syn::Type) -> Option<&syn::Type> {
+    match ty {
+        syn::Type::Reference(_) => Some(ty),
+        syn::Type::Path(path) => path.path.segments.iter().find_map(|segment| {
+            let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+                return None;
+            };
+            if segment.ident != "Cow"
+                && args
+                    .args
+                    .iter()
+                    .any(|arg| matches!(arg, syn::GenericArgument::Lifetime(_)))
+            {
+                return Some(ty);
+            }
+            args.args.iter().find_map(|arg| match arg {
+                syn::GenericArgument::Type(ty) => find_borrow(ty),
+                _ => None,
+            })
+        }),
+        syn::Type::Tuple(tuple) => tuple.elems.iter().find_map(find_borrow),
+        syn::Type::Array(array) => find_borrow(&array.elem),
+        syn::Type::Slice(slice) => find_borrow(&slice.elem),
+        syn::Type::Paren(paren) => find_borrow(&paren.elem),
+        syn::Type::Group(group) => find_borrow(&group.elem),
+        _ => None,
+    }
+}
+
+fn opaque_error(opaque: &syn::TypeImplTrait) -> syn::Error {
+    syn::Error::new(
+        opaque.span(),
+        "unwrap_or_ai can't recover an `impl Trait` return type: the model's answer is \
+         deserialized into the concrete type, which is hidden here. Return a concrete, \
+         nameable type that implements `Deserialize` and `JsonSchema`",
+    )
+}
+
+// Replaces a wall of `DeserializeOwned` bounds at the call site
+fn borrow_error(borrowed: &syn::Type) -> syn::Error {
+    syn::Error::new_spanned(
+        borrowed,
+        "unwrap_or_ai can't recover a borrowed type: recovered values are deserialized \
+         with `DeserializeOwned`, so they can't borrow from the model's answer, which is \
+         dropped once parsed. Use an owned type instead, such as `String` for `&str` or \
+         `Vec<T>` for `&[T]`",
+    )
+}
+
+// One name per parameter, in order; patterns other than a plain binding become `_`
+fn param_names(sig: &syn::Signature) -> Vec<String> {
+    sig.inputs
+        .iter()
+        .map(|input| match input {
+            syn::FnArg::Receiver(_) => "self".to_string(),
+            syn::FnArg::Typed(typed) => match &*typed.pat {
+                syn::Pat::Ident(pat) => pat.ident.to_string(),
+                _ => "_".to_string(),
+            },
+        })
+        .collect()
+}
+
+// The text of the `///` comments (and `#[doc = "..."]` attributes), one line each
+fn doc_text(attrs: &[syn::Attribute]) -> String {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                value:
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(text),
+                        ..
+                    }),
+                ..
+            }) => Some(text.value()),
+            _ => None,
+        })
+        .collect();
+
+    // `/// text` is stored as `" text"`
+    lines
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// Checks that a type satisfies every bound `unwrap_or_ai!` needs to recover it.
+///
+/// The derive does not implement `Deserialize` or `JsonSchema` itself; it emits one
+/// assertion per required trait so a missing derive is reported by name on the type,
+/// instead of as a chain of unsatisfied bounds at the `unwrap_or_ai!` call site.
+///
+/// It also implements `unwrap_or_ai::recoverable::TypeSource` with the type's definition,
+/// which `include_type_source` adds to recovery prompts.
+///
+/// `#[recoverable(warn_missing_docs)]` also warns when neither the type nor any of its
+/// fields or variants has a doc comment, since the model then has only names to go on.
+#[proc_macro_derive(Recoverable, attributes(recoverable))]
+pub fn derive_recoverable(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    let ident = &input.ident;
+
+    let mut warn_missing_docs = false;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("recoverable"))
+    {
+        let parsed = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("warn_missing_docs") {
+                warn_missing_docs = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported recoverable option, expected `warn_missing_docs`"))
+            }
+        });
+        if let Err(err) = parsed {
+            return err.to_compile_error().into();
+        }
+    }
+    if let Some(borrowed) = fields(&input).find_map(|field| find_borrow(&field.ty)) {
+        return borrow_error(borrowed).to_compile_error().into();
+    }
+    // Stable proc macros can't emit warnings, so use a deprecated item: its note is shown
+    // as a warning on the type, and `#[allow(deprecated)]` silences it
+    let docs_warning = if warn_missing_docs && !has_docs(&input) {
+        let note = format!(
+            "`{}` has no doc comments; document the type or its fields so AI recovery knows what the values mean",
+            ident
+        );
+        quote_spanned! {ident.span()=>
+            const _: () = {
+                #[deprecated(note = #note)]
+                #[allow(non_camel_case_types)]
+                struct undocumented_recoverable_type;
+                let _ = undocumented_recoverable_type;
+            };
+        }
+    } else {
+        quote! {}
+    };
+
+    let source = quote! { #input }.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let type_source = quote! {
+        impl #impl_generics ::unwrap_or_ai::recoverable::TypeSource for #ident #ty_generics #where_clause {
+            const SOURCE: &'static str = #source;
+        }
+    };
+
+    // A generic type can only be checked once its parameters are known, so the
+    // assertions are left to the call site in that case.
+    if !input.generics.params.is_empty() {
+        return quote! { #type_source #docs_warning }.into();
+    }
+
+    let requirements = ["RequiresDeserialize", "RequiresJsonSchema"];
+
+    let assertions = requirements.iter().map(|requirement| {
+        let requirement = syn::Ident::new(requirement, ident.span());
+        quote_spanned! {ident.span()=>
+            {
+                fn assert<T: ?Sized + ::unwrap_or_ai::__private::#requirement>() {}
+                assert::<#ident>();
+            }
+        }
+    });
+
+    let span = input.span();
+    let expanded = quote_spanned! {span=>
+        const _: fn() = || {
+            #(#assertions)*
+        };
+        #type_source
+        #docs_warning
     };
 
     expanded.into()
 }
+
+// Every field of a struct, or of any variant of an enum
+fn fields(input: &DeriveInput) -> Box<dyn Iterator<Item = &syn::Field> + '_> {
+    match &input.data {
+        syn::Data::Struct(data) => Box::new(data.fields.iter()),
+        syn::Data::Enum(data) => Box::new(
+            data.variants
+                .iter()
+                .flat_map(|variant| variant.fields.iter()),
+        ),
+        syn::Data::Union(data) => Box::new(data.fields.named.iter()),
+    }
+}
+
+// A doc comment on the type itself or on any of its fields or variants
+fn has_docs(input: &DeriveInput) -> bool {
+    let documented = |attrs: &[syn::Attribute]| !doc_text(attrs).is_empty();
+    let fields_documented =
+        |fields: &syn::Fields| fields.iter().any(|field| documented(&field.attrs));
+
+    documented(&input.attrs)
+        || match &input.data {
+            syn::Data::Struct(data) => fields_documented(&data.fields),
+            syn::Data::Enum(data) => data
+                .variants
+                .iter()
+                .any(|variant| documented(&variant.attrs) || fields_documented(&variant.fields)),
+            syn::Data::Union(data) => data
+                .fields
+                .named
+                .iter()
+                .any(|field| documented(&field.attrs)),
+        }
+}